@@ -1,28 +1,86 @@
-use std::ffi::OsStr;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context};
+use anyhow::{bail, Context};
+use log::Level;
 use mdbook::book::SectionNumber;
-use mdbook::preprocess::PreprocessorContext;
-use pulldown_cmark::{CowStr, Event, LinkType, Tag, TagEnd};
+use pulldown_cmark::{CowStr, Event, LinkType, Parser, Tag, TagEnd};
 use smallvec::{smallvec, SmallVec};
-use wait_timeout::ChildExt;
 
-use crate::config::{Config, Fonts};
+use crate::cache::RenderCache;
+use crate::checkpoint::{sha256_hex, source_hash, Checkpoint};
+use crate::config::{
+    CacheMode, Config, DedupScope, NumberingScope, OutputFormat, RenderErrorBehavior,
+    RenderQuality, ResizeFit, TinyImageBehavior,
+};
+use crate::job_log;
+use crate::manifest::{Manifest, ManifestEntry, MANIFEST_FILE};
+use crate::renderer::{D2Cli, DiagramRenderer};
 
 /// Configuration key in book.toml for this preprocessor
 const PREPROCESSOR_CONFIG_KEY: &str = "preprocessor.d2-png";
 
-/// Default timeout for D2 process execution
+/// CSS class carried by every diagram image emitted as HTML
+const DIAGRAM_CLASS: &str = "d2-diagram";
+
+/// CSS classes carried by diagram images in print builds
+const PRINT_CLASS: &str = "d2-diagram d2-print";
+
+/// CSS classes carried by legend images
+const LEGEND_CLASS: &str = "d2-diagram d2-legend";
+
+/// CSS class carried by render errors shown in the page
+const ERROR_CLASS: &str = "d2-error";
+
+/// CSS classes carried by legend images in print builds
+const LEGEND_PRINT_CLASS: &str = "d2-diagram d2-legend d2-print";
+
+/// Marker line separating a `legend` block's legend when no split delimiter
+/// is configured
+const DEFAULT_LEGEND_DELIMITER: &str = "---";
+
+/// CSS and JS making `d2-zoom` containers zoomable, added once per chapter
 ///
-/// This is a reasonable timeout for most diagrams. Very complex diagrams
-/// may take longer, but this helps prevent hanging on malformed input.
-/// If you experience timeout issues, consider simplifying the diagram
-/// or reporting a bug to the D2 project.
-const D2_PROCESS_TIMEOUT: Duration = Duration::from_secs(30);
+/// The script guards against registering twice, as mdBook's print page
+/// concatenates all chapters.
+pub const ZOOM_SNIPPET: &str = r#"<style>
+.d2-zoom { display: block; overflow: hidden; cursor: zoom-in; }
+.d2-zoom.d2-zoomed { overflow: auto; cursor: zoom-out; }
+.d2-zoom.d2-zoomed img { max-width: none; width: 200%; }
+</style>
+<script>
+if (!window.d2Zoom) {
+  window.d2Zoom = true;
+  document.addEventListener("click", function (event) {
+    var zoom = event.target.closest && event.target.closest(".d2-zoom");
+    if (zoom) zoom.classList.toggle("d2-zoomed");
+  });
+}
+</script>
+"#;
+
+/// Language filled in for `{lang}` when neither the preprocessor nor the book sets one
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Layout engine D2 uses when none is configured
+const DEFAULT_LAYOUT: &str = "dagre";
+
+/// D2 key of the shape carrying the watermark
+const WATERMARK_SHAPE: &str = "d2-png-watermark";
+
+/// Directory (relative to the book root) receiving the input of failed diagrams
+const FAILED_DIAGRAMS_DIR: &str = "failed-diagrams";
+
+/// Directory in the book root holding images cached between builds
+const RENDER_CACHE_DIR: &str = ".d2-png-cache";
+
+/// Number of hex digits of a file's SHA-256 added to its name with `hashed-filenames`
+const FILENAME_HASH_LEN: usize = 8;
 
 /// Path-related configuration for the backend
 ///
@@ -30,13 +88,18 @@ const D2_PROCESS_TIMEOUT: Duration = Duration::from_secs(30);
 /// Keeping paths separate from rendering config makes the purpose of each
 /// field clearer and makes it easier to extend path configuration in the future.
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_field_names)]
 struct PathConfig {
-    /// Absolute path to the D2 binary
-    d2_binary: PathBuf,
     /// Relative path to the output directory for generated diagrams
     output_dir: PathBuf,
     /// Absolute path to the source directory of the book
     source_dir: PathBuf,
+    /// Absolute path to the directory receiving failed diagram input, if enabled
+    failure_dir: Option<PathBuf>,
+    /// Absolute path to the JavaScript module receiving data URIs, if enabled
+    js_data_file: Option<PathBuf>,
+    /// Absolute path to the progress file of resumable builds, if enabled
+    checkpoint_file: Option<PathBuf>,
 }
 
 /// Rendering configuration for D2 diagrams
@@ -45,23 +108,158 @@ struct PathConfig {
 /// Keeping rendering config separate from paths makes the Backend structure
 /// more maintainable and makes it clearer which fields affect diagram rendering.
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
 struct RenderConfig {
-    /// Layout engine to use for D2 diagrams
-    layout: Option<String>,
     /// Whether to inline PNG images as base64 data URIs
     inline: bool,
-    /// Custom font configuration
-    fonts: Option<Fonts>,
-    /// Theme ID for D2 diagrams
-    theme_id: Option<String>,
-    /// Dark theme ID for D2 diagrams
-    dark_theme_id: Option<String>,
+    /// Marker line splitting one code block into several diagrams
+    split_delimiter: Option<String>,
+    /// Whether to derive alt text from the diagram's top-level label or title
+    alt_from_label: bool,
+    /// Template for image alt text
+    alt_template: String,
+    /// Whether to insert an sRGB color profile chunk into rendered PNGs
+    embed_srgb: bool,
+    /// Whether to rewrite element IDs in rendered SVGs to deterministic ones
+    stable_svg_ids: bool,
+    /// Whether to trim uniform borders off rendered PNGs
+    autocrop: bool,
+    /// Background color trimmed by `autocrop`, defaulting to each image's top-left pixel
+    autocrop_background: Option<[u8; 4]>,
+    /// Number of colors to quantize rendered PNGs to
+    palette: Option<u16>,
+    /// Exact width and height in pixels to resize rendered PNGs to
+    resize: Option<(u32, u32)>,
+    /// How diagrams are fitted into the `resize` dimensions
+    resize_fit: ResizeFit,
+    /// Node count above which a diagram triggers a complexity warning
+    warn_complexity_nodes: Option<usize>,
+    /// Minimum width and height (in pixels) of a plausible diagram
+    min_image_size: u32,
+    /// How to treat images below the minimum size
+    tiny_image: TinyImageBehavior,
+    /// Number of diagrams per chapter emitted with eager, high-priority loading hints
+    eager_count: Option<usize>,
+    /// Whether to warn about byte-identical diagrams
+    warn_duplicates: bool,
+    /// Whether to emit print-friendly image markup
+    print: bool,
+    /// Whether images carry their pixel size as data attributes
+    natural_size_attributes: bool,
+    /// Display width and height of images in pixels, if set
+    size: (Option<u32>, Option<u32>),
+    /// Image format diagrams are rendered to
+    format: OutputFormat,
+    /// Rasterization quality, setting how much larger images are rendered than displayed
+    quality: RenderQuality,
+    /// Whether images carry `decoding="async"`
+    decoding_async: bool,
+    /// Whether images are wrapped in a zoomable container
+    zoomable: bool,
+    /// Whether diagram files are buffered and written after rendering
+    batch_writes: bool,
+    /// Whether diagram files are named after a hash of their contents
+    hashed_filenames: bool,
+    /// Class of the size container wrapped around images, if any
+    container_class: Option<String>,
+    /// Shell command run on each generated diagram file, if any
+    post_render_command: Option<String>,
+    /// Absolute URL of the source directory, prefixing `og:image` URLs
+    og_image_base_url: Option<String>,
+    /// Text stamped onto every diagram, with placeholders filled in
+    watermark: Option<String>,
+    /// Theme of the dark variant rendered next to each diagram file, if any
+    picture_dark_theme_id: Option<String>,
 }
 
 /// Represents the backend for processing D2 diagrams
 pub struct Backend {
     paths: PathConfig,
     render: RenderConfig,
+    /// Engine turning diagram source into PNG bytes
+    renderer: Arc<dyn DiagramRenderer>,
+    /// Images rendered by earlier builds, if caching is enabled
+    cache: Option<RenderCache>,
+    /// Which diagrams the render cache stores
+    cache_mode: CacheMode,
+    /// Whether to suppress all non-error logging
+    quiet: bool,
+    /// Time over which to ramp up concurrent renders, if any
+    concurrency_ramp: Option<Duration>,
+    /// Maximum number of concurrent renders, if configured
+    max_concurrency: Option<usize>,
+    /// Maximum total node count of diagrams rendered at once, if any
+    max_concurrent_nodes: Option<usize>,
+    /// Layout engine used for diagrams that don't declare one
+    layout: String,
+    /// Scope within which figure numbers count up
+    numbering_scope: NumberingScope,
+    /// Scope within which identical diagrams share one image
+    dedup_scope: DedupScope,
+    /// Git ref diagram sources are compared against, if any
+    changed_since: Option<String>,
+    /// Maximum number of concurrent renders per layout engine
+    layout_concurrency: BTreeMap<String, usize>,
+    /// Whether to render one diagram at a time
+    sequential: bool,
+    /// Time limit for rendering all diagrams, if any
+    total_timeout: Option<Duration>,
+    /// Maximum number of diagrams to render, if any
+    max_diagrams: Option<usize>,
+    /// Maximum number of diagrams in a single chapter, if any
+    max_diagrams_per_chapter: Option<usize>,
+    /// Tags selecting the diagrams to render; empty renders all
+    only_tags: Vec<String>,
+    /// Language tags of the code blocks rendered as D2 diagrams
+    languages: Vec<String>,
+    /// What to do with a diagram that fails to render
+    on_error: RenderErrorBehavior,
+    /// What to do with a diagram whose `file` can't be read
+    on_missing_file: RenderErrorBehavior,
+    /// Data URIs destined for the JavaScript data file, keyed by diagram ID
+    js_data: Mutex<BTreeMap<String, String>>,
+    /// Diagram files waiting to be written, with `batch-writes`
+    pending_files: Mutex<Vec<PendingFile>>,
+    /// Diagram files generated in the output directory, for the manifest
+    generated_files: Mutex<Vec<ManifestEntry>>,
+}
+
+/// A rendered diagram file buffered until [`Backend::write_pending_files`]
+#[derive(Debug)]
+struct PendingFile {
+    /// Where the file is written
+    path: PathBuf,
+    /// The file's path relative to the source directory, as recorded in checkpoints
+    checkpoint_key: String,
+    /// The diagram's source as recorded in checkpoints
+    content: String,
+    /// The rendered PNG
+    png_bytes: Vec<u8>,
+}
+
+/// A rendered diagram image, kept to show diagrams identical to the one it
+/// was rendered for
+#[derive(Debug)]
+pub struct RenderedImage {
+    /// The rendered PNG
+    png_bytes: Vec<u8>,
+    /// Display size of a `<picture>` with a dark variant: the larger of both
+    /// variants' sizes, so switching themes doesn't shift the layout
+    picture_size: Option<(u32, u32)>,
+    /// Hashes in the names of the light and dark variants' files, with
+    /// `hashed-filenames`
+    content_hashes: (Option<String>, Option<String>),
+}
+
+impl RenderedImage {
+    /// Wraps a rendered PNG without a dark variant
+    const fn new(png_bytes: Vec<u8>) -> Self {
+        Self {
+            png_bytes,
+            picture_size: None,
+            content_hashes: (None, None),
+        }
+    }
 }
 
 /// Context for rendering a specific diagram within a chapter
@@ -71,6 +269,7 @@ pub struct Backend {
 /// 2. Calculate relative paths for image links
 /// 3. Produce helpful error messages
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct RenderContext<'a> {
     /// Path to the chapter file (used to calculate relative paths from chapter to diagram)
     path: &'a Path,
@@ -85,10 +284,61 @@ pub struct RenderContext<'a> {
     /// Index of this diagram within the chapter (1-based, incremented for each diagram)
     /// Combined with section number to create unique filenames
     diagram_index: usize,
+
+    /// Index of this diagram within a code block split into several diagrams
+    /// (1-based, `None` when the block holds a single diagram)
+    sub_index: Option<usize>,
+
+    /// Whether this diagram is the legend of a `legend` code block
+    legend: bool,
+
+    /// Permanent identifier naming the diagram's output file, replacing the
+    /// section and index (set with `stable_id=...` in the info string)
+    stable_id: Option<&'a str>,
+
+    /// Figure number within the configured numbering scope, defaulting to the
+    /// section number and index
+    figure: Option<&'a str>,
+
+    /// Whether the diagram changed since the `changed-since` baseline
+    changed: bool,
+
+    /// Whether the diagram is its chapter's Open Graph image (set with the
+    /// `og-image` info string token)
+    og_image: bool,
+
+    /// Whether the diagram opted into the render cache (set with the `cache`
+    /// info string token), for `cache-mode = "opt-in"`
+    cache: bool,
+
+    /// Options set in the code block's info string (e.g. `pad=40`), overriding
+    /// the configuration for this diagram
+    options: &'a [(String, String)],
+
+    /// Whether this is the dark variant of a diagram shown in a `<picture>`,
+    /// written next to the light one with a `.dark` suffix
+    dark: bool,
+
+    /// Alt text set with `alt=...` in the info string or a leading
+    /// `# alt: ...` comment, taking precedence over the diagram's label
+    alt: Option<&'a str>,
+
+    /// Caption set with `caption=...` in the info string, shown below the
+    /// image in a `<figure>` (may contain inline markdown)
+    figcaption: Option<&'a str>,
+
+    /// Display width and height in pixels set with `width=...` and
+    /// `height=...` in the info string, replacing the configured size
+    size: (Option<u32>, Option<u32>),
+
+    /// Hash of the diagram file's contents added to its name, with
+    /// `hashed-filenames`
+    content_hash: Option<&'a str>,
 }
 
 impl<'a> RenderContext<'a> {
     /// Creates a new [`RenderContext`]
+    #[must_use]
     pub const fn new(
         path: &'a Path,
         chapter: &'a str,
@@ -100,51 +350,432 @@ impl<'a> RenderContext<'a> {
             chapter,
             section,
             diagram_index,
+            sub_index: None,
+            legend: false,
+            stable_id: None,
+            figure: None,
+            changed: false,
+            og_image: false,
+            cache: false,
+            options: &[],
+            dark: false,
+            alt: None,
+            figcaption: None,
+            size: (None, None),
+            content_hash: None,
         }
     }
+
+    /// Marks this context as one part of a split code block
+    #[must_use]
+    pub const fn with_sub_index(mut self, sub_index: Option<usize>) -> Self {
+        self.sub_index = sub_index;
+        self
+    }
+
+    /// Marks this context as the legend of a `legend` code block
+    #[must_use]
+    pub const fn with_legend(mut self, legend: bool) -> Self {
+        self.legend = legend;
+        self
+    }
+
+    /// Names the diagram's output file after a permanent identifier
+    #[must_use]
+    pub const fn with_stable_id(mut self, stable_id: Option<&'a str>) -> Self {
+        self.stable_id = stable_id;
+        self
+    }
+
+    /// Sets the figure number used for `{figure}` in alt text
+    #[must_use]
+    pub const fn with_figure(mut self, figure: Option<&'a str>) -> Self {
+        self.figure = figure;
+        self
+    }
+
+    /// Marks the diagram as changed since the `changed-since` baseline
+    #[must_use]
+    pub const fn with_changed(mut self, changed: bool) -> Self {
+        self.changed = changed;
+        self
+    }
+
+    /// Marks the diagram as its chapter's Open Graph image
+    #[must_use]
+    pub const fn with_og_image(mut self, og_image: bool) -> Self {
+        self.og_image = og_image;
+        self
+    }
+
+    /// Marks the diagram as opted into the render cache
+    #[must_use]
+    pub const fn with_cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Sets the options from the code block's info string
+    #[must_use]
+    pub const fn with_options(mut self, options: &'a [(String, String)]) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Marks this context as the dark variant of a diagram
+    #[must_use]
+    pub const fn with_dark(mut self, dark: bool) -> Self {
+        self.dark = dark;
+        self
+    }
+
+    /// Sets the alt text from the info string or a leading comment
+    #[must_use]
+    pub const fn with_alt(mut self, alt: Option<&'a str>) -> Self {
+        self.alt = alt;
+        self
+    }
+
+    /// Sets the figure caption from the info string
+    #[must_use]
+    pub const fn with_figcaption(mut self, figcaption: Option<&'a str>) -> Self {
+        self.figcaption = figcaption;
+        self
+    }
+
+    /// Sets the display width and height from the info string
+    #[must_use]
+    pub const fn with_size(mut self, width: Option<u32>, height: Option<u32>) -> Self {
+        self.size = (width, height);
+        self
+    }
+
+    /// Adds a hash of the diagram file's contents to its name
+    #[must_use]
+    pub(crate) const fn with_content_hash(mut self, content_hash: Option<&'a str>) -> Self {
+        self.content_hash = content_hash;
+        self
+    }
+
+    /// Path to the chapter file, relative to the book's source directory
+    #[must_use]
+    pub const fn path(&self) -> &'a Path {
+        self.path
+    }
+
+    /// Name of the chapter containing the diagram
+    #[must_use]
+    pub const fn chapter(&self) -> &'a str {
+        self.chapter
+    }
+
+    /// Section number of the chapter, if it is numbered
+    #[must_use]
+    pub const fn section(&self) -> Option<&'a SectionNumber> {
+        self.section
+    }
+
+    /// 1-based index of the diagram within its chapter
+    #[must_use]
+    pub const fn diagram_index(&self) -> usize {
+        self.diagram_index
+    }
+
+    /// 1-based index of the diagram within a split code block
+    #[must_use]
+    pub const fn sub_index(&self) -> Option<usize> {
+        self.sub_index
+    }
+
+    /// Whether the diagram is the legend of a `legend` code block
+    #[must_use]
+    pub const fn is_legend(&self) -> bool {
+        self.legend
+    }
+
+    /// Permanent identifier naming the diagram's output file, if any
+    #[must_use]
+    pub const fn stable_id(&self) -> Option<&'a str> {
+        self.stable_id
+    }
+
+    /// Options set in the code block's info string, in order
+    #[must_use]
+    pub const fn options(&self) -> &'a [(String, String)] {
+        self.options
+    }
+
+    /// Value of an option set in the code block's info string, if any
+    #[must_use]
+    pub fn option(&self, key: &str) -> Option<&'a str> {
+        self.options
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Returns whether messages at the given level should be logged
+///
+/// Quiet mode keeps errors but drops warnings and informational messages.
+///
+/// # Arguments
+/// * `quiet` - Whether quiet mode is enabled
+/// * `level` - The level of the message
+pub fn log_enabled(quiet: bool, level: Level) -> bool {
+    !quiet || level <= Level::Error
 }
 
 /// Generates a unique filename for a diagram based on its context
 ///
 /// Creates filenames in the format:
-/// - With section: `{section}.{diagram_index}.png` (e.g., `1.2.3.png`)
-/// - Without section: `{path_hash}_{diagram_index}.png` (e.g., `a1b2c3d4_1.png`)
+/// - With section: `{section}.{diagram_index}.{ext}` (e.g., `1.2.3.png`)
+/// - Without section: `{path_hash}_{diagram_index}.{ext}` (e.g., `a1b2c3d4_1.png`)
+/// - With a stable ID: `{stable_id}.{ext}` (e.g., `arch-v2.png`), regardless of
+///   section and position
+///
+/// Parts of a split code block gain a `-{sub_index}` suffix (e.g., `1.2.3-2.png`),
+/// dark variants a `.dark` suffix (e.g., `1.2.3.dark.png`) and files named
+/// after their contents the hash (e.g., `1.2.3.0f1e2d3c.png`), unless they
+/// have a stable ID.
 ///
 /// The path hash ensures uniqueness for unnumbered chapters, preventing
 /// filename collisions when multiple chapters lack section numbers.
 ///
 /// # Arguments
 /// * `ctx` - The render context containing section, path, and diagram index
-fn filename(ctx: &RenderContext) -> String {
+/// * `format` - The image format, setting the extension
+fn filename(ctx: &RenderContext, format: OutputFormat) -> String {
+    let dark = if ctx.dark { ".dark" } else { "" };
+    let hash = ctx
+        .content_hash
+        .filter(|_| ctx.stable_id.is_none())
+        .map(|hash| format!(".{hash}"))
+        .unwrap_or_default();
+    format!("{}{dark}{hash}.{}", file_stem(ctx), format.extension())
+}
+
+/// Generates a diagram's filename without extension, as for [`filename`]
+///
+/// # Arguments
+/// * `ctx` - The render context containing section, path, and diagram index
+fn file_stem(ctx: &RenderContext) -> String {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
 
+    if let Some(stable_id) = ctx.stable_id {
+        return ctx.sub_index.map_or_else(
+            || stable_id.to_string(),
+            |sub_index| format!("{stable_id}-{sub_index}"),
+        );
+    }
+
+    let index = ctx.sub_index.map_or_else(
+        || ctx.diagram_index.to_string(),
+        |sub_index| format!("{}-{}", ctx.diagram_index, sub_index),
+    );
+
     ctx.section.as_ref().map_or_else(
         || {
             // Generate a stable hash from the chapter path for uniqueness
             let mut hasher = DefaultHasher::new();
             ctx.path.hash(&mut hasher);
-            let path_hash: String = format!("{:x}", hasher.finish())
-                .chars()
-                .take(8)
-                .collect();
-            format!("{}_{}.png", path_hash, ctx.diagram_index)
+            let path_hash: String = format!("{:x}", hasher.finish()).chars().take(8).collect();
+            format!("{path_hash}_{index}")
         },
         // Note: SectionNumber's Display impl already includes a trailing dot (e.g., "1.2.")
-        // so we just append the diagram_index and extension
-        |section| format!("{}{}.png", section, ctx.diagram_index),
+        // so we just append the diagram_index
+        |section| format!("{section}{index}"),
+    )
+}
+
+/// Generates the ID identifying a diagram in the JavaScript data file
+///
+/// The ID is the diagram's filename without extension (e.g. `1.2.3`).
+///
+/// # Arguments
+/// * `ctx` - The render context for the diagram
+fn diagram_id(ctx: &RenderContext) -> String {
+    file_stem(ctx)
+}
+
+/// Generates the filename for a failed diagram's input
+///
+/// Combines the chapter's source path with the diagram index, e.g.
+/// `part1/intro.md` diagram 2 becomes `part1-intro-2.d2`.
+///
+/// # Arguments
+/// * `ctx` - The render context of the failed diagram
+fn failure_filename(ctx: &RenderContext) -> String {
+    let chapter = ctx
+        .path
+        .with_extension("")
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    ctx.sub_index.map_or_else(
+        || format!("{chapter}-{}.d2", ctx.diagram_index),
+        |sub_index| format!("{chapter}-{}-{sub_index}.d2", ctx.diagram_index),
     )
 }
 
+/// D2 keywords that may appear as keys but don't declare shapes
+const D2_KEYWORDS: &[&str] = &[
+    "label",
+    "shape",
+    "style",
+    "icon",
+    "near",
+    "direction",
+    "tooltip",
+    "link",
+    "width",
+    "height",
+    "constraint",
+    "class",
+    "classes",
+    "vars",
+    "title",
+    "grid-rows",
+    "grid-columns",
+    "grid-gap",
+    "vertical-gap",
+    "horizontal-gap",
+    "source-arrowhead",
+    "target-arrowhead",
+    "layers",
+    "scenarios",
+    "steps",
+    "top",
+    "left",
+];
+
+/// Estimates the number of nodes (shapes) declared in a diagram
+///
+/// A cheap heuristic over the source: every distinct name appearing in a key
+/// (the part before `:`) or as an edge endpoint counts as a node, except for
+/// D2 keywords and their attribute paths (e.g. `a.style.fill`).
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+fn count_nodes(content: &str) -> usize {
+    let mut nodes = std::collections::HashSet::new();
+    for line in content.lines() {
+        let line = strip_comment(line);
+        let key = line.split_once(':').map_or(line, |(key, _)| key);
+        let key = key.trim().trim_end_matches('{').trim();
+        if key.is_empty() || key.starts_with(['(', '}', '|', '*']) {
+            continue;
+        }
+
+        let endpoints = ["<->", "->", "<-", "--"]
+            .iter()
+            .fold(key.to_string(), |key, op| key.replace(op, "\n"));
+        for name in endpoints.lines().map(str::trim) {
+            let is_keyword = name.split('.').any(|part| D2_KEYWORDS.contains(&part));
+            if !name.is_empty() && !is_keyword {
+                nodes.insert(name.to_string());
+            }
+        }
+    }
+    nodes.len()
+}
+
+/// Returns the layout engine a diagram declares in its `d2-config`, if any
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+fn declared_layout(content: &str) -> Option<&str> {
+    content.lines().find_map(|line| {
+        strip_comment(line)
+            .trim()
+            .strip_prefix("layout-engine:")
+            .map(|layout| layout.trim().trim_matches(['"', '\'']))
+            .filter(|layout| !layout.is_empty())
+    })
+}
+
+/// Returns whether a diagram imports other files
+///
+/// D2 imports are values starting with `@`, e.g. `x: @shapes` or the
+/// spread `...@base`. An `@` elsewhere, e.g. in a label or an email address,
+/// isn't one.
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+fn has_imports(content: &str) -> bool {
+    content.lines().map(strip_comment).any(|line| {
+        line.contains("...@")
+            || line
+                .match_indices(':')
+                .any(|(i, _)| line[i + 1..].trim_start().starts_with('@'))
+    })
+}
+
+/// Returns whether a diagram references files relative to its working directory
+///
+/// These are imports and `icon`s given as a path rather than a URL. The same
+/// content renders differently in chapters in different directories.
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+pub fn uses_local_files(content: &str) -> bool {
+    has_imports(content)
+        || content.lines().map(strip_comment).any(|line| {
+            line.split_once("icon:").is_some_and(|(_, value)| {
+                let value = value.trim().trim_matches(['"', '\'']);
+                !value.is_empty() && !value.contains("://")
+            })
+        })
+}
+
+/// Returns whether any of the given environment variables is set
+///
+/// # Arguments
+/// * `names` - The environment variables' names
+fn any_env_set(names: &[String]) -> bool {
+    names.iter().any(|name| std::env::var_os(name).is_some())
+}
+
+/// Splits code block content into sub-diagrams on a delimiter line
+///
+/// A line matches the delimiter when it equals it after trimming surrounding
+/// whitespace. Blank parts are dropped; if nothing but blank parts remain,
+/// the original content is returned as a single diagram so every code block
+/// still produces exactly one render result.
+///
+/// # Arguments
+/// * `content` - The code block content
+/// * `delimiter` - The marker line separating diagrams
+fn split_on_delimiter(content: &str, delimiter: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    for line in content.split_inclusive('\n') {
+        if line.trim() == delimiter {
+            parts.push(String::new());
+        } else if let Some(part) = parts.last_mut() {
+            part.push_str(line);
+        }
+    }
+
+    parts.retain(|part| !part.trim().is_empty());
+    if parts.is_empty() {
+        parts.push(content.to_string());
+    }
+    parts
+}
+
 /// Creates markdown events for an image
 ///
 /// Wraps an image in a paragraph with the given URL.
-/// Returns a `SmallVec` since image events are always exactly 4 elements.
+/// Returns a `SmallVec` since image events are at most 5 elements.
 ///
 /// # Arguments
 /// * `url` - The image URL (can be a file path or data URI)
-fn create_image_events(url: String) -> SmallVec<[Event<'static>; 4]> {
-    smallvec![
+/// * `alt` - The image's alt text (may be empty)
+fn create_image_events(url: String, alt: &str) -> SmallVec<[Event<'static>; 5]> {
+    let mut events = smallvec![
         Event::Start(Tag::Paragraph),
         Event::Start(Tag::Image {
             link_type: LinkType::Inline,
@@ -152,60 +783,782 @@ fn create_image_events(url: String) -> SmallVec<[Event<'static>; 4]> {
             title: CowStr::Borrowed(""),
             id: CowStr::Borrowed(""),
         }),
-        Event::End(TagEnd::Image),
+    ];
+    if !alt.is_empty() {
+        events.push(Event::Text(alt.to_string().into()));
+    }
+    events.extend([Event::End(TagEnd::Image), Event::End(TagEnd::Paragraph)]);
+    events
+}
+
+/// Creates markdown events marking a diagram skipped by `max-diagrams`
+pub fn skipped_diagram_events() -> Vec<Event<'static>> {
+    vec![
+        Event::Start(Tag::Paragraph),
+        Event::Start(Tag::Emphasis),
+        Event::Text("D2 diagram skipped for fast preview (max-diagrams reached)".into()),
+        Event::End(TagEnd::Emphasis),
+        Event::End(TagEnd::Paragraph),
+    ]
+}
+
+/// Creates markdown events showing a render error where its diagram would be
+///
+/// # Arguments
+/// * `message` - The error message, including D2's output
+pub fn error_events(message: &str) -> Vec<Event<'static>> {
+    vec![
+        Event::Start(Tag::HtmlBlock),
+        Event::Html(
+            format!(
+                "<pre class=\"{ERROR_CLASS}\">{}</pre>\n",
+                escape_attribute(message)
+            )
+            .into(),
+        ),
+        Event::End(TagEnd::HtmlBlock),
+    ]
+}
+
+/// Returns a diagram's options with the theme replaced for its dark variant
+///
+/// A `dark-theme` option on the diagram takes precedence over the configured
+/// dark theme.
+///
+/// # Arguments
+/// * `ctx` - The render context of the light diagram
+/// * `theme_id` - The configured dark theme
+fn dark_options(ctx: &RenderContext, theme_id: &str) -> Vec<(String, String)> {
+    let theme_id = ctx.option("dark-theme").unwrap_or(theme_id).to_string();
+    ctx.options
+        .iter()
+        .filter(|(name, _)| name != "theme")
+        .cloned()
+        .chain([(String::from("theme"), theme_id)])
+        .collect()
+}
+
+/// Returns the CSS classes of a diagram's image outside print builds
+const fn image_class(ctx: &RenderContext) -> &'static str {
+    if ctx.legend {
+        LEGEND_CLASS
+    } else {
+        DIAGRAM_CLASS
+    }
+}
+
+/// Wraps a diagram's image in a `<figure>` with a caption
+///
+/// The caption's inline markdown (emphasis, code, links) is rendered to HTML.
+///
+/// # Arguments
+/// * `events` - The image's events, holding its HTML markup
+/// * `figcaption` - The caption
+fn figure_events(events: &[Event<'static>], figcaption: &str) -> Vec<Event<'static>> {
+    let image: String = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::InlineHtml(html) => Some(html.as_ref()),
+            _ => None,
+        })
+        .collect();
+    let mut caption = String::new();
+    pulldown_cmark::html::push_html(&mut caption, Parser::new(figcaption));
+    let caption = caption
+        .trim()
+        .trim_start_matches("<p>")
+        .trim_end_matches("</p>");
+    vec![Event::Html(
+        format!("<figure>{image}<figcaption>{caption}</figcaption></figure>\n").into(),
+    )]
+}
+
+/// Escapes a value for use inside a double-quoted HTML attribute
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Creates markdown events for an image emitted as HTML
+///
+/// Emits the image as inline HTML so it can carry a CSS class and extra
+/// attributes, wrapped in a paragraph like [`create_image_events`].
+///
+/// # Arguments
+/// * `url` - The image URL (can be a file path or data URI)
+/// * `alt` - The image's alt text (may be empty)
+/// * `class` - The image's CSS class
+/// * `attributes` - Further attributes, each preceded by a space
+fn create_html_image_events(
+    url: &str,
+    alt: &str,
+    class: &str,
+    attributes: &str,
+) -> SmallVec<[Event<'static>; 5]> {
+    let html = format!(
+        r#"<img class="{class}" src="{}" alt="{}"{attributes} />"#,
+        escape_attribute(url),
+        escape_attribute(alt)
+    );
+    smallvec![
+        Event::Start(Tag::Paragraph),
+        Event::InlineHtml(html.into()),
         Event::End(TagEnd::Paragraph),
     ]
 }
 
+/// Quotes a path for use as a single shell argument
+fn shell_quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        format!("\"{path}\"")
+    } else {
+        format!("'{}'", path.replace('\'', r"'\''"))
+    }
+}
+
+/// Runs the post-render command on a generated diagram file
+///
+/// # Arguments
+/// * `ctx` - The render context for the diagram
+/// * `command` - The shell command, with `{file}` standing for the file
+/// * `path` - The generated diagram file
+///
+/// # Errors
+/// Returns an error if the command can't be run or exits unsuccessfully.
+fn run_post_render_command(ctx: &RenderContext, command: &str, path: &Path) -> anyhow::Result<()> {
+    let command = command.replace("{file}", &shell_quote(path));
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = std::process::Command::new(shell)
+        .args([flag, &command])
+        .output()
+        .with_context(|| format!("Failed to run post-render command `{command}`"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Post-render command `{command}` failed for D2 diagram ({}, #{}) with status {}:{}",
+            ctx.chapter,
+            ctx.diagram_index,
+            output.status,
+            format!("\n{stderr}").replace('\n', "\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Encodes image bytes as a base64 data URI
+///
+/// # Arguments
+/// * `format` - The image's format, setting the MIME type
+/// * `bytes` - The encoded image
+fn data_uri(format: OutputFormat, bytes: &[u8]) -> String {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        STANDARD.encode(bytes)
+    )
+}
+
+/// Strips a trailing D2 `#` comment from a line, ignoring `#` inside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('#', None) => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Fills in an alt text template for a diagram
+///
+/// Placeholders are substituted in a single pass, so placeholder-like text
+/// inside the caption is kept verbatim. Unknown placeholders, which
+/// configuration validation rejects, are left as they are. Images never go
+/// without alt text: an empty result falls back to the chapter name and
+/// diagram index, e.g. `Intro, diagram 2`.
+///
+/// # Arguments
+/// * `template` - The alt text template
+/// * `ctx` - The render context for the diagram
+/// * `caption` - The diagram's label or title (may be empty)
+fn alt_text(template: &str, ctx: &RenderContext, caption: &str) -> String {
+    let section = ctx
+        .section
+        .map(|section| section.to_string().trim_end_matches('.').to_string())
+        .unwrap_or_default();
+    let index = ctx.sub_index.map_or_else(
+        || ctx.diagram_index.to_string(),
+        |sub_index| format!("{}-{}", ctx.diagram_index, sub_index),
+    );
+    let figure = ctx.figure.map_or_else(
+        || {
+            if section.is_empty() {
+                index.clone()
+            } else {
+                format!("{section}.{index}")
+            }
+        },
+        ToString::to_string,
+    );
+
+    let alt = fill_template(
+        template,
+        &[
+            ("chapter", ctx.chapter),
+            ("section", &section),
+            ("index", &index),
+            ("figure", &figure),
+            ("caption", caption),
+        ],
+    );
+    let alt = alt.trim();
+    if alt.is_empty() {
+        format!("{}, diagram {index}", ctx.chapter)
+    } else {
+        alt.to_string()
+    }
+}
+
+/// Substitutes `{name}` placeholders in a template
+///
+/// Placeholders are substituted in a single pass, so placeholder-like text
+/// inside the values is kept verbatim. Unknown placeholders are left as
+/// they are.
+///
+/// # Arguments
+/// * `template` - The template to fill in
+/// * `values` - Each placeholder's name and value
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + end];
+        match values.iter().find(|(placeholder, _)| *placeholder == name) {
+            Some((_, value)) => filled.push_str(value),
+            None => filled.push_str(&rest[start..=start + end]),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Returns the source a checkpoint records for a diagram
+///
+/// Info string options change the rendered image as much as the content
+/// does, so they are appended as a trailing comment.
+///
+/// # Arguments
+/// * `ctx` - The render context for the diagram
+/// * `content` - The D2 diagram content
+fn checkpoint_source<'c>(ctx: &RenderContext, content: &'c str) -> Cow<'c, str> {
+    if ctx.options.is_empty() {
+        return Cow::Borrowed(content);
+    }
+    let options: Vec<_> = ctx
+        .options
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    Cow::Owned(format!("{content}\n# options: {}", options.join(" ")))
+}
+
+/// Writes a diagram file, replacing any previous version atomically
+///
+/// The bytes go to a temporary file in the output directory, which is then
+/// renamed into place, so readers never see a partially written image.
+/// Like other written files, the image gets the mode the umask allows rather
+/// than the temporary file's private one.
+///
+/// # Arguments
+/// * `output_path` - The directory holding the file
+/// * `filepath` - Where the file is written
+/// * `png_bytes` - The rendered PNG
+fn write_atomically(output_path: &Path, filepath: &Path, png_bytes: &[u8]) -> anyhow::Result<()> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".d2-").suffix(".tmp");
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let mut temp_file = builder.tempfile_in(output_path).with_context(|| {
+        format!(
+            "Failed to create temporary file in {}",
+            output_path.display()
+        )
+    })?;
+    temp_file
+        .write_all(png_bytes)
+        .with_context(|| format!("Failed to write diagram: {}", filepath.display()))?;
+    // Renaming within a directory is atomic; the temporary file is removed on failure
+    let temp_path = temp_file.into_temp_path();
+    fs::rename(&temp_path, filepath)
+        .with_context(|| format!("Failed to move diagram into place: {}", filepath.display()))?;
+    temp_path.keep()?;
+    Ok(())
+}
+
+/// Fills in the watermark template with the build date and git commit
+///
+/// The commit is `unknown` when the book isn't in a git repository.
+///
+/// # Arguments
+/// * `template` - The watermark template
+/// * `root_dir` - The book's root directory
+fn watermark_text(template: &str, root_dir: &Path) -> String {
+    let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+    let sha = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .current_dir(root_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map_or_else(
+            || String::from("unknown"),
+            |output| String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        );
+    fill_template(template, &[("date", &date), ("sha", &sha)])
+}
+
+/// Appends a watermark shape near the bottom-right corner of a diagram
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+/// * `text` - The watermark text
+fn add_watermark(content: &str, text: &str) -> String {
+    let text = text.replace('\\', r"\\").replace('"', r#"\""#);
+    format!(
+        "{content}\n{WATERMARK_SHAPE}: \"{text}\" {{\n  shape: text\n  near: bottom-right\n  \
+         style.font-size: 12\n  style.opacity: 0.6\n}}\n"
+    )
+}
+
+/// Extracts a diagram's top-level label or title
+///
+/// Looks for a `label: ...` or `title: ...` declaration outside of any block
+/// (a root label, or D2's conventional title shape) and returns its value
+/// with surrounding quotes removed. Block strings (`|md ...|`) are ignored.
+///
+/// # Arguments
+/// * `content` - The D2 diagram content
+fn diagram_label(content: &str) -> Option<String> {
+    let mut depth = 0usize;
+    for line in content.lines() {
+        let line = strip_comment(line).trim();
+        if depth == 0 {
+            if let Some((key, value)) = line.split_once(':') {
+                if matches!(key.trim(), "label" | "title") {
+                    let value = value.trim().trim_end_matches('{').trim();
+                    let value = value.trim_matches(|c| c == '"' || c == '\'');
+                    if !value.is_empty() && !value.starts_with('|') {
+                        return Some(value.to_string());
+                    }
+                }
+            }
+        }
+        depth += line.matches('{').count();
+        depth = depth.saturating_sub(line.matches('}').count());
+    }
+    None
+}
+
 impl Backend {
     /// Creates a new Backend instance
     ///
     /// # Arguments
     /// * `config` - Configuration for the D2 preprocessor
+    /// * `root_dir` - Absolute path to the book's root directory
     /// * `source_dir` - Absolute path to the book's source directory
-    pub fn new(config: Config, source_dir: PathBuf) -> Self {
+    /// * `renderer` - Rendering engine, or `None` to run the D2 binary
+    pub fn new(
+        config: Config,
+        root_dir: &Path,
+        source_dir: PathBuf,
+        renderer: Option<Arc<dyn DiagramRenderer>>,
+    ) -> Self {
+        let renderer =
+            renderer.unwrap_or_else(|| Arc::new(D2Cli::new(&config, root_dir, &source_dir)));
+        let cache = renderer
+            .cache_key()
+            .filter(|_| config.cache)
+            .map(|fingerprint| {
+                RenderCache::new(
+                    root_dir.join(RENDER_CACHE_DIR),
+                    config.format.extension(),
+                    fingerprint,
+                    config.cache_namespace.clone(),
+                )
+            });
+
+        let picture_dark_theme_id = config.picture_dark_theme_id().cloned();
+
+        let language = config.language.as_deref().unwrap_or(DEFAULT_LANGUAGE);
         let paths = PathConfig {
-            d2_binary: config.path,
-            output_dir: config.output_dir,
+            output_dir: PathBuf::from(fill_template(
+                &config.output_dir.to_string_lossy(),
+                &[("lang", language)],
+            )),
+            js_data_file: config.js_data_file.map(|file| source_dir.join(file)),
+            checkpoint_file: config.checkpoint_file.map(|file| root_dir.join(file)),
             source_dir,
+            failure_dir: config
+                .dump_failures
+                .then(|| root_dir.join(FAILED_DIAGRAMS_DIR)),
         };
 
         let render = RenderConfig {
-            layout: config.layout,
             inline: config.inline,
-            fonts: config.fonts,
-            theme_id: config.theme_id,
-            dark_theme_id: config.dark_theme_id,
+            split_delimiter: config.split_delimiter,
+            alt_from_label: config.alt_from_label,
+            alt_template: config.alt_template,
+            embed_srgb: config.embed_srgb,
+            stable_svg_ids: config.stable_svg_ids,
+            autocrop: config.autocrop,
+            autocrop_background: config.autocrop_background.map(|color| color.0),
+            palette: config.palette,
+            resize: config.resize,
+            resize_fit: config.resize_fit,
+            warn_complexity_nodes: config.warn_complexity_nodes,
+            min_image_size: config.min_image_size,
+            tiny_image: config.tiny_image,
+            eager_count: config.eager_count.or_else(|| config.lazy.then_some(0)),
+            warn_duplicates: config.warn_duplicates,
+            print: config.print,
+            natural_size_attributes: config.natural_size_attributes,
+            size: (config.width, config.height),
+            format: config.format,
+            quality: config.quality,
+            decoding_async: config.decoding_async,
+            zoomable: config.zoomable,
+            batch_writes: config.batch_writes,
+            hashed_filenames: config.hashed_filenames,
+            container_class: config.container_class,
+            post_render_command: config.post_render_command,
+            og_image_base_url: config.og_image_base_url,
+            watermark: config
+                .watermark
+                .map(|template| watermark_text(&template, root_dir)),
+            picture_dark_theme_id,
         };
 
-        Self { paths, render }
+        Self {
+            paths,
+            render,
+            renderer,
+            cache,
+            cache_mode: config.cache_mode,
+            quiet: config.quiet,
+            concurrency_ramp: config.concurrency_ramp.map(Duration::from_secs),
+            max_concurrency: config.max_concurrency,
+            max_concurrent_nodes: config.max_concurrent_nodes,
+            layout: config.layout.unwrap_or_else(|| DEFAULT_LAYOUT.to_string()),
+            numbering_scope: config.numbering_scope,
+            dedup_scope: config.dedup_scope,
+            changed_since: config.changed_since,
+            layout_concurrency: config.layout_concurrency,
+            sequential: any_env_set(&config.no_parallel_env),
+            total_timeout: config.total_timeout.map(Duration::from_secs),
+            max_diagrams: config.max_diagrams,
+            max_diagrams_per_chapter: config.max_diagrams_per_chapter,
+            only_tags: config.only_tags,
+            languages: std::iter::once(config.code_block_lang)
+                .chain(config.code_block_aliases)
+                .collect(),
+            on_error: if config.strict {
+                RenderErrorBehavior::Fail
+            } else {
+                config.on_error
+            },
+            on_missing_file: if config.strict {
+                RenderErrorBehavior::Fail
+            } else {
+                config.on_missing_file.unwrap_or(config.on_error)
+            },
+            js_data: Mutex::default(),
+            pending_files: Mutex::default(),
+            generated_files: Mutex::default(),
+        }
     }
 
-    /// Creates a Backend instance from a [`PreprocessorContext`]
+    /// Creates a Backend instance from a book's configuration
     ///
     /// # Arguments
-    /// * `ctx` - The preprocessor context
+    /// * `book_config` - The book's `book.toml` configuration
+    /// * `root` - Absolute path to the book's root directory
+    /// * `renderer` - Rendering engine, or `None` to run the D2 binary
     ///
-    /// # Panics
-    /// Panics if the d2-png preprocessor configuration is missing or invalid in book.toml
-    pub fn from_context(ctx: &PreprocessorContext) -> Self {
-        let config: Config = ctx
-            .config
-            .get_deserialized_opt(PREPROCESSOR_CONFIG_KEY)
-            .unwrap_or_else(|e| {
-                panic!("Unable to deserialize d2-png preprocessor config: {e}")
-            })
-            .unwrap_or_else(|| {
-                panic!(
-                    "d2-png preprocessor config not found. Add [{PREPROCESSOR_CONFIG_KEY}] section to book.toml"
-                )
-            });
-        let source_dir = ctx.root.join(&ctx.config.book.src);
-
-        Self::new(config, source_dir)
+    /// # Errors
+    /// Returns an error if the d2-png preprocessor configuration is missing
+    /// or invalid.
+    pub fn from_book_config(
+        book_config: &mdbook::Config,
+        root: &Path,
+        renderer: Option<Arc<dyn DiagramRenderer>>,
+    ) -> anyhow::Result<Self> {
+        let config = Self::load_config(book_config, root)?;
+        Ok(Self::new(
+            config,
+            root,
+            root.join(&book_config.book.src),
+            renderer,
+        ))
     }
 
-    /// Returns the relative path to the output directory
+    /// Loads the d2-png preprocessor configuration from a book's configuration
+    ///
+    /// # Arguments
+    /// * `book_config` - The book's `book.toml` configuration
+    /// * `root` - Absolute path to the book's root directory
+    ///
+    /// # Errors
+    /// Returns an error if the d2-png preprocessor configuration is missing
+    /// or invalid.
+    pub fn load_config(book_config: &mdbook::Config, root: &Path) -> anyhow::Result<Config> {
+        let table: toml::Table = book_config
+            .get_deserialized_opt(PREPROCESSOR_CONFIG_KEY)
+            .context("Unable to deserialize d2-png preprocessor config")?
+            .with_context(|| {
+                format!(
+                    "d2-png preprocessor config not found. Add [{PREPROCESSOR_CONFIG_KEY}] section to book.toml"
+                )
+            })?;
+        let mut config =
+            Config::from_table(table, root).context("Unable to load d2-png preprocessor config")?;
+        if config.language.is_none() {
+            config.language.clone_from(&book_config.book.language);
+        }
+        Ok(config)
+    }
+
+    /// Checks that the renderer is usable, returning its version string if any
+    ///
+    /// The version is folded into the render cache's keys.
+    ///
+    /// # Errors
+    /// Returns an error if the renderer can't be used.
+    pub fn preflight(&self) -> anyhow::Result<Option<String>> {
+        let version = self.renderer.preflight()?;
+        if let (Some(cache), Some(version)) = (&self.cache, &version) {
+            cache.set_version(version);
+        }
+        Ok(version)
+    }
+
+    /// Returns whether messages at the given level should be logged
+    ///
+    /// Quiet mode keeps errors but drops warnings and informational messages.
+    pub fn log_enabled(&self, level: Level) -> bool {
+        log_enabled(self.quiet, level)
+    }
+
+    /// Returns the time over which to ramp up concurrent renders, if configured
+    pub const fn concurrency_ramp(&self) -> Option<Duration> {
+        self.concurrency_ramp
+    }
+
+    /// Returns the maximum number of concurrent renders, if configured
+    pub const fn max_concurrency(&self) -> Option<usize> {
+        self.max_concurrency
+    }
+
+    /// Returns the maximum total node count rendered at once, if configured
+    pub const fn max_concurrent_nodes(&self) -> Option<usize> {
+        self.max_concurrent_nodes
+    }
+
+    /// Returns whether diagrams are zoomable, needing [`ZOOM_SNIPPET`]
+    pub const fn zoomable(&self) -> bool {
+        self.render.zoomable
+    }
+
+    /// Returns the maximum number of concurrent renders per layout engine
+    pub const fn layout_concurrency(&self) -> &BTreeMap<String, usize> {
+        &self.layout_concurrency
+    }
+
+    /// Returns the scope within which figure numbers count up
+    pub const fn numbering_scope(&self) -> NumberingScope {
+        self.numbering_scope
+    }
+
+    /// Returns the scope within which identical diagrams share one image
+    pub const fn dedup_scope(&self) -> DedupScope {
+        self.dedup_scope
+    }
+
+    /// Returns a chapter file's contents at the `changed-since` baseline
+    ///
+    /// Returns `None` if no baseline is configured. A chapter that didn't
+    /// exist at the baseline is empty.
+    ///
+    /// # Arguments
+    /// * `chapter_path` - The chapter file's path, relative to the source directory
+    pub fn baseline_file(&self, chapter_path: &Path) -> Option<String> {
+        let git_ref = self.changed_since.as_deref()?;
+        Some(
+            crate::baseline::file_at(&self.paths.source_dir, git_ref, chapter_path)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Reads a diagram source file referenced from a chapter
+    ///
+    /// # Arguments
+    /// * `chapter_path` - The chapter file's path, relative to the source directory
+    /// * `file` - The diagram file's path, relative to the chapter
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read.
+    pub fn read_diagram_file(&self, chapter_path: &Path, file: &str) -> anyhow::Result<String> {
+        let chapter_dir = chapter_path.parent().map_or_else(
+            || self.paths.source_dir.clone(),
+            |dir| self.paths.source_dir.join(dir),
+        );
+        let path = chapter_dir.join(file);
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    /// Checks that the `changed-since` ref exists, if configured
+    ///
+    /// # Errors
+    /// Returns an error if the ref doesn't name a commit.
+    pub fn check_changed_since(&self) -> anyhow::Result<()> {
+        self.changed_since.as_ref().map_or(Ok(()), |git_ref| {
+            crate::baseline::verify_ref(&self.paths.source_dir, git_ref)
+        })
+    }
+
+    /// Opens the progress file of a resumable build, if enabled
+    ///
+    /// Checkpoints only apply when images are written as files.
+    ///
+    /// # Errors
+    /// Returns an error if the progress file can't be read or opened.
+    pub fn open_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        if self.render.inline || self.paths.js_data_file.is_some() {
+            return Ok(None);
+        }
+        self.paths
+            .checkpoint_file
+            .as_deref()
+            .map(Checkpoint::open)
+            .transpose()
+    }
+
+    /// Returns whether diagrams must render one at a time
+    ///
+    /// Set when one of the `no-parallel-env` variables is present.
+    pub const fn sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// Returns the layout engine a diagram is rendered with
+    ///
+    /// # Arguments
+    /// * `content` - The D2 diagram content
+    /// * `layout` - The layout set in the code block's info string, if any
+    pub fn effective_layout<'a>(&'a self, content: &'a str, layout: Option<&'a str>) -> &'a str {
+        declared_layout(content).or(layout).unwrap_or(&self.layout)
+    }
+
+    /// Estimates a diagram's rendering weight from its node count
+    ///
+    /// # Arguments
+    /// * `content` - The D2 diagram content
+    pub fn diagram_weight(content: &str) -> usize {
+        count_nodes(content).max(1)
+    }
+
+    /// Returns the time limit for rendering all diagrams, if configured
+    pub const fn total_timeout(&self) -> Option<Duration> {
+        self.total_timeout
+    }
+
+    /// Returns the maximum number of diagrams to render, if configured
+    pub const fn max_diagrams(&self) -> Option<usize> {
+        self.max_diagrams
+    }
+
+    /// Returns the maximum number of diagrams in a single chapter, if configured
+    pub const fn max_diagrams_per_chapter(&self) -> Option<usize> {
+        self.max_diagrams_per_chapter
+    }
+
+    /// Returns the tags selecting the diagrams to render; empty renders all
+    pub fn only_tags(&self) -> &[String] {
+        &self.only_tags
+    }
+
+    /// Returns whether a code block language tag marks a D2 diagram
+    pub fn is_d2_language(&self, language: &str) -> bool {
+        self.languages.iter().any(|tag| tag == language)
+    }
+
+    /// Returns what to do with a diagram that fails to render
+    pub const fn on_error(&self) -> RenderErrorBehavior {
+        self.on_error
+    }
+
+    /// Returns what to do with a diagram whose `file` can't be read
+    pub const fn on_missing_file(&self) -> RenderErrorBehavior {
+        self.on_missing_file
+    }
+
+    /// Returns whether byte-identical diagrams should be reported
+    pub const fn warn_duplicates(&self) -> bool {
+        self.render.warn_duplicates
+    }
+
+    /// Splits a code block into the diagrams it defines
+    ///
+    /// Returns the content as a single diagram unless a split delimiter is
+    /// configured and appears on a line of its own.
+    ///
+    /// # Arguments
+    /// * `content` - The code block content
+    pub fn split_diagrams(&self, content: &str) -> Vec<String> {
+        self.render.split_delimiter.as_ref().map_or_else(
+            || vec![content.to_string()],
+            |delimiter| split_on_delimiter(content, delimiter),
+        )
+    }
+
+    /// Splits a `legend` code block into its diagrams and trailing legend
+    ///
+    /// The legend is the part after the last delimiter line, using the split
+    /// delimiter if one is configured and `---` otherwise. Without a
+    /// delimiter, the block has no legend.
+    ///
+    /// # Arguments
+    /// * `content` - The code block content
+    pub fn split_legend(&self, content: &str) -> (Vec<String>, Option<String>) {
+        let delimiter = self
+            .render
+            .split_delimiter
+            .as_deref()
+            .unwrap_or(DEFAULT_LEGEND_DELIMITER);
+        let mut parts = split_on_delimiter(content, delimiter);
+        let legend = if parts.len() > 1 { parts.pop() } else { None };
+        (parts, legend)
+    }
+
+    /// Returns the relative path to the output directory
     fn output_dir(&self) -> &Path {
         &self.paths.output_dir
     }
@@ -223,216 +1576,891 @@ impl Backend {
     /// # Arguments
     /// * `ctx` - The render context for the diagram
     fn relative_file_path(&self, ctx: &RenderContext) -> PathBuf {
-        self.paths.output_dir.join(filename(ctx))
+        self.paths
+            .output_dir
+            .join(filename(ctx, self.render.format))
     }
 
     /// Renders a D2 diagram and returns the appropriate markdown events
     ///
+    /// The rendered image is returned as well, so diagrams identical to this
+    /// one can reuse it with [`Backend::render_duplicate`].
+    ///
+    /// Diagram files get a dark variant as well when a `<picture>` theme is
+    /// configured, and the `<picture>` the larger of both variants' sizes.
+    ///
     /// # Arguments
     /// * `ctx` - The render context for the diagram
     /// * `content` - The D2 diagram content
+    /// * `checkpoint` - Progress of a resumable build, if enabled
     pub fn render(
         &self,
         ctx: &RenderContext,
         content: &str,
-    ) -> anyhow::Result<Vec<Event<'static>>> {
-        if self.render.inline {
-            self.render_inline_png(ctx, content).map(SmallVec::into_vec)
+        checkpoint: Option<&Checkpoint>,
+    ) -> anyhow::Result<(Vec<Event<'static>>, RenderedImage)> {
+        if let Some(threshold) = self.render.warn_complexity_nodes {
+            let nodes = count_nodes(content);
+            if nodes > threshold && self.log_enabled(Level::Warn) {
+                job_log::log(
+                    Level::Warn,
+                    format!(
+                        "D2 diagram ({}, #{}) declares about {nodes} nodes, more than the \
+                         configured threshold of {threshold}. Consider splitting it into smaller \
+                         diagrams.",
+                        ctx.chapter, ctx.diagram_index
+                    ),
+                );
+            }
+        }
+
+        let image = if self.paths.js_data_file.is_some() {
+            let png_bytes = self.render_png(ctx, content)?;
+            self.js_data
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(diagram_id(ctx), data_uri(self.render.format, &png_bytes));
+            RenderedImage::new(png_bytes)
+        } else if self.render.inline {
+            RenderedImage::new(self.render_png(ctx, content)?)
         } else {
-            self.render_embedded_png(ctx, content).map(SmallVec::into_vec)
+            let mut image = RenderedImage::new(self.generate_diagram(ctx, content, checkpoint)?);
+            image.content_hashes.0 = self.content_hash(&image.png_bytes);
+            if let Some(theme_id) = &self.render.picture_dark_theme_id {
+                let options = dark_options(ctx, theme_id);
+                let dark_ctx = ctx.with_options(&options).with_dark(true);
+                let dark_png_bytes = self.generate_diagram(&dark_ctx, content, checkpoint)?;
+                image.picture_size = Self::picture_size(&image.png_bytes, &dark_png_bytes);
+                image.content_hashes.1 = self.content_hash(&dark_png_bytes);
+            }
+            image
+        };
+
+        Ok((self.diagram_events(ctx, ctx, content, &image), image))
+    }
+
+    /// Returns the hash added to the name of a diagram file, with `hashed-filenames`
+    ///
+    /// # Arguments
+    /// * `png_bytes` - The file's contents
+    fn content_hash(&self, png_bytes: &[u8]) -> Option<String> {
+        self.render.hashed_filenames.then(|| {
+            let mut hash = sha256_hex(png_bytes);
+            hash.truncate(FILENAME_HASH_LEN);
+            hash
+        })
+    }
+
+    /// Returns the display size of a `<picture>` with a dark variant
+    ///
+    /// This is the larger of both variants' widths and heights. `None` if
+    /// either isn't a readable PNG.
+    ///
+    /// # Arguments
+    /// * `png_bytes` - The light variant's PNG
+    /// * `dark_png_bytes` - The dark variant's PNG
+    fn picture_size(png_bytes: &[u8], dark_png_bytes: &[u8]) -> Option<(u32, u32)> {
+        let (width, height) = crate::png::dimensions(png_bytes).ok()?;
+        let (dark_width, dark_height) = crate::png::dimensions(dark_png_bytes).ok()?;
+        Some((width.max(dark_width), height.max(dark_height)))
+    }
+
+    /// Returns the markdown events for a diagram identical to a rendered one
+    ///
+    /// The image itself (file, data URI or JavaScript data entry) is shared
+    /// with the original, while alt text, loading hints and the relative path
+    /// to a shared file are the duplicate's own.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the duplicate diagram
+    /// * `original` - The render context the image was rendered with
+    /// * `content` - The D2 diagram content
+    /// * `image` - The original's rendered image
+    pub fn render_duplicate(
+        &self,
+        ctx: &RenderContext,
+        original: &RenderContext,
+        content: &str,
+        image: &RenderedImage,
+    ) -> Vec<Event<'static>> {
+        self.diagram_events(ctx, original, content, image)
+    }
+
+    /// Creates the markdown events showing a rendered image in a chapter
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context of the diagram being shown
+    /// * `image_ctx` - The render context the image was rendered with
+    /// * `content` - The D2 diagram content
+    /// * `image` - The rendered image
+    fn diagram_events(
+        &self,
+        ctx: &RenderContext,
+        image_ctx: &RenderContext,
+        content: &str,
+        image: &RenderedImage,
+    ) -> Vec<Event<'static>> {
+        let png_bytes = &image.png_bytes;
+        let file_ctx = image_ctx.with_content_hash(image.content_hashes.0.as_deref());
+        let caption = match ctx.alt {
+            Some(alt) => alt.to_string(),
+            None if self.render.alt_from_label => diagram_label(content).unwrap_or_default(),
+            None => String::new(),
+        };
+        let alt = alt_text(&self.render.alt_template, ctx, &caption);
+
+        let (mut events, og_image_url) = if self.paths.js_data_file.is_some() {
+            let events = self.js_data_events(ctx, &diagram_id(image_ctx), &alt, png_bytes);
+            (events.into_vec(), None)
+        } else if self.render.inline {
+            let events = self.image_events(
+                ctx,
+                data_uri(self.render.format, png_bytes),
+                &alt,
+                png_bytes,
+            );
+            (events.into_vec(), None)
+        } else {
+            let url = self
+                .calculate_relative_path_for_chapter(ctx, &file_ctx)
+                .to_string_lossy()
+                .replace('\\', "/");
+            let dark_url = self.render.picture_dark_theme_id.is_some().then(|| {
+                let dark_ctx = image_ctx
+                    .with_dark(true)
+                    .with_content_hash(image.content_hashes.1.as_deref());
+                self.calculate_relative_path_for_chapter(ctx, &dark_ctx)
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            });
+            // A configured size wins over the picture's own
+            let picture_ctx = match image.picture_size {
+                Some((width, height))
+                    if ctx.size == (None, None) && self.render.size == (None, None) =>
+                {
+                    ctx.with_size(Some(width), Some(height))
+                }
+                _ => *ctx,
+            };
+            let events = self.picture_events(
+                &picture_ctx,
+                url.clone(),
+                dark_url.as_deref(),
+                &alt,
+                png_bytes,
+            );
+            (events.into_vec(), ctx.og_image.then_some(url))
+        };
+
+        if let Some(figcaption) = ctx.figcaption {
+            events = figure_events(&events, figcaption);
+        }
+        if let Some(url) = og_image_url {
+            events.insert(0, self.og_image_event(&file_ctx, url));
         }
+        events
+    }
+
+    /// Creates the `og:image` meta tag pointing at a chapter's Open Graph image
+    ///
+    /// # Arguments
+    /// * `image_ctx` - The render context the image file was written for
+    /// * `url` - The image's URL relative to the chapter, used without a base URL
+    fn og_image_event(&self, image_ctx: &RenderContext, url: String) -> Event<'static> {
+        let url = self
+            .render
+            .og_image_base_url
+            .as_ref()
+            .map_or(url, |base_url| {
+                let path = self
+                    .relative_file_path(image_ctx)
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                format!("{}/{path}", base_url.trim_end_matches('/'))
+            });
+        Event::Html(
+            format!(
+                "<meta property=\"og:image\" content=\"{}\">\n",
+                escape_attribute(&url)
+            )
+            .into(),
+        )
     }
 
     /// Generates a D2 diagram PNG file
     ///
-    /// Creates the output directory if needed, builds command arguments,
-    /// and executes the D2 process to generate the PNG file.
+    /// Creates the output directory if needed, renders the diagram and
+    /// writes the PNG file. The file is written under a temporary name in the
+    /// same directory and renamed into place, so `mdbook serve` and other
+    /// readers never observe a partially written image. The post-render
+    /// command, if configured, then runs on the file. With `batch-writes`,
+    /// the PNG is buffered for [`Backend::write_pending_files`] instead.
+    ///
+    /// With `hashed-filenames`, the file is named after a hash of the PNG.
+    /// A file that a checkpoint records as completed from the same source is
+    /// kept as it is; otherwise the completed file is recorded.
     ///
     /// # Arguments
     /// * `ctx` - The render context for the diagram
     /// * `content` - The D2 diagram content
+    /// * `checkpoint` - Progress of a resumable build, if enabled
     ///
     /// # Returns
-    /// The absolute path to the generated PNG file
+    /// The bytes of the generated PNG file
     fn generate_diagram(
         &self,
         ctx: &RenderContext,
         content: &str,
-    ) -> anyhow::Result<PathBuf> {
-        use std::fs;
-
+        checkpoint: Option<&Checkpoint>,
+    ) -> anyhow::Result<Vec<u8>> {
         // Ensure output directory exists
         let output_path = self.paths.source_dir.join(self.output_dir());
-        fs::create_dir_all(&output_path)
-            .with_context(|| format!("Failed to create output directory: {}", output_path.display()))?;
+        fs::create_dir_all(&output_path).with_context(|| {
+            format!(
+                "Failed to create output directory: {}",
+                output_path.display()
+            )
+        })?;
 
-        // Build command arguments and execute D2
-        let mut args = self.basic_args();
         let filepath = self.filepath(ctx);
-        args.push(filepath.as_os_str());
+        let checkpoint_key = self
+            .relative_file_path(ctx)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let source = checkpoint_source(ctx, content);
+        if let Some(png_bytes) = checkpoint
+            .filter(|checkpoint| checkpoint.is_done(&checkpoint_key, &source))
+            .and_then(|_| fs::read(&filepath).ok())
+        {
+            self.record_generated(ctx, &checkpoint_key, &source, sha256_hex(&png_bytes));
+            return Ok(png_bytes);
+        }
 
-        // When writing to file, D2 outputs nothing to stdout
-        let _ = self.run_process(ctx, content, args)?;
+        let png_bytes = self.render_png(ctx, content)?;
+        let content_hash = self.content_hash(&png_bytes);
+        let ctx = &ctx.with_content_hash(content_hash.as_deref());
+        let filepath = self.filepath(ctx);
+        let path = self
+            .relative_file_path(ctx)
+            .to_string_lossy()
+            .replace('\\', "/");
+        if self.render.batch_writes {
+            self.record_generated(ctx, &path, &source, sha256_hex(&png_bytes));
+            self.pending_files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(PendingFile {
+                    path: filepath,
+                    checkpoint_key,
+                    content: source.into_owned(),
+                    png_bytes: png_bytes.clone(),
+                });
+            return Ok(png_bytes);
+        }
+        write_atomically(&output_path, &filepath, &png_bytes)?;
+
+        let integrity = if let Some(command) = &self.render.post_render_command {
+            run_post_render_command(ctx, command, &filepath)?;
+            // The command may have rewritten the file, e.g. to optimize it
+            let written = fs::read(&filepath)
+                .with_context(|| format!("Failed to read diagram: {}", filepath.display()))?;
+            sha256_hex(&written)
+        } else {
+            sha256_hex(&png_bytes)
+        };
+        self.record_generated(ctx, &path, &source, integrity);
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.record(&checkpoint_key, &source)?;
+        }
 
-        Ok(filepath)
+        Ok(png_bytes)
     }
 
-    fn render_inline_png(
+    /// Records a diagram's file for the manifest
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `path` - The file's path relative to the source directory
+    /// * `source` - The diagram's source as recorded in checkpoints
+    /// * `integrity` - SHA-256 of the file as written, in hex
+    fn record_generated(&self, ctx: &RenderContext, path: &str, source: &str, integrity: String) {
+        let entry = ManifestEntry {
+            path: path.to_string(),
+            chapter: ctx.chapter.to_string(),
+            section: ctx
+                .section
+                .map(|section| section.to_string().trim_end_matches('.').to_string()),
+            diagram_index: ctx.diagram_index,
+            sub_index: ctx.sub_index,
+            hash: source_hash(source),
+            integrity,
+        };
+        self.generated_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(entry);
+    }
+
+    /// Creates a placeholder image referencing a JavaScript data file entry
+    ///
+    /// The entry's data URI is stored by [`Backend::render`] and written by
+    /// [`Backend::write_js_data`].
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `id` - ID of the image's entry in the data file
+    /// * `alt` - The image's alt text (may be empty)
+    /// * `png_bytes` - The rendered PNG
+    fn js_data_events(
         &self,
         ctx: &RenderContext,
-        content: &str,
-    ) -> anyhow::Result<SmallVec<[Event<'static>; 4]>> {
-        use base64::engine::general_purpose::STANDARD;
-        use base64::Engine;
+        id: &str,
+        alt: &str,
+        png_bytes: &[u8],
+    ) -> SmallVec<[Event<'static>; 5]> {
+        let attributes = format!(
+            r#" data-d2-id="{}"{}"#,
+            escape_attribute(id),
+            self.screen_attributes(ctx, png_bytes)
+        );
+        let html = self.wrap_image(format!(
+            r#"<img class="{}" alt="{}"{attributes} />"#,
+            image_class(ctx),
+            escape_attribute(alt)
+        ));
+        smallvec![
+            Event::Start(Tag::Paragraph),
+            Event::InlineHtml(html.into()),
+            Event::End(TagEnd::Paragraph),
+        ]
+    }
+
+    /// Checks up front that diagram files can be written, if they are
+    ///
+    /// Creates the output directory and a probe file in it, so a read-only
+    /// source tree fails once with an actionable message instead of once per
+    /// diagram. Nothing is checked when images are embedded as data URIs.
+    ///
+    /// # Errors
+    /// Returns an error if the output directory isn't writable.
+    pub fn check_output_dir(&self) -> anyhow::Result<()> {
+        if self.render.inline || self.paths.js_data_file.is_some() {
+            return Ok(());
+        }
+        let output_path = self.paths.source_dir.join(self.output_dir());
+        fs::create_dir_all(&output_path)
+            .and_then(|()| {
+                tempfile::Builder::new()
+                    .prefix(".d2-")
+                    .tempfile_in(&output_path)
+            })
+            .with_context(|| {
+                format!(
+                    "D2 output directory {} isn't writable. Point `output-dir` at a writable \
+                     location or set `inline = true` to embed diagrams as data URIs",
+                    output_path.display()
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Writes the collected data URIs to the JavaScript data file, if enabled
+    ///
+    /// The file is an ES module whose default export maps diagram IDs to
+    /// data URIs.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be written.
+    pub fn write_js_data(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.paths.js_data_file else {
+            return Ok(());
+        };
+        let js_data = self.js_data.lock().unwrap_or_else(PoisonError::into_inner);
+        let module = format!(
+            "export default {};\n",
+            serde_json::to_string_pretty(&*js_data)?
+        );
+        drop(js_data);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        fs::write(path, module)
+            .with_context(|| format!("Failed to write diagram data file: {}", path.display()))
+    }
+
+    /// Writes the diagram files buffered with `batch-writes`, in one pass
+    ///
+    /// # Arguments
+    /// * `checkpoint` - Progress of a resumable build, recording each file written
+    ///
+    /// # Errors
+    /// Returns an error if a file can't be written.
+    pub fn write_pending_files(&self, checkpoint: Option<&Checkpoint>) -> anyhow::Result<()> {
+        let pending = std::mem::take(
+            &mut *self
+                .pending_files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let output_path = self.paths.source_dir.join(self.output_dir());
+        for file in pending {
+            write_atomically(&output_path, &file.path, &file.png_bytes)?;
+            if let Some(checkpoint) = checkpoint {
+                checkpoint.record(&file.checkpoint_key, &file.content)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the manifest of generated diagram files, if they are files
+    ///
+    /// Nothing is written when images are embedded, or when no diagram
+    /// generated a file and the output directory doesn't exist. The files
+    /// listed by earlier builds are carried over from the previous manifest.
+    ///
+    /// # Arguments
+    /// * `partial` - Whether the build left diagrams out, e.g. failed ones
+    ///
+    /// # Errors
+    /// Returns an error if the manifest can't be written.
+    pub fn write_manifest(&self, partial: bool) -> anyhow::Result<()> {
+        if self.render.inline || self.paths.js_data_file.is_some() {
+            return Ok(());
+        }
+        let mut files = std::mem::take(
+            &mut *self
+                .generated_files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        let output_path = self.paths.source_dir.join(self.output_dir());
+        if files.is_empty() && !output_path.is_dir() {
+            return Ok(());
+        }
+        files.sort();
+        files.dedup_by(|a, b| a.path == b.path);
+        fs::create_dir_all(&output_path).with_context(|| {
+            format!(
+                "Failed to create output directory: {}",
+                output_path.display()
+            )
+        })?;
+        let previous = Manifest::read(&output_path)?;
+        Manifest::new(files, previous, partial).write(&output_path)
+    }
+
+    /// Removes images in the output directory that an earlier build generated
+    /// but the latest one didn't
+    ///
+    /// # Arguments
+    /// * `dry_run` - Only list the files instead of removing them
+    ///
+    /// # Returns
+    /// The stale files, removed unless `dry_run` is set
+    ///
+    /// # Errors
+    /// Returns an error if no build has written a manifest yet, the latest
+    /// build was partial, or a file can't be removed.
+    pub fn clean(&self, dry_run: bool) -> anyhow::Result<Vec<PathBuf>> {
+        let output_path = self.paths.source_dir.join(self.output_dir());
+        let mut manifest = Manifest::read(&output_path)?.with_context(|| {
+            format!(
+                "No {MANIFEST_FILE} found in {}. Build the book first, so the generated \
+                 diagram files are known",
+                output_path.display()
+            )
+        })?;
+        let stale = manifest.stale_files(&output_path)?;
+        if !dry_run {
+            for path in &stale {
+                fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+            manifest.forget(&stale);
+            manifest.write(&output_path)?;
+        }
+        Ok(stale)
+    }
 
-        // For inline mode, don't specify an output file - D2 will output PNG to stdout
-        let args = self.basic_args();
-        let png_bytes = self.run_process(ctx, content, args)?;
+    /// Renders a diagram to PNG bytes and post-processes them
+    ///
+    /// The watermark, if configured, is added to the content first. If
+    /// rendering fails and failure dumps are enabled, the content is written
+    /// to the failed diagrams directory for local reproduction.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `content` - The D2 diagram content
+    pub fn render_png(&self, ctx: &RenderContext, content: &str) -> anyhow::Result<Vec<u8>> {
+        let content = self
+            .render
+            .watermark
+            .as_ref()
+            .map_or(Cow::Borrowed(content), |text| {
+                Cow::Owned(add_watermark(content, text))
+            });
+        let png_bytes = self.render_cached(ctx, &content).inspect_err(|_| {
+            if let Some(failure_dir) = &self.paths.failure_dir {
+                let path = failure_dir.join(failure_filename(ctx));
+                let written = fs::create_dir_all(failure_dir)
+                    .and_then(|()| fs::write(&path, content.as_bytes()));
+                if self.log_enabled(Level::Warn) {
+                    let message = match written {
+                        Ok(()) => format!("Wrote failing D2 diagram input to {}", path.display()),
+                        Err(e) => format!(
+                            "Failed to write failing D2 diagram input to {}: {e}",
+                            path.display()
+                        ),
+                    };
+                    job_log::log(Level::Warn, message);
+                }
+            }
+        })?;
+        self.postprocess(ctx, &png_bytes)
+    }
+
+    /// Renders a diagram, reusing the image from an earlier build if cached
+    ///
+    /// The renderer's diagnostics are stored with the image and replayed when
+    /// it is reused, so warnings are still logged, or fail the diagram with
+    /// `warnings-as-errors`. Failing to store an image in the cache only logs
+    /// a warning. Diagrams importing other files are never cached, as the
+    /// imported files aren't part of the cache key, and with
+    /// `cache-mode = "opt-in"` only diagrams tagged `cache` are.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `content` - The D2 diagram content, as passed to the renderer
+    fn render_cached(&self, ctx: &RenderContext, content: &str) -> anyhow::Result<Vec<u8>> {
+        let Some(cache) = self
+            .cache
+            .as_ref()
+            .filter(|_| self.cache_mode == CacheMode::All || ctx.cache)
+            .filter(|_| !has_imports(content))
+        else {
+            return self.renderer.render(content, ctx);
+        };
+        let path = cache.path(content, ctx.options);
+        if let Some((bytes, diagnostics)) = RenderCache::get(&path) {
+            self.renderer.replay_diagnostics(ctx, &diagnostics)?;
+            return Ok(bytes);
+        }
+        let (bytes, diagnostics) = self.renderer.render_with_diagnostics(content, ctx)?;
+        if let Err(e) = cache.put(&path, &bytes, &diagnostics) {
+            if self.log_enabled(Level::Warn) {
+                job_log::log(Level::Warn, format!("Failed to cache D2 diagram: {e:#}"));
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Checks rendered PNG bytes and applies configured post-render transforms
+    ///
+    /// SVG images only have their element IDs rewritten, with `stable-svg-ids`.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `png_bytes` - The PNG produced by D2
+    fn postprocess(&self, ctx: &RenderContext, png_bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if self.render.format == OutputFormat::Svg {
+            if !self.render.stable_svg_ids {
+                return Ok(png_bytes.to_vec());
+            }
+            let svg = std::str::from_utf8(png_bytes)
+                .context("D2 produced an SVG that isn't valid UTF-8")?;
+            return Ok(crate::svg::stabilize_ids(svg).into_bytes());
+        }
+        let mut png_bytes = Cow::Borrowed(png_bytes);
+        let scale = self.render.quality.scale();
+        if scale > 1 {
+            let downsampled = crate::raster::downsample(&png_bytes, scale)
+                .context("Failed to downsample diagram")?;
+            png_bytes = Cow::Owned(downsampled);
+        }
+        self.check_dimensions(ctx, &png_bytes)?;
+
+        if self.render.autocrop {
+            let cropped = crate::raster::autocrop(&png_bytes, self.render.autocrop_background)
+                .context("Failed to crop diagram")?;
+            png_bytes = Cow::Owned(cropped);
+        }
+        if let Some(size) = self.render.resize {
+            let resized = crate::raster::resize(&png_bytes, size, self.render.resize_fit)
+                .context("Failed to resize diagram")?;
+            png_bytes = Cow::Owned(resized);
+        }
+        if let Some(colors) = self.render.palette {
+            let quantized = crate::raster::quantize(&png_bytes, colors)
+                .context("Failed to quantize diagram")?;
+            png_bytes = Cow::Owned(quantized);
+        }
+        if self.render.embed_srgb {
+            crate::png::embed_srgb(&png_bytes).context("Failed to embed sRGB color profile")
+        } else {
+            Ok(png_bytes.into_owned())
+        }
+    }
+
+    /// Flags images too small to plausibly show a diagram
+    ///
+    /// # Errors
+    /// Returns an error if the image is below the minimum size and tiny
+    /// images are configured as errors.
+    fn check_dimensions(&self, ctx: &RenderContext, png_bytes: &[u8]) -> anyhow::Result<()> {
+        let min = self.render.min_image_size;
+        let Ok((width, height)) = crate::png::dimensions(png_bytes) else {
+            // Output that isn't a readable PNG has no dimensions to check
+            return Ok(());
+        };
+        if width >= min && height >= min {
+            return Ok(());
+        }
+
+        let message = format!(
+            "D2 diagram ({}, #{}) rendered to a suspiciously small {width}x{height} image \
+             (minimum {min}x{min}). The diagram may be empty or degenerate.",
+            ctx.chapter, ctx.diagram_index
+        );
+        match self.render.tiny_image {
+            TinyImageBehavior::Error => bail!(message),
+            TinyImageBehavior::Warn => {
+                if self.log_enabled(Level::Warn) {
+                    job_log::log(Level::Warn, message);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Creates the markdown events for a rendered diagram's image
+    ///
+    /// Print builds get plain HTML images marked with the print class.
+    /// Otherwise, loading hints based on the diagram's position in its
+    /// chapter are added when an eager count is configured. Images switch to
+    /// HTML markup as well when they carry a decoding hint or size
+    /// attributes, are wrapped in a container, or are a legend, which is marked
+    /// with the legend class.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `url` - The image URL (can be a file path or data URI)
+    /// * `alt` - The image's alt text (may be empty)
+    /// * `png_bytes` - The rendered PNG
+    fn image_events(
+        &self,
+        ctx: &RenderContext,
+        url: String,
+        alt: &str,
+        png_bytes: &[u8],
+    ) -> SmallVec<[Event<'static>; 5]> {
+        self.picture_events(ctx, url, None, alt, png_bytes)
+    }
+
+    /// Creates the image events for a diagram with an optional dark variant
+    ///
+    /// With a dark variant, the image becomes the fallback of a `<picture>`
+    /// whose source is chosen for readers preferring a dark color scheme.
+    /// Otherwise the events are those of [`Backend::image_events`].
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `url` - The image URL
+    /// * `dark_url` - The dark variant's URL, if any
+    /// * `alt` - The image's alt text (may be empty)
+    /// * `png_bytes` - The rendered PNG
+    fn picture_events(
+        &self,
+        ctx: &RenderContext,
+        url: String,
+        dark_url: Option<&str>,
+        alt: &str,
+        png_bytes: &[u8],
+    ) -> SmallVec<[Event<'static>; 5]> {
+        let mut events = self.image_markup(ctx, url, alt, png_bytes, dark_url.is_some());
+        for event in &mut events {
+            if let Event::InlineHtml(html) = event {
+                let mut wrapped = html.to_string();
+                if let Some(dark_url) = dark_url {
+                    wrapped = format!(
+                        r#"<picture><source media="(prefers-color-scheme: dark)" srcset="{}" />{wrapped}</picture>"#,
+                        escape_attribute(dark_url)
+                    );
+                }
+                if self.wraps_images() {
+                    wrapped = self.wrap_image(wrapped);
+                }
+                *html = wrapped.into();
+            }
+        }
+        events
+    }
+
+    /// Returns whether images are wrapped in a zoomable or size container
+    const fn wraps_images(&self) -> bool {
+        self.render.zoomable || self.render.container_class.is_some()
+    }
 
-        let data_uri = format!("data:image/png;base64,{}", STANDARD.encode(&png_bytes));
-        Ok(create_image_events(data_uri))
+    /// Wraps an image's HTML in the zoomable and size containers, if enabled
+    fn wrap_image(&self, mut html: String) -> String {
+        if self.render.zoomable {
+            html = format!(r#"<span class="d2-zoom">{html}</span>"#);
+        }
+        if let Some(class) = &self.render.container_class {
+            html = format!(
+                r#"<span class="{}" style="display: block; container-type: inline-size">{html}</span>"#,
+                escape_attribute(class)
+            );
+        }
+        html
     }
 
-    fn render_embedded_png(
+    /// Creates the image events for [`Backend::picture_events`], before wrapping
+    ///
+    /// `html` forces HTML markup even where a markdown image would do, as
+    /// does a figure caption.
+    fn image_markup(
         &self,
         ctx: &RenderContext,
-        content: &str,
-    ) -> anyhow::Result<SmallVec<[Event<'static>; 4]>> {
-        self.generate_diagram(ctx, content)?;
+        url: String,
+        alt: &str,
+        png_bytes: &[u8],
+        html: bool,
+    ) -> SmallVec<[Event<'static>; 5]> {
+        if self.render.print {
+            let class = if ctx.legend {
+                LEGEND_PRINT_CLASS
+            } else {
+                PRINT_CLASS
+            };
+            return create_html_image_events(
+                &url,
+                alt,
+                class,
+                &self.size_attributes(ctx, png_bytes),
+            );
+        }
+        let class = image_class(ctx);
+        let attributes = self.screen_attributes(ctx, png_bytes);
+        match self.render.eager_count {
+            Some(eager_count) if ctx.diagram_index <= eager_count => create_html_image_events(
+                &url,
+                alt,
+                class,
+                &format!(r#" loading="eager" fetchpriority="high"{attributes}"#),
+            ),
+            Some(_) => create_html_image_events(
+                &url,
+                alt,
+                class,
+                &format!(r#" loading="lazy"{attributes}"#),
+            ),
+            None if attributes.is_empty()
+                && !ctx.legend
+                && !self.wraps_images()
+                && !html
+                && ctx.figcaption.is_none() =>
+            {
+                create_image_events(url, alt)
+            }
+            None => create_html_image_events(&url, alt, class, &attributes),
+        }
+    }
 
-        let rel_path = self.calculate_relative_path_for_chapter(ctx);
-        let url = rel_path
-            .to_string_lossy()
-            .to_string()
-            .replace('\\', "/");
+    /// Returns the decoding hint, size and change attributes of screen images
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `png_bytes` - The rendered PNG
+    fn screen_attributes(&self, ctx: &RenderContext, png_bytes: &[u8]) -> String {
+        let decoding = if self.render.decoding_async {
+            r#" decoding="async""#
+        } else {
+            ""
+        };
+        let changed = if ctx.changed {
+            r#" data-changed="true""#
+        } else {
+            ""
+        };
+        format!(
+            "{decoding}{}{changed}",
+            self.size_attributes(ctx, png_bytes)
+        )
+    }
 
-        Ok(create_image_events(url))
+    /// Returns the image's display size and natural size attributes
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `png_bytes` - The rendered PNG
+    fn size_attributes(&self, ctx: &RenderContext, png_bytes: &[u8]) -> String {
+        format!("{}{}", self.display_size(ctx), self.natural_size(png_bytes))
     }
 
-    /// Calculates the relative path from a chapter to its diagram file
+    /// Returns the image's `width`/`height` attributes, if any
     ///
-    /// Uses pathdiff for robust cross-platform path calculation.
+    /// A size set on the diagram replaces the configured one as a whole, so
+    /// setting only its width keeps its aspect ratio.
     ///
     /// # Arguments
     /// * `ctx` - The render context for the diagram
-    ///
-    /// # Returns
-    /// A relative path from the chapter's location to the diagram file
-    fn calculate_relative_path_for_chapter(&self, ctx: &RenderContext) -> PathBuf {
-        let chapter_dir = ctx.path.parent().unwrap_or_else(|| Path::new(""));
-        let diagram_path = self.relative_file_path(ctx);
-
-        // Use pathdiff for robust relative path calculation
-        // Falls back to the diagram path if diff_paths returns None (e.g., Windows cross-drive)
-        pathdiff::diff_paths(&diagram_path, chapter_dir).unwrap_or(diagram_path)
+    fn display_size(&self, ctx: &RenderContext) -> String {
+        let (width, height) = match ctx.size {
+            (None, None) => self.render.size,
+            size => size,
+        };
+        let width = width
+            .map(|width| format!(r#" width="{width}""#))
+            .unwrap_or_default();
+        let height = height
+            .map(|height| format!(r#" height="{height}""#))
+            .unwrap_or_default();
+        format!("{width}{height}")
     }
 
-    fn basic_args(&self) -> Vec<&OsStr> {
-        let mut args = vec![];
-
-        if let Some(fonts) = &self.render.fonts {
-            args.extend([
-                OsStr::new("--font-regular"),
-                fonts.regular.as_os_str(),
-                OsStr::new("--font-italic"),
-                fonts.italic.as_os_str(),
-                OsStr::new("--font-bold"),
-                fonts.bold.as_os_str(),
-            ]);
-        }
-        if let Some(layout) = &self.render.layout {
-            args.extend([OsStr::new("--layout"), layout.as_ref()]);
-        }
-        if let Some(theme_id) = &self.render.theme_id {
-            args.extend([OsStr::new("--theme"), theme_id.as_ref()]);
-        }
-        if let Some(dark_theme_id) = &self.render.dark_theme_id {
-            args.extend([OsStr::new("--dark-theme"), dark_theme_id.as_ref()]);
+    /// Returns the image's natural size attributes, if enabled
+    ///
+    /// The attributes are omitted when the output isn't a readable PNG.
+    ///
+    /// # Arguments
+    /// * `png_bytes` - The rendered PNG
+    fn natural_size(&self, png_bytes: &[u8]) -> String {
+        if !self.render.natural_size_attributes {
+            return String::new();
         }
-        args.push(OsStr::new("-"));
-        args
+        crate::png::dimensions(png_bytes)
+            .map(|(width, height)| {
+                format!(r#" data-natural-width="{width}" data-natural-height="{height}""#)
+            })
+            .unwrap_or_default()
     }
 
-    /// Runs the D2 process to generate a diagram
+    /// Calculates the relative path from a chapter to a diagram file
     ///
-    /// Executes the D2 binary with a timeout to prevent hanging on malformed input.
-    /// Returns the stdout bytes from the D2 process (PNG data when no output file is specified).
+    /// Uses pathdiff for robust cross-platform path calculation.
     ///
     /// # Arguments
-    /// * `ctx` - The render context for the diagram
-    /// * `content` - The D2 diagram content
-    /// * `args` - Additional arguments for the D2 process
+    /// * `ctx` - The render context of the diagram shown in the chapter
+    /// * `image_ctx` - The render context the file was written for
     ///
-    /// # Errors
-    /// Returns an error if:
-    /// - The D2 process fails to spawn
-    /// - Writing to stdin fails
-    /// - The process exceeds the timeout (30 seconds)
-    /// - The D2 compilation fails
-    fn run_process(
+    /// # Returns
+    /// A relative path from the chapter's location to the diagram file
+    fn calculate_relative_path_for_chapter(
         &self,
         ctx: &RenderContext,
-        content: &str,
-        args: Vec<&OsStr>,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut child = Command::new(&self.paths.d2_binary)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .args(args)
-            .spawn()
-            .with_context(|| {
-                format!(
-                    "Failed to spawn D2 process. Is D2 installed and available at {}?",
-                    self.paths.d2_binary.display()
-                )
-            })?;
-
-        // Write to stdin safely and close it
-        {
-            let stdin = child
-                .stdin
-                .as_mut()
-                .context("Failed to open stdin for D2 process")?;
-            stdin
-                .write_all(content.as_bytes())
-                .context("Failed to write D2 diagram content to stdin")?;
-        }
-        // stdin is automatically closed when it goes out of scope
-
-        // Wait for the process with a timeout
-        let Some(status_code) = child.wait_timeout(D2_PROCESS_TIMEOUT)? else {
-            // Process exceeded timeout, kill it and reap to prevent zombie
-            child.kill().context("Failed to kill D2 process after timeout")?;
-            let _ = child.wait(); // Reap the killed process to prevent zombie
-            return Err(anyhow!(
-                "D2 process timed out after {} seconds while processing diagram ({}, #{}). \
-                 The diagram may be too complex or D2 may be hanging. \
-                 Consider simplifying the diagram.",
-                D2_PROCESS_TIMEOUT.as_secs(),
-                ctx.chapter,
-                ctx.diagram_index
-            ));
-        };
-
-        // Collect output after process completes
-        let output = child
-            .wait_with_output()
-            .context("Failed to collect D2 process output")?;
+        image_ctx: &RenderContext,
+    ) -> PathBuf {
+        let chapter_dir = ctx.path.parent().unwrap_or_else(|| Path::new(""));
+        let diagram_path = self.relative_file_path(image_ctx);
 
-        if status_code.success() {
-            Ok(output.stdout)
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let indented_stderr = format!("\n{stderr}").replace('\n', "\n  ");
-            bail!(
-                "Failed to compile D2 diagram ({}, #{}) - D2 exited with status {}:{}",
-                ctx.chapter,
-                ctx.diagram_index,
-                status_code,
-                indented_stderr
-            )
-        }
+        // Use pathdiff for robust relative path calculation
+        // Falls back to the diagram path if diff_paths returns None (e.g., Windows cross-drive)
+        pathdiff::diff_paths(&diagram_path, chapter_dir).unwrap_or(diagram_path)
     }
 }
 
@@ -446,17 +2474,71 @@ mod tests {
     fn create_test_backend() -> Backend {
         Backend {
             paths: PathConfig {
-                d2_binary: PathBuf::from("d2"),
                 output_dir: PathBuf::from("d2"),
                 source_dir: PathBuf::from("/test/src"),
+                failure_dir: None,
+                js_data_file: None,
+                checkpoint_file: None,
             },
             render: RenderConfig {
-                layout: None,
                 inline: false,
-                fonts: None,
-                theme_id: None,
-                dark_theme_id: None,
+                split_delimiter: None,
+                alt_from_label: true,
+                alt_template: String::from("{caption}"),
+                embed_srgb: false,
+                stable_svg_ids: false,
+                autocrop: false,
+                autocrop_background: None,
+                palette: None,
+                resize: None,
+                resize_fit: ResizeFit::default(),
+                warn_complexity_nodes: None,
+                min_image_size: 2,
+                tiny_image: TinyImageBehavior::Warn,
+                eager_count: None,
+                warn_duplicates: false,
+                print: false,
+                natural_size_attributes: false,
+                size: (None, None),
+                format: OutputFormat::Png,
+                quality: RenderQuality::Standard,
+                decoding_async: false,
+                zoomable: false,
+                batch_writes: false,
+                hashed_filenames: false,
+                container_class: None,
+                post_render_command: None,
+                og_image_base_url: None,
+                watermark: None,
+                picture_dark_theme_id: None,
             },
+            renderer: Arc::new(D2Cli::new(
+                &Config::default(),
+                Path::new("/book"),
+                Path::new("/book/src"),
+            )),
+            cache: None,
+            cache_mode: CacheMode::All,
+            quiet: false,
+            concurrency_ramp: None,
+            max_concurrency: None,
+            max_concurrent_nodes: None,
+            layout: DEFAULT_LAYOUT.to_string(),
+            numbering_scope: NumberingScope::Chapter,
+            dedup_scope: DedupScope::None,
+            changed_since: None,
+            layout_concurrency: BTreeMap::new(),
+            sequential: false,
+            total_timeout: None,
+            max_diagrams: None,
+            max_diagrams_per_chapter: None,
+            only_tags: Vec::new(),
+            languages: vec![String::from("d2")],
+            on_error: RenderErrorBehavior::Skip,
+            on_missing_file: RenderErrorBehavior::Skip,
+            js_data: Mutex::default(),
+            pending_files: Mutex::default(),
+            generated_files: Mutex::default(),
         }
     }
 
@@ -478,7 +2560,7 @@ mod tests {
         let section = SectionNumber(vec![1]);
         let ctx = create_test_context(chapter_path, "Test Chapter", Some(&section), 1);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
 
         // Root-level: no "../" needed, just "d2/1.1.png"
         assert_eq!(rel_path, PathBuf::from("d2/1.1.png"));
@@ -492,7 +2574,7 @@ mod tests {
         let section = SectionNumber(vec![1]);
         let ctx = create_test_context(chapter_path, "Test Chapter", Some(&section), 1);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
 
         // One level: "../d2/1.1.png"
         assert_eq!(rel_path, PathBuf::from("../d2/1.1.png"));
@@ -506,7 +2588,7 @@ mod tests {
         let section = SectionNumber(vec![1, 1]);
         let ctx = create_test_context(chapter_path, "Test Chapter", Some(&section), 1);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
 
         // Two levels: "../../d2/1.1.1.png"
         assert_eq!(rel_path, PathBuf::from("../../d2/1.1.1.png"));
@@ -520,7 +2602,7 @@ mod tests {
         let section = SectionNumber(vec![2, 3, 4]);
         let ctx = create_test_context(chapter_path, "Deep Chapter", Some(&section), 2);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
 
         // Three levels: "../../../d2/2.3.4.2.png"
         assert_eq!(rel_path, PathBuf::from("../../../d2/2.3.4.2.png"));
@@ -534,12 +2616,18 @@ mod tests {
         let chapter_path = Path::new("appendix/info.md");
         let ctx = create_test_context(chapter_path, "Appendix", None, 1);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
         let rel_str = rel_path.to_string_lossy();
 
         // One level deep, no section: "../d2/<hash>_1.png"
-        assert!(rel_str.starts_with("../d2/"), "Should start with ../d2/, got: {rel_str}");
-        assert!(rel_str.ends_with("_1.png"), "Should end with _1.png, got: {rel_str}");
+        assert!(
+            rel_str.starts_with("../d2/"),
+            "Should start with ../d2/, got: {rel_str}"
+        );
+        assert!(
+            rel_str.ends_with("_1.png"),
+            "Should end with _1.png, got: {rel_str}"
+        );
     }
 
     #[test]
@@ -552,7 +2640,7 @@ mod tests {
         let section = SectionNumber(vec![1]);
         let ctx = create_test_context(chapter_path, "Test", Some(&section), 1);
 
-        let rel_path = backend.calculate_relative_path_for_chapter(&ctx);
+        let rel_path = backend.calculate_relative_path_for_chapter(&ctx, &ctx);
 
         // Should use custom output dir: "../diagrams/1.1.png"
         assert_eq!(rel_path, PathBuf::from("../diagrams/1.1.png"));
@@ -563,18 +2651,24 @@ mod tests {
         // Test filename generation for various section numbers
         let section1 = SectionNumber(vec![1]);
         let ctx1 = create_test_context(Path::new("test.md"), "Test", Some(&section1), 2);
-        assert_eq!(filename(&ctx1), "1.2.png");
+        assert_eq!(filename(&ctx1, OutputFormat::Png), "1.2.png");
 
         let section2 = SectionNumber(vec![1, 2, 3]);
         let ctx2 = create_test_context(Path::new("test.md"), "Test", Some(&section2), 1);
-        assert_eq!(filename(&ctx2), "1.2.3.1.png");
+        assert_eq!(filename(&ctx2, OutputFormat::Png), "1.2.3.1.png");
 
         // No section number - uses path hash for uniqueness
         let ctx3 = create_test_context(Path::new("test.md"), "Test", None, 5);
-        let filename3 = filename(&ctx3);
+        let filename3 = filename(&ctx3, OutputFormat::Png);
         // Filename should be hash_index.png format (e.g., "a1b2c3d4_5.png")
-        assert!(filename3.ends_with("_5.png"), "Expected hash_5.png format, got: {filename3}");
-        assert!(filename3.len() > 6, "Filename should have hash prefix: {filename3}");
+        assert!(
+            filename3.ends_with("_5.png"),
+            "Expected hash_5.png format, got: {filename3}"
+        );
+        assert!(
+            filename3.len() > 6,
+            "Filename should have hash prefix: {filename3}"
+        );
     }
 
     #[test]
@@ -583,10 +2677,13 @@ mod tests {
         let ctx1 = create_test_context(Path::new("chapter1.md"), "Chapter 1", None, 1);
         let ctx2 = create_test_context(Path::new("chapter2.md"), "Chapter 2", None, 1);
 
-        let filename1 = filename(&ctx1);
-        let filename2 = filename(&ctx2);
+        let filename1 = filename(&ctx1, OutputFormat::Png);
+        let filename2 = filename(&ctx2, OutputFormat::Png);
 
-        assert_ne!(filename1, filename2, "Different paths should produce different filenames");
+        assert_ne!(
+            filename1, filename2,
+            "Different paths should produce different filenames"
+        );
     }
 
     #[test]
@@ -595,7 +2692,745 @@ mod tests {
         let ctx1 = create_test_context(Path::new("test.md"), "Test", None, 1);
         let ctx2 = create_test_context(Path::new("test.md"), "Test", None, 1);
 
-        assert_eq!(filename(&ctx1), filename(&ctx2), "Same path should produce same filename");
+        assert_eq!(
+            filename(&ctx1, OutputFormat::Png),
+            filename(&ctx2, OutputFormat::Png),
+            "Same path should produce same filename"
+        );
+    }
+
+    #[test]
+    fn test_dark_variant() {
+        let section = SectionNumber(vec![1]);
+        let options = [
+            (String::from("theme"), String::from("1")),
+            (String::from("pad"), String::from("0")),
+        ];
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 2)
+            .with_options(&options);
+        assert_eq!(
+            filename(&ctx.with_dark(true), OutputFormat::Png),
+            "1.2.dark.png"
+        );
+
+        // The dark theme replaces the diagram's own theme
+        let dark = dark_options(&ctx, "200");
+        assert_eq!(
+            RenderContext::new(Path::new("test.md"), "Test", None, 1)
+                .with_options(&dark)
+                .option("theme"),
+            Some("200")
+        );
+        assert_eq!(dark.len(), 2);
+    }
+
+    #[test]
+    fn test_filename_with_sub_index() {
+        let section = SectionNumber(vec![1, 2]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 3)
+            .with_sub_index(Some(2));
+        assert_eq!(filename(&ctx, OutputFormat::Png), "1.2.3-2.png");
+
+        let ctx =
+            create_test_context(Path::new("test.md"), "Test", None, 3).with_sub_index(Some(1));
+        assert!(filename(&ctx, OutputFormat::Png).ends_with("_3-1.png"));
+    }
+
+    #[test]
+    fn test_filename_with_stable_id() {
+        let section = SectionNumber(vec![1, 2]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 3)
+            .with_stable_id(Some("arch-v2"));
+        assert_eq!(filename(&ctx, OutputFormat::Png), "arch-v2.png");
+        assert_eq!(
+            filename(&ctx.with_sub_index(Some(2)), OutputFormat::Png),
+            "arch-v2-2.png"
+        );
+    }
+
+    #[test]
+    fn test_filename_with_content_hash() {
+        let section = SectionNumber(vec![1, 2]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 3)
+            .with_content_hash(Some("0f1e2d3c"));
+        assert_eq!(filename(&ctx, OutputFormat::Png), "1.2.3.0f1e2d3c.png");
+        assert_eq!(
+            filename(&ctx.with_dark(true), OutputFormat::Png),
+            "1.2.3.dark.0f1e2d3c.png"
+        );
+
+        // Stable IDs keep their permanent name
+        let ctx = ctx.with_stable_id(Some("arch-v2"));
+        assert_eq!(filename(&ctx, OutputFormat::Png), "arch-v2.png");
+    }
+
+    #[test]
+    fn test_filename_extension_per_format() {
+        // The format's extension is appended even to stable IDs that look like filenames,
+        // so no two formats share a path
+        for stable_id in [None, Some("arch"), Some("arch.png"), Some("arch.svg")] {
+            let ctx = create_test_context(Path::new("test.md"), "Test", None, 1)
+                .with_stable_id(stable_id);
+            let png = filename(&ctx, OutputFormat::Png);
+            let svg = filename(&ctx, OutputFormat::Svg);
+            assert_eq!(Path::new(&png).extension().unwrap(), "png");
+            assert_eq!(Path::new(&svg).extension().unwrap(), "svg");
+            assert_ne!(png, svg);
+        }
+    }
+
+    #[test]
+    fn test_filename_svg() {
+        let section = SectionNumber(vec![1]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 1);
+        assert_eq!(filename(&ctx, OutputFormat::Svg), "1.1.svg");
+        assert_eq!(diagram_id(&ctx), "1.1");
+        assert_eq!(
+            data_uri(OutputFormat::Svg, b"<svg/>"),
+            "data:image/svg+xml;base64,PHN2Zy8+"
+        );
+    }
+
+    #[test]
+    fn test_diagram_label() {
+        assert_eq!(
+            diagram_label("label: Request flow\na -> b\n"),
+            Some("Request flow".into())
+        );
+        assert_eq!(
+            diagram_label("a -> b\ntitle: \"Auth sequence\" {\n  near: top-center\n}\n"),
+            Some("Auth sequence".into())
+        );
+
+        // Labels nested inside blocks belong to shapes, not the diagram
+        assert_eq!(diagram_label("a: {\n  label: Inner\n}\n"), None);
+        assert_eq!(diagram_label("a -> b\n"), None);
+        assert_eq!(
+            diagram_label("a: {style.fill: \"#fff\"} # styled\nlabel: Colors\n"),
+            Some("Colors".into())
+        );
+        assert_eq!(diagram_label("title: |md\n  # Title\n|\n"), None);
+    }
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn test_alt_text_template() {
+        let section = SectionNumber(vec![1, 2]);
+        let ctx = create_test_context(Path::new("test.md"), "Intro", Some(&section), 3);
+
+        assert_eq!(alt_text("{caption}", &ctx, "Flow"), "Flow");
+        // Images without a caption fall back to the chapter and index
+        assert_eq!(alt_text("{caption}", &ctx, ""), "Intro, diagram 3");
+        assert_eq!(
+            alt_text(
+                "Abbildung {section}.{index} ({chapter}): {caption}",
+                &ctx,
+                "{index}"
+            ),
+            "Abbildung 1.2.3 (Intro): {index}"
+        );
+
+        assert_eq!(alt_text("Figure {figure}", &ctx, ""), "Figure 1.2.3");
+        let ctx = ctx.with_figure(Some("7"));
+        assert_eq!(alt_text("Figure {figure}", &ctx, ""), "Figure 7");
+    }
+
+    #[test]
+    fn test_add_watermark() {
+        assert_eq!(
+            add_watermark("a -> b", r#"Draft "abc" \ 2024"#),
+            "a -> b\nd2-png-watermark: \"Draft \\\"abc\\\" \\\\ 2024\" {\n  shape: text\n  \
+             near: bottom-right\n  style.font-size: 12\n  style.opacity: 0.6\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_watermark_text_outside_git() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = watermark_text("Build {sha} {date} {other}", dir.path());
+        assert!(text.starts_with("Build unknown "), "{text}");
+        assert!(text.ends_with(" {other}"), "{text}");
+    }
+
+    #[test]
+    fn test_create_image_events_alt_text() {
+        let events = create_image_events("d2/1.1.png".into(), "Request flow");
+        assert!(events.contains(&Event::Text("Request flow".into())));
+
+        let events = create_image_events("d2/1.1.png".into(), "");
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn test_image_events_loading_hints() {
+        let mut backend = create_test_backend();
+        backend.render.eager_count = Some(1);
+        let html = |backend: &Backend, index| {
+            let ctx = create_test_context(Path::new("test.md"), "Test", None, index);
+            backend.image_events(&ctx, "d2/1.1.png".into(), "A \"quoted\" <flow>", &[])[1].clone()
+        };
+
+        assert_eq!(
+            html(&backend, 1),
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="A &quot;quoted&quot; &lt;flow&gt;" loading="eager" fetchpriority="high" />"#.into()
+            )
+        );
+        assert_eq!(
+            html(&backend, 2),
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="A &quot;quoted&quot; &lt;flow&gt;" loading="lazy" />"#.into()
+            )
+        );
+
+        // Print builds drop the loading hints
+        backend.render.print = true;
+        assert_eq!(
+            html(&backend, 2),
+            Event::InlineHtml(
+                r#"<img class="d2-diagram d2-print" src="d2/1.1.png" alt="A &quot;quoted&quot; &lt;flow&gt;" />"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_image_events_natural_size() {
+        let mut backend = create_test_backend();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend([0, 0, 1, 0x2c, 0, 0, 0, 0xc8, 8, 6, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Disabled by default, keeping the markdown image
+        let events = backend.image_events(&ctx, "d2/1.1.png".into(), "", &png);
+        assert!(matches!(events[1], Event::Start(Tag::Image { .. })));
+
+        backend.render.natural_size_attributes = true;
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", &png)[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="" data-natural-width="300" data-natural-height="200" />"#.into()
+            )
+        );
+
+        // Unreadable output gets no size
+        let events = backend.image_events(&ctx, "d2/1.1.png".into(), "", b"<svg/>");
+        assert!(matches!(events[1], Event::Start(Tag::Image { .. })));
+    }
+
+    #[test]
+    fn test_image_events_size() {
+        let mut backend = create_test_backend();
+        backend.render.size = (Some(600), None);
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="" width="600" />"#.into()
+            )
+        );
+
+        // A diagram's own size replaces the configured one
+        let ctx = ctx.with_size(None, Some(200));
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="" height="200" />"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_postprocess_high_quality() {
+        let mut backend = create_test_backend();
+        backend.render.quality = RenderQuality::High;
+        backend.render.min_image_size = 1;
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+        let mut png = Vec::new();
+        image::RgbaImage::new(40, 20)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        // Rendered at twice the size and downsampled to the native one
+        let downsampled = backend.postprocess(&ctx, &png).unwrap();
+        assert_eq!(crate::png::dimensions(&downsampled).unwrap(), (20, 10));
+
+        // `resize` applies to the downsampled image
+        backend.render.resize = Some((80, 60));
+        let resized = backend.postprocess(&ctx, &png).unwrap();
+        assert_eq!(crate::png::dimensions(&resized).unwrap(), (80, 60));
+        // The image is displayed at its pixel size
+        let events = backend.image_events(&ctx, "d2/1.1.png".into(), "", &resized);
+        assert!(!format!("{events:?}").contains("width"), "{events:?}");
+    }
+
+    #[test]
+    fn test_picture_size() {
+        let mut light = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        light.extend([0, 0, 1, 0x2c, 0, 0, 0, 0xc8, 8, 6, 0, 0, 0, 0, 0, 0, 0]);
+        let mut dark = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        dark.extend([0, 0, 1, 0x2a, 0, 0, 0, 0xcb, 8, 6, 0, 0, 0, 0, 0, 0, 0]);
+
+        // The larger of both variants' widths and heights
+        assert_eq!(Backend::picture_size(&light, &dark), Some((300, 203)));
+        assert_eq!(Backend::picture_size(&dark, &light), Some((300, 203)));
+        assert_eq!(Backend::picture_size(&light, b"<svg/>"), None);
+    }
+
+    #[test]
+    fn test_image_events_changed() {
+        let backend = create_test_backend();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1).with_changed(true);
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", b"")[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.1.png" alt="" data-changed="true" />"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_og_image_event() {
+        let mut backend = create_test_backend();
+        let section = SectionNumber(vec![2, 1]);
+        let ctx = create_test_context(Path::new("guide/setup.md"), "Setup", Some(&section), 1);
+        assert_eq!(
+            backend.og_image_event(&ctx, "../d2/2.1.1.png".into()),
+            Event::Html("<meta property=\"og:image\" content=\"../d2/2.1.1.png\">\n".into())
+        );
+
+        backend.render.og_image_base_url = Some("https://example.com/book/".into());
+        assert_eq!(
+            backend.og_image_event(&ctx, "../d2/2.1.1.png".into()),
+            Event::Html(
+                "<meta property=\"og:image\" content=\"https://example.com/book/d2/2.1.1.png\">\n"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_image_events_decoding_async() {
+        let mut backend = create_test_backend();
+        backend.render.decoding_async = true;
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 2);
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.2.png".into(), "", b"")[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.2.png" alt="" decoding="async" />"#.into()
+            )
+        );
+
+        // Composes with loading hints
+        backend.render.eager_count = Some(1);
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.2.png".into(), "", b"")[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram" src="d2/1.2.png" alt="" loading="lazy" decoding="async" />"#.into()
+            )
+        );
+
+        // Print markup stays without it
+        backend.render.print = true;
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.2.png".into(), "", b"")[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram d2-print" src="d2/1.2.png" alt="" />"#.into()
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_post_render_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("it's here.png");
+        fs::write(&path, b"png").unwrap();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+
+        assert!(run_post_render_command(&ctx, "test -f {file}", &path).is_ok());
+
+        let error = run_post_render_command(&ctx, "echo oops >&2; exit 3", &path)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("(Test, #1)"), "{error}");
+        assert!(error.contains("oops"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("1.1.png");
+        write_atomically(dir.path(), &filepath, b"png").unwrap();
+        assert_eq!(fs::read(&filepath).unwrap(), b"png");
+
+        // Same mode as a plainly written file, not the temporary file's 0600
+        let plain = dir.path().join("plain.png");
+        fs::write(&plain, b"png").unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&filepath), mode(&plain));
+    }
+
+    #[test]
+    fn test_effective_layout() {
+        let mut backend = create_test_backend();
+        assert_eq!(backend.effective_layout("a -> b", None), "dagre");
+
+        backend.layout = String::from("elk");
+        assert_eq!(backend.effective_layout("a -> b", None), "elk");
+        assert_eq!(backend.effective_layout("a -> b", Some("dagre")), "dagre");
+
+        let content =
+            "vars: {\n  d2-config: {\n    layout-engine: \"tala\" # fancy\n  }\n}\na -> b\n";
+        assert_eq!(backend.effective_layout(content, Some("elk")), "tala");
+    }
+
+    #[test]
+    fn test_check_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = create_test_backend();
+        backend.paths.source_dir = dir.path().to_path_buf();
+        backend.check_output_dir().unwrap();
+        assert!(dir.path().join("d2").is_dir());
+        // The probe file is cleaned up
+        assert_eq!(fs::read_dir(dir.path().join("d2")).unwrap().count(), 0);
+
+        // A file in the way can't be written into
+        fs::write(dir.path().join("blocked"), "").unwrap();
+        backend.paths.output_dir = PathBuf::from("blocked");
+        let error = format!("{:#}", backend.check_output_dir().unwrap_err());
+        assert!(error.contains("isn't writable"), "{error}");
+        assert!(error.contains("inline = true"), "{error}");
+
+        // Inline images don't need the directory
+        backend.render.inline = true;
+        assert!(backend.check_output_dir().is_ok());
+    }
+
+    #[test]
+    fn test_generate_diagram_batch_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = create_test_backend();
+        backend.paths.source_dir = dir.path().to_path_buf();
+        backend.render.batch_writes = true;
+        let section = SectionNumber(vec![1]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 1);
+        let filepath = dir.path().join("d2/1.1.png");
+
+        let png_bytes = backend.generate_diagram(&ctx, "a -> b", None).unwrap();
+        assert!(!filepath.exists());
+
+        backend.write_pending_files(None).unwrap();
+        assert_eq!(fs::read(&filepath).unwrap(), png_bytes);
+    }
+
+    #[test]
+    fn test_generate_diagram_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = create_test_backend();
+        backend.paths.source_dir = dir.path().to_path_buf();
+        let section = SectionNumber(vec![1]);
+        let ctx = create_test_context(Path::new("test.md"), "Test", Some(&section), 1);
+        fs::create_dir(dir.path().join("d2")).unwrap();
+        fs::write(dir.path().join("d2/1.1.png"), b"done").unwrap();
+
+        let checkpoint = Checkpoint::open(&dir.path().join("progress")).unwrap();
+        checkpoint.record("d2/1.1.png", "a -> b").unwrap();
+        let checkpoint = Checkpoint::open(&dir.path().join("progress")).unwrap();
+        assert_eq!(
+            backend
+                .generate_diagram(&ctx, "a -> b", Some(&checkpoint))
+                .unwrap(),
+            b"done"
+        );
+    }
+
+    /// Renderer counting its renders, whose diagnostics fail the diagram when replayed
+    #[derive(Debug, Default)]
+    struct CountingRenderer {
+        renders: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DiagramRenderer for CountingRenderer {
+        fn render(&self, _content: &str, _ctx: &RenderContext) -> anyhow::Result<Vec<u8>> {
+            self.renders
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(b"image".to_vec())
+        }
+
+        fn render_with_diagnostics(
+            &self,
+            content: &str,
+            ctx: &RenderContext,
+        ) -> anyhow::Result<(Vec<u8>, String)> {
+            self.render(content, ctx)
+                .map(|bytes| (bytes, String::from("warn: deprecated")))
+        }
+
+        fn replay_diagnostics(
+            &self,
+            _ctx: &RenderContext,
+            diagnostics: &str,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(diagnostics.is_empty(), "replayed {diagnostics}");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_render_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let renderer = Arc::new(CountingRenderer::default());
+        let mut backend = create_test_backend();
+        backend.renderer = renderer.clone();
+        backend.cache = Some(RenderCache::new(
+            dir.path().to_path_buf(),
+            "png",
+            String::new(),
+            None,
+        ));
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+        let renders = || renderer.renders.load(std::sync::atomic::Ordering::Relaxed);
+
+        assert_eq!(backend.render_cached(&ctx, "a -> b").unwrap(), b"image");
+        assert_eq!(renders(), 1);
+        // The cached image's diagnostics are replayed instead of rendering again
+        let error = backend
+            .render_cached(&ctx, "a -> b")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "replayed warn: deprecated");
+        assert_eq!(renders(), 1);
+
+        // Diagrams importing files are rendered every time
+        backend.render_cached(&ctx, "x: @shapes").unwrap();
+        backend.render_cached(&ctx, "x: @shapes").unwrap();
+        assert_eq!(renders(), 3);
+
+        // With opt-in caching, only diagrams tagged `cache` are cached
+        backend.cache_mode = CacheMode::OptIn;
+        backend.render_cached(&ctx, "c -> d").unwrap();
+        backend.render_cached(&ctx, "c -> d").unwrap();
+        assert_eq!(renders(), 5);
+        let _ = backend.render_cached(&ctx.with_cache(true), "a -> b");
+        assert_eq!(renders(), 5);
+    }
+
+    #[test]
+    fn test_uses_local_files() {
+        assert!(uses_local_files("x: @shapes"));
+        assert!(uses_local_files("a: {\n  icon: ./server.svg\n}"));
+        assert!(uses_local_files("a.icon: 'icons/db.png'"));
+        assert!(!uses_local_files(
+            "a.icon: https://icons.terrastruct.com/aws.svg"
+        ));
+        assert!(!uses_local_files("a -> b # icon: x.svg"));
+        assert!(!uses_local_files("a -> b"));
+    }
+
+    #[test]
+    fn test_has_imports() {
+        assert!(has_imports("x: @shapes"));
+        assert!(has_imports("x: {\n  ...@base\n}"));
+        assert!(has_imports("a.b:@'dir/file'"));
+        assert!(!has_imports("a: mail me@example.com"));
+        assert!(!has_imports("a: \"@handle\""));
+        assert!(!has_imports("a -> b # see x: @y"));
+    }
+
+    #[test]
+    fn test_any_env_set() {
+        assert!(!any_env_set(&[]));
+        assert!(!any_env_set(&[String::from("MDBOOK_D2_PNG_SURELY_UNSET")]));
+        assert!(any_env_set(&[
+            String::from("MDBOOK_D2_PNG_SURELY_UNSET"),
+            String::from("PATH"),
+        ]));
+    }
+
+    #[test]
+    fn test_split_legend() {
+        let mut backend = create_test_backend();
+        let (diagrams, legend) = backend.split_legend("a -> b\n---\nkey: Key\n");
+        assert_eq!(diagrams, ["a -> b\n"]);
+        assert_eq!(legend.as_deref(), Some("key: Key\n"));
+
+        // No delimiter, no legend
+        let (diagrams, legend) = backend.split_legend("a -> b\n");
+        assert_eq!(diagrams, ["a -> b\n"]);
+        assert_eq!(legend, None);
+
+        // The configured split delimiter takes over, and the last part is the legend
+        backend.render.split_delimiter = Some(String::from("%%"));
+        let (diagrams, legend) = backend.split_legend("a\n%%\nb\n%%\nkey\n---\n");
+        assert_eq!(diagrams, ["a\n", "b\n"]);
+        assert_eq!(legend.as_deref(), Some("key\n---\n"));
+    }
+
+    #[test]
+    fn test_image_events_legend() {
+        let mut backend = create_test_backend();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1).with_legend(true);
+
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1-2.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram d2-legend" src="d2/1.1-2.png" alt="" />"#.into()
+            )
+        );
+
+        backend.render.print = true;
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1-2.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<img class="d2-diagram d2-legend d2-print" src="d2/1.1-2.png" alt="" />"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_image_events_zoomable() {
+        let mut backend = create_test_backend();
+        backend.render.zoomable = true;
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<span class="d2-zoom"><img class="d2-diagram" src="d2/1.1.png" alt="" /></span>"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_figure_events() {
+        let backend = create_test_backend();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1)
+            .with_figcaption(Some("The *request* flow"));
+
+        assert_eq!(
+            figure_events(
+                &backend.image_events(&ctx, "d2/1.1.png".into(), "Flow", &[]),
+                "The *request* flow"
+            ),
+            [Event::Html(
+                "<figure><img class=\"d2-diagram\" src=\"d2/1.1.png\" alt=\"Flow\" />\
+                 <figcaption>The <em>request</em> flow</figcaption></figure>\n"
+                    .into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_image_events_container_class() {
+        let mut backend = create_test_backend();
+        backend.render.container_class = Some("d2-container".into());
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+
+        assert_eq!(
+            backend.image_events(&ctx, "d2/1.1.png".into(), "", &[])[1],
+            Event::InlineHtml(
+                r#"<span class="d2-container" style="display: block; container-type: inline-size"><img class="d2-diagram" src="d2/1.1.png" alt="" /></span>"#
+                    .into()
+            )
+        );
+
+        // The size container holds the zoomable one
+        backend.render.zoomable = true;
+        let Event::InlineHtml(html) = &backend.image_events(&ctx, "d2/1.1.png".into(), "", &[])[1]
+        else {
+            panic!("expected HTML image");
+        };
+        assert!(
+            html.contains(r#"inline-size"><span class="d2-zoom"><img"#),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn test_check_dimensions() {
+        let mut backend = create_test_backend();
+        let ctx = create_test_context(Path::new("test.md"), "Test", None, 1);
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend([0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Warnings keep the image
+        assert!(backend.check_dimensions(&ctx, &png).is_ok());
+
+        backend.render.tiny_image = TinyImageBehavior::Error;
+        let error = backend
+            .check_dimensions(&ctx, &png)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("1x1"), "{error}");
+
+        backend.render.min_image_size = 1;
+        assert!(backend.check_dimensions(&ctx, &png).is_ok());
+    }
+
+    #[test]
+    fn test_quiet_keeps_only_errors() {
+        let mut backend = create_test_backend();
+        assert!(backend.log_enabled(Level::Info));
+
+        backend.quiet = true;
+        assert!(backend.log_enabled(Level::Error));
+        assert!(!backend.log_enabled(Level::Warn));
+        assert!(!backend.log_enabled(Level::Info));
+    }
+
+    #[test]
+    fn test_count_nodes() {
+        assert_eq!(count_nodes("x -> y -> z\n"), 3);
+        assert_eq!(
+            count_nodes("a: Alpha\nb: Beta {\n  shape: circle\n}\na -> b: hello\n"),
+            2
+        );
+        assert_eq!(
+            count_nodes("a <-> b\na.style.fill: red # note\ndirection: right\n"),
+            2
+        );
+        assert_eq!(count_nodes("group: {\n  inner\n}\n"), 2);
+        assert_eq!(count_nodes("api-server -- db\n"), 2);
+    }
+
+    #[test]
+    fn test_failure_filename() {
+        let ctx = create_test_context(Path::new("part1/intro.md"), "Intro", None, 2);
+        assert_eq!(failure_filename(&ctx), "part1-intro-2.d2");
+
+        let ctx = create_test_context(Path::new("chapter1.md"), "Chapter 1", None, 3)
+            .with_sub_index(Some(1));
+        assert_eq!(failure_filename(&ctx), "chapter1-3-1.d2");
+    }
+
+    #[test]
+    fn test_split_on_delimiter() {
+        let parts = split_on_delimiter("a -> b\n---\nc -> d\n", "---");
+        assert_eq!(parts, vec!["a -> b\n", "c -> d\n"]);
+
+        // Blank parts (e.g. a leading or trailing delimiter) are dropped
+        let parts = split_on_delimiter("---\na -> b\n  ---  \n", "---");
+        assert_eq!(parts, vec!["a -> b\n"]);
+
+        // Content without the delimiter is a single diagram
+        let parts = split_on_delimiter("a -> b\n", "---");
+        assert_eq!(parts, vec!["a -> b\n"]);
+
+        // A block holding nothing but delimiters still yields one diagram
+        let parts = split_on_delimiter("---\n", "---");
+        assert_eq!(parts, vec!["---\n"]);
+    }
+
+    #[test]
+    fn test_split_diagrams_without_delimiter() {
+        let backend = create_test_backend();
+        assert_eq!(
+            backend.split_diagrams("a -> b\n---\nc\n"),
+            vec!["a -> b\n---\nc\n"]
+        );
     }
 
     #[test]
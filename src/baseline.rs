@@ -0,0 +1,141 @@
+//! Comparing diagram sources against a git baseline
+//!
+//! With `changed-since`, each chapter's diagrams are compared with the
+//! chapter file's version at a git ref, and diagrams that don't appear there
+//! are marked as changed for reviewers.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context};
+
+/// Checks that a git ref names a commit
+///
+/// # Arguments
+/// * `dir` - A directory inside the git repository
+/// * `git_ref` - The baseline ref, e.g. `main` or `v1.2`
+///
+/// # Errors
+/// Returns an error if git can't be run or the ref doesn't name a commit.
+pub fn verify_ref(dir: &Path, git_ref: &str) -> anyhow::Result<()> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{git_ref}^{{commit}}"))
+        .current_dir(dir)
+        .output()
+        .context("Failed to run git to resolve `changed-since`")?;
+    if !output.status.success() {
+        bail!("`changed-since` ref `{git_ref}` doesn't name a commit in the book's git repository");
+    }
+    Ok(())
+}
+
+/// Returns a file's contents at a git ref
+///
+/// Returns `None` if the file didn't exist at the ref or git fails.
+///
+/// # Arguments
+/// * `dir` - The directory `path` is relative to, inside the git repository
+/// * `git_ref` - The baseline ref
+/// * `path` - The file's path relative to `dir`
+pub fn file_at(dir: &Path, git_ref: &str, path: &Path) -> Option<String> {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let output = Command::new("git")
+        .arg("show")
+        .arg(format!("{git_ref}:./{path}"))
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The diagrams of a chapter file's baseline version
+#[derive(Debug, Default)]
+pub struct BaselineDiagrams(HashSet<String>);
+
+impl BaselineDiagrams {
+    /// Collects the baseline's diagrams, split like the current ones
+    ///
+    /// # Arguments
+    /// * `diagrams` - The sources of the baseline's diagrams
+    pub fn new(diagrams: impl IntoIterator<Item = String>) -> Self {
+        Self(
+            diagrams
+                .into_iter()
+                .map(|diagram| normalize(&diagram))
+                .collect(),
+        )
+    }
+
+    /// Returns whether a diagram is unchanged since the baseline
+    ///
+    /// Only whitespace at the end of lines and around the diagram is
+    /// ignored; any other edit, including removing lines, is a change.
+    ///
+    /// # Arguments
+    /// * `content` - The diagram's current source
+    pub fn contains(&self, content: &str) -> bool {
+        self.0.contains(&normalize(content))
+    }
+}
+
+/// Normalizes a diagram's source for comparison with its baseline
+fn normalize(content: &str) -> String {
+    content
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs git in a directory, panicking on failure
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn test_file_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("chapter.md"), "old").unwrap();
+        git(dir.path(), &["init", "--quiet"]);
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "--quiet", "-m", "baseline"]);
+        std::fs::write(src.join("chapter.md"), "new").unwrap();
+
+        verify_ref(&src, "HEAD").unwrap();
+        assert!(verify_ref(&src, "no-such-ref").is_err());
+        assert_eq!(
+            file_at(&src, "HEAD", Path::new("chapter.md")).as_deref(),
+            Some("old")
+        );
+        assert_eq!(file_at(&src, "HEAD", Path::new("missing.md")), None);
+    }
+
+    #[test]
+    fn test_baseline_diagrams() {
+        let baseline = BaselineDiagrams::new([String::from("a -> b\nb -> c\nc -> d\n")]);
+        assert!(baseline.contains("a -> b\nb -> c\nc -> d"));
+        assert!(baseline.contains("\na -> b  \nb -> c\nc -> d\n\n"));
+
+        // Removing edges leaves the new source inside the old one, but is a change
+        assert!(!baseline.contains("a -> b\nb -> c"));
+        assert!(!baseline.contains("b -> c"));
+        assert!(!baseline.contains("a -> b\nb -> c\nc -> d\nd -> e"));
+    }
+}
@@ -0,0 +1,211 @@
+//! Caching rendered images between builds
+//!
+//! With `cache`, each image the renderer produces is stored under
+//! `.d2-png-cache/` in the book root, named after the SHA-256 hash of
+//! everything that determines it: the diagram source and info string options, the
+//! renderer's settings and its version. Later builds reuse a stored image
+//! instead of running D2 again, and any change, including upgrading D2,
+//! leads to a different name. D2's stderr is stored next to each image, so
+//! its warnings are reported again when the image is reused.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
+
+use crate::checkpoint::source_hash;
+
+/// Extension of the file holding a cached image's renderer diagnostics
+const DIAGNOSTICS_EXTENSION: &str = "stderr";
+
+/// Rendered images stored by a hash of their inputs
+#[derive(Debug)]
+pub struct RenderCache {
+    /// Directory holding the cached images
+    dir: PathBuf,
+    /// Extension of the cached images, e.g. `png`
+    extension: &'static str,
+    /// The renderer's settings affecting its output
+    fingerprint: String,
+    /// Namespace keeping entries apart from those of other books, if any
+    namespace: Option<String>,
+    /// The renderer's version, once known
+    version: OnceLock<String>,
+}
+
+impl RenderCache {
+    /// Creates a cache in a directory, created when the first image is stored
+    ///
+    /// # Arguments
+    /// * `dir` - Directory holding the cached images
+    /// * `extension` - Extension of the cached images
+    /// * `fingerprint` - The renderer's settings affecting its output
+    /// * `namespace` - Namespace keeping entries apart from those of other books, if any
+    pub const fn new(
+        dir: PathBuf,
+        extension: &'static str,
+        fingerprint: String,
+        namespace: Option<String>,
+    ) -> Self {
+        Self {
+            dir,
+            extension,
+            fingerprint,
+            namespace,
+            version: OnceLock::new(),
+        }
+    }
+
+    /// Records the renderer's version, so upgrading it invalidates the cache
+    ///
+    /// # Arguments
+    /// * `version` - The version reported by the renderer's preflight check
+    pub fn set_version(&self, version: &str) {
+        let _ = self.version.set(version.to_string());
+    }
+
+    /// Returns the path of the cached image for a diagram
+    ///
+    /// The name is a SHA-256 hash, so it stays the same across Rust versions
+    /// and platforms, and a shared cache keeps working after upgrading either.
+    ///
+    /// # Arguments
+    /// * `content` - The D2 source passed to the renderer
+    /// * `options` - Options set in the code block's info string
+    pub fn path(&self, content: &str, options: &[(String, String)]) -> PathBuf {
+        // Serialized as JSON so the inputs' boundaries are unambiguous
+        let key = serde_json::to_string(&(
+            &self.namespace,
+            &self.fingerprint,
+            self.version.get(),
+            content,
+            options,
+        ))
+        .expect("Failed to serialize cache key");
+        self.dir
+            .join(format!("{}.{}", source_hash(&key), self.extension))
+    }
+
+    /// Returns a cached image and its renderer diagnostics, if one is stored at the path
+    ///
+    /// # Arguments
+    /// * `path` - The path returned by [`RenderCache::path`]
+    pub fn get(path: &Path) -> Option<(Vec<u8>, String)> {
+        let bytes = fs::read(path).ok()?;
+        let diagnostics =
+            fs::read_to_string(path.with_extension(DIAGNOSTICS_EXTENSION)).unwrap_or_default();
+        Some((bytes, diagnostics))
+    }
+
+    /// Stores an image and its renderer diagnostics in the cache
+    ///
+    /// Files are written under a temporary name and renamed into place, so
+    /// concurrent builds never read a partially written file. The
+    /// diagnostics, if any, are written first, so a cached image always has
+    /// them.
+    ///
+    /// # Arguments
+    /// * `path` - The path returned by [`RenderCache::path`]
+    /// * `bytes` - The rendered image
+    /// * `diagnostics` - The renderer's diagnostics, e.g. D2's stderr
+    ///
+    /// # Errors
+    /// Returns an error if a file can't be written.
+    pub fn put(&self, path: &Path, bytes: &[u8], diagnostics: &str) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache directory {}", self.dir.display()))?;
+        if !diagnostics.is_empty() {
+            self.write(
+                &path.with_extension(DIAGNOSTICS_EXTENSION),
+                diagnostics.as_bytes(),
+            )?;
+        }
+        self.write(path, bytes)
+    }
+
+    /// Writes a file in the cache directory atomically
+    fn write(&self, path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut temp_file = tempfile::NamedTempFile::new_in(&self.dir).with_context(|| {
+            format!("Failed to create temporary file in {}", self.dir.display())
+        })?;
+        temp_file
+            .write_all(bytes)
+            .with_context(|| format!("Failed to write cached file {}", path.display()))?;
+        temp_file.persist(path).with_context(|| {
+            format!("Failed to move cached file into place: {}", path.display())
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = RenderCache::new(
+            dir.path().join("cache"),
+            "png",
+            String::from("--layout elk"),
+            None,
+        );
+        let path = cache.path("a -> b", &[]);
+        assert_eq!(RenderCache::get(&path), None);
+
+        cache.put(&path, b"image", "").unwrap();
+        assert_eq!(
+            RenderCache::get(&path),
+            Some((b"image".to_vec(), String::new()))
+        );
+        cache.put(&path, b"image", "warn: deprecated").unwrap();
+        assert_eq!(
+            RenderCache::get(&path),
+            Some((b"image".to_vec(), String::from("warn: deprecated")))
+        );
+
+        // Any input change leads to a different image
+        let options = [(String::from("pad"), String::from("40"))];
+        assert_ne!(cache.path("a -> c", &[]), path);
+        assert_ne!(cache.path("a -> b", &options), path);
+        cache.set_version("0.7.1");
+        assert_ne!(cache.path("a -> b", &[]), path);
+    }
+
+    #[test]
+    fn test_path_is_stable() {
+        let cache = RenderCache::new(PathBuf::from("cache"), "png", String::new(), None);
+        assert_eq!(
+            cache.path("a -> b", &[]),
+            Path::new("cache/567179755c6f26857a34945dcd44a4e32a6011210bb5786d8e93a13e7846d77b.png")
+        );
+    }
+
+    #[test]
+    fn test_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = |namespace: Option<&str>| {
+            RenderCache::new(
+                dir.path().to_path_buf(),
+                "png",
+                String::new(),
+                namespace.map(str::to_owned),
+            )
+        };
+        assert_eq!(
+            cache(None).path("a -> b", &[]),
+            cache(None).path("a -> b", &[])
+        );
+        assert_ne!(
+            cache(None).path("a -> b", &[]),
+            cache(Some("book-a")).path("a -> b", &[])
+        );
+        assert_ne!(
+            cache(Some("book-a")).path("a -> b", &[]),
+            cache(Some("book-b")).path("a -> b", &[])
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Resuming interrupted builds
+//!
+//! With `checkpoint-file`, every diagram file written is recorded in a
+//! progress file together with a hash of its source. If the build is
+//! interrupted, the next one skips diagrams whose file is recorded with the
+//! same source, and the progress file is removed once rendering completes.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+/// Progress of the render phase, persisted as it is made
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// Path of the progress file
+    path: PathBuf,
+    /// Source hashes of the diagram files completed by an earlier build
+    done: HashMap<String, String>,
+    /// The progress file, open for appending
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    /// Opens a progress file, reading the progress it already records
+    ///
+    /// # Arguments
+    /// * `path` - Path of the progress file, created if missing
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or opened for appending.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read checkpoint {}", path.display()))
+            }
+        };
+        let done = contents
+            .lines()
+            .filter_map(|line| {
+                let (hash, file) = line.split_once('\t')?;
+                Some((file.to_string(), hash.to_string()))
+            })
+            .collect();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open checkpoint {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            done,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Returns whether an earlier build completed a diagram file from this source
+    ///
+    /// # Arguments
+    /// * `file` - The diagram file's path, relative to the source directory
+    /// * `content` - The diagram's D2 source
+    pub fn is_done(&self, file: &str, content: &str) -> bool {
+        self.done.get(file) == Some(&source_hash(content))
+    }
+
+    /// Records a completed diagram file
+    ///
+    /// # Arguments
+    /// * `file` - The diagram file's path, relative to the source directory
+    /// * `content` - The diagram's D2 source
+    ///
+    /// # Errors
+    /// Returns an error if the progress file can't be written.
+    pub fn record(&self, file: &str, content: &str) -> anyhow::Result<()> {
+        let line = format!("{}\t{file}\n", source_hash(content));
+        self.file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write checkpoint {}", self.path.display()))
+    }
+
+    /// Removes the progress file after rendering completed
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be removed.
+    pub fn finish(self) -> anyhow::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove checkpoint {}", self.path.display()))
+    }
+}
+
+/// Hashes a diagram's source for the progress file, the manifest and the render cache
+///
+/// This is the hex-encoded SHA-256 digest of the source, so it stays the same
+/// across builds, platforms and Rust versions and can be recomputed by other
+/// tools.
+pub fn source_hash(content: &str) -> String {
+    sha256_hex(content.as_bytes())
+}
+
+/// Returns the hex-encoded SHA-256 digest of some bytes
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress");
+
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(!checkpoint.is_done("d2/1.1.png", "a -> b"));
+        checkpoint.record("d2/1.1.png", "a -> b").unwrap();
+        drop(checkpoint);
+
+        // An interrupted build leaves the progress for the next one
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(checkpoint.is_done("d2/1.1.png", "a -> b"));
+        assert!(!checkpoint.is_done("d2/1.1.png", "a -> c"));
+        assert!(!checkpoint.is_done("d2/1.2.png", "a -> b"));
+
+        checkpoint.finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_source_hash() {
+        assert_eq!(
+            source_hash("a -> b"),
+            "849092e16a6b17f6bde711e0135d9f4fafa54d744cd28b160357a9c47b3421cd"
+        );
+    }
+}
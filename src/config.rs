@@ -1,12 +1,23 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
+use anyhow::{bail, ensure, Context};
+use log::{warn, Level};
 use serde::Deserialize;
 
+use crate::backend::log_enabled;
+use crate::renderer::parse_semver;
+
 /// Default path to the D2 binary
 fn default_bin_path() -> PathBuf {
     PathBuf::from("d2")
 }
 
+/// Default language tag of D2 code blocks
+fn default_code_block_lang() -> String {
+    String::from("d2")
+}
+
 /// Default output directory for generated diagrams
 fn default_output_dir() -> PathBuf {
     PathBuf::from("d2")
@@ -17,24 +28,293 @@ const fn default_inline() -> bool {
     false
 }
 
+/// Default value for the render cache
+const fn default_cache() -> bool {
+    true
+}
+
+/// Default minimum width and height (in pixels) of a plausible diagram
+const fn default_min_image_size() -> u32 {
+    2
+}
+
+/// Default timeout in seconds for rendering a single diagram
+///
+/// This is a reasonable timeout for most diagrams. Very complex diagrams
+/// may take longer, but this helps prevent hanging on malformed input.
+const fn default_timeout() -> u64 {
+    30
+}
+
+/// Default timeout in seconds for the startup `d2 --version` check
+const fn default_preflight_timeout() -> u64 {
+    5
+}
+
+/// Default number of retries for the startup `d2 --version` check
+const fn default_preflight_retries() -> u32 {
+    2
+}
+
+/// Default value for deriving alt text from the diagram's label
+const fn default_alt_from_label() -> bool {
+    true
+}
+
+/// Default alt text template: the diagram's caption
+fn default_alt_template() -> String {
+    String::from("{caption}")
+}
+
+/// Placeholders accepted in `alt-template`
+pub const ALT_TEMPLATE_PLACEHOLDERS: &[&str] =
+    &["chapter", "section", "index", "figure", "caption"];
+
+/// Placeholders accepted in `output-dir`
+pub const OUTPUT_DIR_PLACEHOLDERS: &[&str] = &["lang"];
+
+/// Placeholders accepted in `watermark`
+pub const WATERMARK_PLACEHOLDERS: &[&str] = &["date", "sha"];
+
+/// How to treat a rendered image smaller than the configured minimum size
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TinyImageBehavior {
+    /// Log a warning and keep the image
+    #[default]
+    Warn,
+    /// Treat the diagram as failed
+    Error,
+}
+
+/// What to do with a diagram that fails to render
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RenderErrorBehavior {
+    /// Log the error and leave the diagram out of the page
+    #[default]
+    Skip,
+    /// Log the error and show it in the page where the diagram would be
+    Embed,
+    /// Fail the build
+    Fail,
+}
+
+/// How to treat `d2 --version` output that holds no recognizable version
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownVersionBehavior {
+    /// Log a warning and skip the version checks
+    #[default]
+    Warn,
+    /// Fail the build
+    Fail,
+    /// Silently skip the version checks
+    Ignore,
+}
+
+/// Scope within which figure numbers count up
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NumberingScope {
+    /// One running number across the whole book, e.g. `7`
+    Book,
+    /// Per chapter, with chapter numbers restarting at each part, e.g. `1.2`
+    Part,
+    /// Per chapter, prefixed with the chapter's section number, e.g. `3.2`
+    #[default]
+    Chapter,
+}
+
+/// Scope within which identical diagrams share one rendered image
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupScope {
+    /// Render every diagram separately
+    #[default]
+    None,
+    /// Share images between identical diagrams of the same chapter
+    Chapter,
+    /// Share images between identical diagrams anywhere in the book
+    Book,
+}
+
+/// Which diagrams the render cache stores
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheMode {
+    /// Cache every diagram
+    #[default]
+    All,
+    /// Cache only diagrams tagged `cache`
+    OptIn,
+}
+
+/// How a diagram is fitted into the dimensions set with `resize`
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFit {
+    /// Scale to fit within the dimensions, padding the rest with transparency
+    #[default]
+    Contain,
+    /// Scale to fill the dimensions, cropping what extends beyond them
+    Cover,
+    /// Scale each axis to the dimensions, ignoring the aspect ratio
+    Stretch,
+}
+
+/// Rasterization quality of rendered diagrams
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RenderQuality {
+    /// Render at D2's native resolution
+    #[default]
+    Standard,
+    /// Render at twice the resolution and downsample to the native size
+    High,
+}
+
+impl RenderQuality {
+    /// Returns the factor diagrams are rendered larger than displayed
+    pub const fn scale(self) -> u32 {
+        match self {
+            Self::Standard => 1,
+            Self::High => 2,
+        }
+    }
+}
+
+/// Factor by which D2 scales rendered diagrams
+///
+/// Only positive, finite values deserialize, which also makes equality total.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(try_from = "f64")]
+pub struct Scale(f64);
+
+impl Eq for Scale {}
+
+impl TryFrom<f64> for Scale {
+    type Error = String;
+
+    fn try_from(scale: f64) -> Result<Self, Self::Error> {
+        if scale.is_finite() && scale > 0.0 {
+            Ok(Self(scale))
+        } else {
+            Err(format!("`scale` must be a positive number, got {scale}"))
+        }
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An RGBA color, written as `#rrggbb` or `#rrggbbaa`
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(color: String) -> Result<Self, Self::Error> {
+        let channels = color
+            .strip_prefix('#')
+            .filter(|hex| matches!(hex.len(), 6 | 8) && hex.is_ascii())
+            .and_then(|hex| {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| {
+                format!("colors must be written as \"#rrggbb\" or \"#rrggbbaa\", got {color:?}")
+            })?;
+        Ok(Self([
+            channels[0],
+            channels[1],
+            channels[2],
+            channels.get(3).copied().unwrap_or(255),
+        ]))
+    }
+}
+
+/// Image format diagrams are rendered to
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Raster PNG images
+    #[default]
+    Png,
+    /// Vector SVG images, keeping text sharp at any zoom level
+    Svg,
+}
+
+impl OutputFormat {
+    /// Returns the file extension, from which D2 infers the format to render
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Svg => "svg",
+        }
+    }
+
+    /// Returns the format's MIME type, for data URIs
+    pub const fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+}
+
 #[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct Fonts {
     pub regular: PathBuf,
     pub italic: PathBuf,
     pub bold: PathBuf,
+    /// Font for semibold text, e.g. class names in UML class diagrams
+    #[serde(default)]
+    pub semibold: Option<PathBuf>,
 }
 #[derive(Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
+    /// Path (relative to the book root) of a TOML file holding further options
+    ///
+    /// Options set directly in `book.toml` take precedence over the file.
+    #[serde(rename = "config-file")]
+    pub options_file: Option<PathBuf>,
+
     /// The path to the d2 binary
     #[serde(default = "default_bin_path")]
     pub path: PathBuf,
 
+    /// Output directory for generated diagrams, relative to the source directory
+    ///
+    /// `{lang}` is replaced with `language`, so separate builds of a
+    /// localized book keep their diagrams apart (e.g. `"d2/{lang}"`).
     #[serde(default = "default_output_dir")]
     pub output_dir: PathBuf,
 
+    /// Language filled in for `{lang}` in `output-dir`
+    ///
+    /// Defaults to the book's `language`. Like any option, it can be set per
+    /// build with the `MDBOOK_PREPROCESSOR__D2_PNG__LANGUAGE` environment
+    /// variable.
+    pub language: Option<String>,
+
     pub layout: Option<String>,
 
+    /// Layout engine to retry with when rendering with `layout` fails
+    ///
+    /// Useful when a layout engine like `elk` occasionally chokes on a
+    /// diagram that renders fine with `dagre`.
+    pub fallback_layout: Option<String>,
+
     /// Whether to inline PNG images as base64 data URIs
     ///
     /// When `true`, images are embedded directly in the HTML.
@@ -42,36 +322,897 @@ pub struct Config {
     #[serde(default = "default_inline")]
     pub inline: bool,
 
-    /// Custom font path
+    /// JavaScript module (relative to the book's source directory) receiving
+    /// the diagrams' data URIs
+    ///
+    /// When set, images are emitted as `<img data-d2-id="...">` placeholders
+    /// and the data URIs are written to this file as an ES module whose
+    /// default export maps each diagram ID to its image, for JS-driven
+    /// book viewers. Takes precedence over `inline`.
+    pub js_data_file: Option<PathBuf>,
+
+    /// Custom font paths, relative to the book root
     ///
-    /// Only ttf fonts are valid
+    /// Only ttf fonts are valid; other extensions and missing files are
+    /// reported when the configuration is loaded.
     pub fonts: Option<Fonts>,
 
+    /// Directory (relative to the book root) D2 runs in
+    ///
+    /// D2 resolves `@import`s and local image paths in `icon:` and
+    /// `shape: image` declarations against its working directory. Set this
+    /// to the directory holding those files (e.g. `"assets"`) so they resolve
+    /// the same in every chapter. Defaults to the directory of the chapter
+    /// containing the diagram.
+    pub asset_root: Option<PathBuf>,
+
+    /// Fall back to D2's default font when a configured font file is missing
+    ///
+    /// Font paths are checked once when the configuration is loaded. A
+    /// missing file fails the build unless this is `true`, in which case a
+    /// warning is logged and the custom fonts are dropped.
+    #[serde(default)]
+    pub font_fallback: bool,
+
     pub theme_id: Option<String>,
     pub dark_theme_id: Option<String>,
+
+    /// Theme ID used instead of `theme-id` when images are embedded as data
+    /// URIs (`inline` or `js-data-file`)
+    pub inline_theme_id: Option<String>,
+
+    /// Theme ID used instead of `theme-id` when images are written as files
+    pub embedded_theme_id: Option<String>,
+
+    /// Render diagrams in D2's hand-drawn sketch mode
+    ///
+    /// Individual diagrams can override this with `sketch=true` or
+    /// `sketch=false` in their info string.
+    #[serde(default)]
+    pub sketch: bool,
+
+    /// Padding in pixels around diagrams (D2's `--pad`)
+    ///
+    /// When unset, D2's default of 100 pixels applies. Individual diagrams
+    /// can override this with `pad=<n>` in their info string.
+    pub pad: Option<u32>,
+
+    /// Further command-line flags passed verbatim to the D2 binary
+    ///
+    /// Appended after the flags derived from other options, e.g.
+    /// `["--center", "--force-appendix"]`. They aren't checked, so a flag
+    /// D2 doesn't know fails every diagram.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+
+    /// Language tag of the code blocks rendered as D2 diagrams
+    #[serde(default = "default_code_block_lang")]
+    pub code_block_lang: String,
+
+    /// Further language tags rendered as D2 diagrams, e.g. `["d2lang"]`
+    ///
+    /// For books whose D2 code blocks use other tags. Blocks with any of
+    /// these tags are treated exactly like `code-block-lang` blocks.
+    #[serde(default)]
+    pub code_block_aliases: Vec<String>,
+
+    /// Image format diagrams are rendered to
+    ///
+    /// `"svg"` keeps text sharp where rasterized PNGs look blurry. Image
+    /// files get the `.svg` extension and inline images `image/svg+xml` data
+    /// URIs. PNG-specific options (`quality`, `embed-srgb`, `autocrop`, `resize`,
+    /// `palette`, size attributes and the minimum image size check) don't apply to SVG.
+    #[serde(default)]
+    pub format: OutputFormat,
+
+    /// Rasterization quality of rendered diagrams
+    ///
+    /// D2 has no anti-aliasing setting, so `"high"` supersamples instead:
+    /// diagrams are rendered with `--scale 2` and downsampled back to their
+    /// native size with a Lanczos filter, for smoother lines and text at the
+    /// cost of slower rendering.
+    #[serde(default)]
+    pub quality: RenderQuality,
+
+    /// Factor by which D2 scales rendered diagrams (D2's `--scale`)
+    ///
+    /// Renders PNGs at e.g. 2x or 3x resolution for high-DPI screens. The
+    /// images are displayed at their pixel size. It can't be combined with
+    /// `quality = "high"`, which sets the scale itself.
+    pub scale: Option<Scale>,
+
+    /// Marker line splitting a single code block into several diagrams
+    ///
+    /// When set, any line of a `d2` block consisting solely of this marker
+    /// (e.g. `---`) ends one diagram and starts the next. Each part is
+    /// rendered separately and emitted in order.
+    pub split_delimiter: Option<String>,
+
+    /// Write the input of diagrams that fail to render to disk
+    ///
+    /// When `true`, the exact content piped to D2 for each failing diagram
+    /// is saved under `failed-diagrams/` in the book root, named after the
+    /// chapter and diagram index, so it can be reproduced with `d2` directly.
+    #[serde(default)]
+    pub dump_failures: bool,
+
+    /// Whether to use a diagram's top-level `label` or `title` as its alt text
+    ///
+    /// When `true` (default), a diagram declaring e.g. `title: Request flow`
+    /// at the top level gets "Request flow" as the image's alt text.
+    #[serde(default = "default_alt_from_label")]
+    pub alt_from_label: bool,
+
+    /// Template for image alt text
+    ///
+    /// Supports the placeholders `{chapter}` (chapter name), `{section}`
+    /// (section number, e.g. `1.2`), `{index}` (diagram index within the
+    /// chapter) and `{caption}` (the diagram's `alt` info string attribute or
+    /// `# alt:` comment, else its label or title, see `alt-from-label`). An
+    /// empty result falls back to the chapter name and diagram index.
+    #[serde(default = "default_alt_template")]
+    pub alt_template: String,
+
+    /// Scope of the figure numbers filled in for `{figure}` in `alt-template`
+    ///
+    /// `"chapter"` numbers diagrams per chapter after its section number
+    /// (`1.2.3`), `"part"` does the same but restarts chapter numbers at each
+    /// part of the book, and `"book"` counts diagrams across the whole book.
+    #[serde(default)]
+    pub numbering_scope: NumberingScope,
+
+    /// Substrings of D2 warnings to drop instead of logging
+    ///
+    /// Warnings D2 prints while successfully rendering a diagram are logged;
+    /// any containing one of these substrings (e.g. a notice about default
+    /// fonts) are considered benign and suppressed.
+    #[serde(default)]
+    pub suppress_warnings: Vec<String>,
+
+    /// Fail diagrams that render with D2 warnings
+    ///
+    /// When `true`, a diagram D2 renders successfully but with warnings not
+    /// covered by `suppress-warnings` (e.g. deprecated syntax) is treated as
+    /// a failed render, enforcing warning-free diagram sources.
+    #[serde(default)]
+    pub warnings_as_errors: bool,
+
+    /// Hex-dump invalid bytes in D2's error output
+    ///
+    /// Error messages always note when D2's stderr wasn't valid UTF-8 (the
+    /// invalid bytes are shown as `�`). When `true`, a hex dump of the bytes
+    /// around the first invalid sequence is included as well.
+    #[serde(default)]
+    pub hex_dump_stderr: bool,
+
+    /// Whether to mark rendered PNGs as sRGB
+    ///
+    /// When `true`, an `sRGB` color profile chunk is inserted into each PNG
+    /// that lacks one, so colors reproduce consistently in print pipelines.
+    #[serde(default)]
+    pub embed_srgb: bool,
+
+    /// Whether to rewrite element IDs in rendered SVGs to deterministic ones
+    ///
+    /// When `true`, the IDs D2 generates are renumbered in order of
+    /// appearance, so committed SVGs only change when their diagrams do.
+    #[serde(default)]
+    pub stable_svg_ids: bool,
+
+    /// Whether to trim uniform borders off rendered PNGs
+    ///
+    /// Even with `pad = 0`, D2 sometimes leaves asymmetric margins. When
+    /// `true`, edge rows and columns of the `autocrop-background` color are
+    /// removed, before any `resize`.
+    #[serde(default)]
+    pub autocrop: bool,
+
+    /// Background color trimmed by `autocrop`, defaulting to the color of
+    /// each image's top-left pixel
+    pub autocrop_background: Option<Color>,
+
+    /// Number of colors to quantize rendered PNGs to, from 2 to 256
+    ///
+    /// Writes indexed PNGs, which are much smaller for diagrams with few
+    /// colors. Images with no more colors than this keep them exactly.
+    pub palette: Option<u16>,
+
+    /// Exact width and height in pixels to resize rendered PNGs to
+    ///
+    /// Gives every diagram the same dimensions, e.g. for grid layouts, which
+    /// D2's `--scale` alone can't. How diagrams of another aspect ratio are
+    /// fitted is set with `resize-fit`.
+    pub resize: Option<(u32, u32)>,
+
+    /// How diagrams are fitted into the `resize` dimensions
+    #[serde(default)]
+    pub resize_fit: ResizeFit,
+
+    /// Suppress all non-error logging
+    ///
+    /// When `true`, only failures are logged; warnings and informational
+    /// messages from this preprocessor are dropped.
+    #[serde(default)]
+    pub quiet: bool,
+
+    /// Reuse images rendered by earlier builds
+    ///
+    /// When `true`, rendered images are stored under `.d2-png-cache/` in the
+    /// book root, keyed by a hash of the diagram source, its info string
+    /// options, the D2 flags and the D2 version, and unchanged diagrams skip
+    /// D2 entirely. Changing any of these, including upgrading D2, misses
+    /// the cache; delete the directory to reclaim space from stale entries.
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+
+    /// Namespace folded into the render cache's keys
+    ///
+    /// Keeps the cache entries of books sharing a book root, e.g. variants
+    /// of one book built with different environment overrides, apart even
+    /// when their diagrams and settings are identical.
+    #[serde(default)]
+    pub cache_namespace: Option<String>,
+
+    /// Which diagrams the render cache stores
+    ///
+    /// `"all"` caches every diagram. `"opt-in"` only caches diagrams tagged
+    /// `cache` in their info string, e.g. stable hand-drawn ones, while the
+    /// rest, e.g. generated ones, are rendered on every build.
+    #[serde(default)]
+    pub cache_mode: CacheMode,
+
+    /// Timeout in seconds for rendering a single diagram
+    ///
+    /// D2 is killed and the diagram fails when it runs longer, guarding
+    /// against hangs on malformed input. Raise it for large diagrams that
+    /// legitimately take longer, e.g. with the ELK layout. `0` disables the
+    /// timeout.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    /// Number of times to retry a diagram whose D2 process failed transiently
+    ///
+    /// Covers D2 failing to start for lack of resources (the process limit
+    /// or memory) or being killed by a signal, e.g. by the OOM killer on a
+    /// busy CI machine, which would otherwise leave a diagram missing at
+    /// random. Any exit status D2 returns itself is never retried.
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Timeout in seconds for the startup `d2 --version` check
+    ///
+    /// Kept separate from the per-diagram timeout so a slow spawn on a busy
+    /// machine fails fast and can be retried.
+    #[serde(default = "default_preflight_timeout")]
+    pub preflight_timeout: u64,
+
+    /// Number of times to retry a failed startup `d2 --version` check
+    #[serde(default = "default_preflight_retries")]
+    pub preflight_retries: u32,
+
+    /// Exact D2 version the build requires (e.g. `0.7.1`)
+    ///
+    /// Checked against the startup `d2 --version` output; any other version
+    /// fails the build, for hermetic, reproducible renders.
+    pub require_d2_version: Option<String>,
+
+    /// Oldest D2 version the build accepts (e.g. `0.7.1`)
+    ///
+    /// Checked against the startup `d2 --version` output, so options needing
+    /// a recent D2 fail with a clear message rather than an obscure D2
+    /// error. Defaults to the oldest release the preprocessor supports.
+    pub min_d2_version: Option<String>,
+
+    /// What to do when `d2 --version` doesn't report a recognizable version
+    ///
+    /// Custom builds and wrapper scripts may print something other than a
+    /// `0.7.1`-style version. With `"warn"` or `"ignore"`, version checks
+    /// such as `require-d2-version` are then skipped so the build still
+    /// works; `"fail"` stops it instead.
+    #[serde(default)]
+    pub on_unknown_version: UnknownVersionBehavior,
+
+    /// Node count above which a diagram triggers a warning
+    ///
+    /// Nodes are counted with a cheap heuristic over the diagram source.
+    /// Diagrams exceeding the threshold are likely to be slow or unreadable
+    /// and are worth splitting.
+    pub warn_complexity_nodes: Option<usize>,
+
+    /// Minimum width and height (in pixels) of a plausible diagram
+    ///
+    /// Degenerate input can make D2 "succeed" with a 0x0 or 1x1 image that
+    /// shows nothing. Images with a side shorter than this are flagged.
+    #[serde(default = "default_min_image_size")]
+    pub min_image_size: u32,
+
+    /// Whether an image below `min-image-size` is a warning or an error
+    #[serde(default)]
+    pub tiny_image: TinyImageBehavior,
+
+    /// What to do with a diagram that fails to render
+    ///
+    /// `"skip"` logs the error and leaves the diagram out of the page.
+    /// `"embed"` also shows the error, including D2's output, in a
+    /// `<pre class="d2-error">` block where the diagram would have been, so
+    /// failures are obvious when reading the book. `"fail"` stops the build
+    /// after all diagrams have been attempted.
+    #[serde(default)]
+    pub on_error: RenderErrorBehavior,
+
+    /// What to do with a diagram whose `file` can't be read
+    ///
+    /// Takes the same values as `on-error`, which it defaults to, e.g. to
+    /// only skip diagrams with a broken reference in a draft build that
+    /// otherwise fails on errors.
+    #[serde(default)]
+    pub on_missing_file: Option<RenderErrorBehavior>,
+
+    /// Fail the build if any diagram fails to render
+    ///
+    /// Shorthand for `on-error = "fail"`, taking precedence over `on-error`,
+    /// for CI pipelines that must never publish a book with missing diagrams.
+    /// All diagrams are attempted and every failure is reported together.
+    #[serde(default)]
+    pub strict: bool,
+
+    /// Seconds over which to ramp up concurrent D2 processes
+    ///
+    /// When set, rendering starts with a single D2 process and the limit
+    /// grows linearly to the maximum over this period, instead of spawning
+    /// the full batch at once on a cold machine.
+    pub concurrency_ramp: Option<u64>,
+
+    /// Maximum number of concurrent D2 processes
+    ///
+    /// Defaults to the number of CPUs, capped at 8. Lower it on machines
+    /// where that many renders exhaust memory, raise it on large ones; `1`
+    /// renders diagrams one at a time, e.g. for debugging.
+    pub max_concurrency: Option<usize>,
+
+    /// Maximum total node count of diagrams rendered at once
+    ///
+    /// Each diagram's weight is its estimated node count. When set, renders
+    /// wait until the running diagrams' combined weight leaves room, so many
+    /// small diagrams render in parallel while large ones are serialized.
+    /// A diagram larger than the limit renders on its own.
+    pub max_concurrent_nodes: Option<usize>,
+
+    /// Maximum number of concurrent renders per layout engine
+    ///
+    /// Layout engines differ widely in resource use, e.g.
+    /// `{ tala = 2, dagre = 6 }`. Each diagram counts against the budget of
+    /// its layout: the `layout-engine` it declares in `d2-config`, else
+    /// `layout`, else `dagre`. Layouts without a budget are only limited by
+    /// the overall process cap.
+    #[serde(default)]
+    pub layout_concurrency: BTreeMap<String, usize>,
+
+    /// Environment variables whose presence forces sequential rendering
+    ///
+    /// Some sandboxed CI environments forbid spawning several child
+    /// processes at once. When any of the listed variables is set (e.g.
+    /// `["RESTRICTED_CI"]`), diagrams render one at a time, so the same
+    /// `book.toml` builds in parallel locally and sequentially there.
+    #[serde(default)]
+    pub no_parallel_env: Vec<String>,
+
+    /// Timeout in seconds for rendering all diagrams of the book
+    ///
+    /// Bounds the whole render phase on top of the per-diagram timeout.
+    /// Once exceeded, diagrams that haven't started rendering are skipped
+    /// and the build fails.
+    pub total_timeout: Option<u64>,
+
+    /// Maximum number of diagrams to render per build
+    ///
+    /// Meant for fast preview builds of large books: diagrams beyond the
+    /// cap (in book order) are replaced with a visible "skipped" note.
+    pub max_diagrams: Option<usize>,
+
+    /// Maximum number of diagrams a single chapter may contain
+    ///
+    /// A safety valve against pathological generated input: a chapter with
+    /// more diagrams fails the build instead of spawning thousands of D2
+    /// processes.
+    pub max_diagrams_per_chapter: Option<usize>,
+
+    /// Tags selecting the diagrams to render, for focused preview builds
+    ///
+    /// When non-empty, only code blocks whose `tag` attribute (e.g.
+    /// `d2 tag=core`, or several as `tag=core,api`) lists one of these tags
+    /// are rendered; all other D2 blocks are left as plain code blocks.
+    #[serde(default)]
+    pub only_tags: Vec<String>,
+
+    /// Number of diagrams per chapter to load eagerly with high priority
+    ///
+    /// When set, images are emitted as HTML carrying loading hints: the
+    /// first `eager-count` diagrams of each chapter get `loading="eager"`
+    /// and `fetchpriority="high"`, the rest `loading="lazy"`.
+    pub eager_count: Option<usize>,
+
+    /// Load all diagrams lazily
+    ///
+    /// When `true`, images are emitted as HTML with `loading="lazy"`, so
+    /// browsers defer offscreen diagrams. Shorthand for `eager-count = 0`;
+    /// an explicit `eager-count` takes precedence.
+    #[serde(default)]
+    pub lazy: bool,
+
+    /// Warn about byte-identical diagrams appearing more than once
+    ///
+    /// Pasting the same diagram in two places is usually a mistake; when
+    /// `true`, each set of duplicates is logged with the chapters they're in.
+    #[serde(default)]
+    pub warn_duplicates: bool,
+
+    /// Absolute URL of the book's source directory on the published site
+    ///
+    /// Used to make the `og:image` URL of diagrams tagged `og-image`
+    /// absolute, as social networks require (e.g.
+    /// `"https://example.com/book"`). Without it, the URL is relative to the
+    /// chapter.
+    pub og_image_base_url: Option<String>,
+
+    /// Progress file (relative to the book root) making long builds resumable
+    ///
+    /// Each diagram file written is recorded along with a hash of its
+    /// source. After an interrupted build, the next one keeps recorded files
+    /// whose source is unchanged instead of rendering them again. The file
+    /// is removed once rendering completes. Only applies when images are
+    /// written as files.
+    pub checkpoint_file: Option<PathBuf>,
+
+    /// Git ref to compare diagram sources against, marking changed diagrams
+    ///
+    /// Each chapter's diagrams are compared with the chapter file at this
+    /// ref (e.g. `"main"`); diagrams that don't appear there get a
+    /// `data-changed="true"` attribute, which a stylesheet can turn into a
+    /// badge for reviewers.
+    pub changed_since: Option<String>,
+
+    /// Scope within which identical diagrams are rendered only once
+    ///
+    /// With `"chapter"` or `"book"`, byte-identical diagrams in the same
+    /// chapter or anywhere in the book share the first one's image (file,
+    /// data URI or data file entry), keeping their own alt text. `"none"`
+    /// renders and writes each diagram separately, e.g. for per-chapter
+    /// asset bundling. Diagrams with a `stable_id` always get their own file.
+    #[serde(default)]
+    pub dedup_scope: DedupScope,
+
+    /// Emit print-friendly image markup
+    ///
+    /// When `true` (e.g. for a PDF build), images are emitted as plain HTML
+    /// with `class="d2-diagram d2-print"` and without loading hints, so
+    /// print stylesheets can target them.
+    #[serde(default)]
+    pub print: bool,
+
+    /// Display width of images in pixels
+    ///
+    /// Emits images as HTML with a `width` attribute, e.g. to fit wide
+    /// diagrams into the content column. Individual diagrams can set their
+    /// own size with `width=...` and `height=...` in the info string,
+    /// replacing both configured values.
+    pub width: Option<u32>,
+
+    /// Display height of images in pixels, as for `width`
+    pub height: Option<u32>,
+
+    /// Add the rendered image's pixel size as data attributes
+    ///
+    /// When `true`, each image carries `data-natural-width` and
+    /// `data-natural-height` read from the PNG header, for scripts that lay
+    /// out diagrams before they load. Unlike `width`/`height`, these don't
+    /// affect how the image is displayed.
+    #[serde(default)]
+    pub natural_size_attributes: bool,
+
+    /// Let the browser decode images off the main thread
+    ///
+    /// When `true`, images are emitted as HTML with `decoding="async"`,
+    /// alongside any loading hints and size attributes. Print markup is left
+    /// without it, so images are decoded before the page is printed.
+    #[serde(default)]
+    pub decoding_async: bool,
+
+    /// Make diagrams zoomable by clicking them
+    ///
+    /// When `true`, each image is wrapped in a `d2-zoom` container and a small
+    /// self-contained CSS/JS snippet is added once to every chapter with
+    /// diagrams. Clicking a diagram toggles it between fitting the page and
+    /// twice its width, scrollable to pan.
+    #[serde(default)]
+    pub zoomable: bool,
+
+    /// Class of a size container wrapped around each diagram
+    ///
+    /// When set, each image is wrapped in a block with this class and
+    /// `container-type: inline-size`, so CSS container queries (e.g.
+    /// `@container (max-width: 30em) { ... }`) can size diagrams relative to
+    /// the column or sidebar holding them instead of the viewport.
+    pub container_class: Option<String>,
+
+    /// Shell command run on each diagram file after it is written
+    ///
+    /// `{file}` is replaced with the quoted path of the generated PNG (e.g.
+    /// `"optipng -quiet {file}"`). The command runs through `sh -c` (`cmd /C`
+    /// on Windows) in the render pool; a failing command fails its diagram.
+    /// Only applies when images are written as files.
+    pub post_render_command: Option<String>,
+
+    /// Buffer diagram files in memory and write them after rendering
+    ///
+    /// When `true`, the PNGs aren't written from the render pool as each
+    /// diagram finishes but all at once, one after another, once every
+    /// diagram is rendered. This avoids many concurrent small writes, which
+    /// are slow on network filesystems, at the cost of holding every image
+    /// in memory. Can't be combined with `post-render-command`, which needs
+    /// each file on disk as soon as it is rendered.
+    #[serde(default)]
+    pub batch_writes: bool,
+
+    /// Add a hash of each rendered diagram to its file name
+    ///
+    /// When `true`, files are named e.g. `1.2.3.0f1e2d3c.png` instead of
+    /// `1.2.3.png`, so a changed diagram gets a new URL and CDNs or browsers
+    /// never serve a stale copy. `manifest.json` lists each file's SHA-256
+    /// for integrity checks either way. Diagrams with a `stable_id` keep
+    /// their permanent name. Can't be combined with `checkpoint-file`, which
+    /// finds completed files by their name before rendering them.
+    #[serde(default)]
+    pub hashed_filenames: bool,
+
+    /// Text stamped in the corner of every diagram, e.g. for review copies
+    ///
+    /// `{date}` is replaced with the build date and `{sha}` with the book
+    /// repository's abbreviated git commit. The text is added to each
+    /// diagram's D2 source as a small shape near its bottom-right corner.
+    pub watermark: Option<String>,
+}
+
+impl Config {
+    /// Builds the configuration from the preprocessor's `book.toml` table
+    ///
+    /// If the table sets `config-file`, that file is loaded (relative to the
+    /// book root) and merged underneath the table, so keys set in `book.toml`
+    /// win over keys from the file.
+    ///
+    /// # Errors
+    /// Returns an error if the config file can't be read or either source
+    /// contains invalid options.
+    pub fn from_table(table: toml::Table, root: &Path) -> anyhow::Result<Self> {
+        let Some(config_file) = table.get("config-file") else {
+            let mut config: Self = table.try_into()?;
+            config.validate()?;
+            config.check_fonts(root)?;
+            config.check_asset_root(root)?;
+            return Ok(config);
+        };
+
+        let config_file = config_file
+            .as_str()
+            .context("`config-file` must be a path string")?;
+        let path = root.join(config_file);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read d2-png config file {}", path.display()))?;
+        let mut merged: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("Invalid d2-png config file {}", path.display()))?;
+
+        merged.extend(table);
+        let mut config: Self = merged.try_into()?;
+        config.validate()?;
+        config.check_fonts(root)?;
+        config.check_asset_root(root)?;
+        Ok(config)
+    }
+
+    /// Checks that the configured font files exist and are TTF fonts
+    ///
+    /// With `font-fallback`, missing files drop the custom fonts with a
+    /// warning instead, so D2 renders with its default font.
+    ///
+    /// # Arguments
+    /// * `root` - The book's root directory, against which relative paths resolve
+    ///
+    /// # Errors
+    /// Returns an error listing the font files without a `.ttf` extension,
+    /// or the missing font files unless falling back.
+    pub fn check_fonts(&mut self, root: &Path) -> anyhow::Result<()> {
+        let Some(fonts) = &self.fonts else {
+            return Ok(());
+        };
+        let paths: Vec<_> = [&fonts.regular, &fonts.italic, &fonts.bold]
+            .into_iter()
+            .chain(&fonts.semibold)
+            .collect();
+        let not_ttf: Vec<_> = paths
+            .iter()
+            .filter(|path| {
+                !path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("ttf"))
+            })
+            .map(|path| path.display().to_string())
+            .collect();
+        ensure!(
+            not_ttf.is_empty(),
+            "Font file(s) without a .ttf extension: {}. D2 only accepts TrueType fonts",
+            not_ttf.join(", ")
+        );
+        let missing: Vec<_> = paths
+            .iter()
+            .filter(|path| !root.join(path).is_file())
+            .map(|path| path.display().to_string())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        ensure!(
+            self.font_fallback,
+            "Font file(s) not found: {}. Fix the `fonts` paths or set `font-fallback = true` \
+             to render with D2's default font",
+            missing.join(", ")
+        );
+        if log_enabled(self.quiet, Level::Warn) {
+            warn!(
+                "Font file(s) not found: {}. Falling back to D2's default font",
+                missing.join(", ")
+            );
+        }
+        self.fonts = None;
+        Ok(())
+    }
+
+    /// Checks that the configured asset root is a directory
+    ///
+    /// # Arguments
+    /// * `root` - The book's root directory, against which relative paths resolve
+    ///
+    /// # Errors
+    /// Returns an error if `asset-root` is set but isn't a directory.
+    pub fn check_asset_root(&self, root: &Path) -> anyhow::Result<()> {
+        let Some(asset_root) = &self.asset_root else {
+            return Ok(());
+        };
+        ensure!(
+            root.join(asset_root).is_dir(),
+            "`asset-root` directory {} not found",
+            asset_root.display()
+        );
+        Ok(())
+    }
+
+    /// Returns the theme ID configured for the current output mode, if any
+    pub const fn mode_theme_id(&self) -> Option<&String> {
+        if self.inline || self.js_data_file.is_some() {
+            self.inline_theme_id.as_ref()
+        } else {
+            self.embedded_theme_id.as_ref()
+        }
+    }
+
+    /// Returns the theme of the dark variants rendered next to diagram files
+    ///
+    /// Diagrams written as PNG files with both a light and a dark theme
+    /// configured are also rendered with the dark theme, and shown in a
+    /// `<picture>` that follows the reader's color scheme. PNGs can't switch
+    /// themes themselves, unlike D2's SVGs.
+    pub fn picture_dark_theme_id(&self) -> Option<&String> {
+        let files = !self.inline && self.js_data_file.is_none() && self.format == OutputFormat::Png;
+        let light = self.mode_theme_id().or(self.theme_id.as_ref()).is_some();
+        self.dark_theme_id.as_ref().filter(|_| files && light)
+    }
+
+    /// Checks option values that deserialization alone can't validate
+    ///
+    /// # Errors
+    /// Returns an error if `alt-template`, `output-dir` or `watermark` is
+    /// malformed or uses an unknown placeholder, or if incompatible options
+    /// (`embed-srgb`, `autocrop`, `palette` or `resize` with SVG output,
+    /// `stable-svg-ids` with PNG output, `batch-writes` with
+    /// `post-render-command`, `hashed-filenames` with `checkpoint-file`) are
+    /// combined.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_placeholders(
+            "alt-template",
+            &self.alt_template,
+            ALT_TEMPLATE_PLACEHOLDERS,
+        )?;
+        check_placeholders(
+            "output-dir",
+            &self.output_dir.to_string_lossy(),
+            OUTPUT_DIR_PLACEHOLDERS,
+        )?;
+        if let Some(watermark) = &self.watermark {
+            check_placeholders("watermark", watermark, WATERMARK_PLACEHOLDERS)?;
+        }
+        if let Some(version) = &self.min_d2_version {
+            ensure!(
+                parse_semver(version).is_some(),
+                "`min-d2-version` must be a version like \"0.7.1\", got {version:?}"
+            );
+        }
+        ensure!(
+            self.max_concurrency != Some(0),
+            "`max-concurrency` must be at least 1"
+        );
+        ensure!(
+            !(self.embed_srgb && self.format == OutputFormat::Svg),
+            "`embed-srgb` only applies to PNG images and can't be combined with `format = \"svg\"`"
+        );
+        ensure!(
+            !(self.autocrop && self.format == OutputFormat::Svg),
+            "`autocrop` only applies to PNG images and can't be combined with `format = \"svg\"`"
+        );
+        if let Some(colors) = self.palette {
+            ensure!(
+                (2..=256).contains(&colors),
+                "`palette` must be from 2 to 256 colors, got {colors}"
+            );
+            ensure!(
+                self.format == OutputFormat::Png,
+                "`palette` only applies to PNG images and can't be combined with `format = \"svg\"`"
+            );
+        }
+        if let Some((width, height)) = self.resize {
+            ensure!(
+                width > 0 && height > 0,
+                "`resize` dimensions must be at least 1 pixel, got {width}x{height}"
+            );
+            ensure!(
+                self.format == OutputFormat::Png,
+                "`resize` only applies to PNG images and can't be combined with `format = \"svg\"`"
+            );
+        }
+        ensure!(
+            !(self.stable_svg_ids && self.format == OutputFormat::Png),
+            "`stable-svg-ids` only applies to SVG images and requires `format = \"svg\"`"
+        );
+        ensure!(
+            !(self.scale.is_some() && self.quality == RenderQuality::High),
+            "`scale` can't be combined with `quality = \"high\"`, which sets the scale itself"
+        );
+        ensure!(
+            !(self.batch_writes && self.post_render_command.is_some()),
+            "`batch-writes` can't be combined with `post-render-command`, which runs on each \
+             file as soon as it is written"
+        );
+        ensure!(
+            !(self.hashed_filenames && self.checkpoint_file.is_some()),
+            "`hashed-filenames` can't be combined with `checkpoint-file`, which finds completed \
+             files by their name"
+        );
+        Ok(())
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            options_file: None,
             path: default_bin_path(),
             layout: None,
+            fallback_layout: None,
             output_dir: default_output_dir(),
+            language: None,
             inline: default_inline(),
+            js_data_file: None,
             fonts: None,
+            asset_root: None,
+            font_fallback: false,
             theme_id: None,
             dark_theme_id: None,
+            inline_theme_id: None,
+            embedded_theme_id: None,
+            sketch: false,
+            pad: None,
+            extra_args: Vec::new(),
+            code_block_lang: default_code_block_lang(),
+            code_block_aliases: Vec::new(),
+            format: OutputFormat::default(),
+            quality: RenderQuality::default(),
+            scale: None,
+            split_delimiter: None,
+            dump_failures: false,
+            alt_from_label: default_alt_from_label(),
+            alt_template: default_alt_template(),
+            numbering_scope: NumberingScope::default(),
+            suppress_warnings: Vec::new(),
+            warnings_as_errors: false,
+            hex_dump_stderr: false,
+            embed_srgb: false,
+            stable_svg_ids: false,
+            autocrop: false,
+            autocrop_background: None,
+            palette: None,
+            resize: None,
+            resize_fit: ResizeFit::default(),
+            quiet: false,
+            cache: default_cache(),
+            cache_namespace: None,
+            cache_mode: CacheMode::default(),
+            timeout: default_timeout(),
+            retries: 0,
+            preflight_timeout: default_preflight_timeout(),
+            preflight_retries: default_preflight_retries(),
+            require_d2_version: None,
+            min_d2_version: None,
+            on_unknown_version: UnknownVersionBehavior::default(),
+            warn_complexity_nodes: None,
+            min_image_size: default_min_image_size(),
+            tiny_image: TinyImageBehavior::default(),
+            on_error: RenderErrorBehavior::default(),
+            on_missing_file: None,
+            strict: false,
+            concurrency_ramp: None,
+            max_concurrency: None,
+            max_concurrent_nodes: None,
+            layout_concurrency: BTreeMap::new(),
+            no_parallel_env: Vec::new(),
+            total_timeout: None,
+            max_diagrams: None,
+            max_diagrams_per_chapter: None,
+            only_tags: Vec::new(),
+            eager_count: None,
+            lazy: false,
+            warn_duplicates: false,
+            og_image_base_url: None,
+            checkpoint_file: None,
+            changed_since: None,
+            dedup_scope: DedupScope::default(),
+            print: false,
+            width: None,
+            height: None,
+            natural_size_attributes: false,
+            decoding_async: false,
+            zoomable: false,
+            container_class: None,
+            post_render_command: None,
+            batch_writes: false,
+            hashed_filenames: false,
+            watermark: None,
         }
     }
 }
 
+/// Checks that a template's placeholders are closed and known
+///
+/// # Arguments
+/// * `option` - The option's name, for error messages
+/// * `template` - The template to check
+/// * `placeholders` - The placeholders the option accepts
+fn check_placeholders(option: &str, template: &str, placeholders: &[&str]) -> anyhow::Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in `{option}`: {template}");
+        };
+        let name = &rest[start + 1..start + end];
+        ensure!(
+            placeholders.contains(&name),
+            "Unknown placeholder `{{{name}}}` in `{option}`; expected one of {}",
+            placeholders
+                .iter()
+                .map(|placeholder| format!("{{{placeholder}}}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::path::PathBuf;
 
     use test_case::test_case;
 
-    use super::Config;
+    use super::{Config, Scale};
 
     #[test_case(""; "empty")]
     #[test_case(
@@ -96,15 +1237,239 @@ output-dir = "d2-img"
     => Config {
         path: PathBuf::from("/custom/bin/d2"),
         layout: Some(String::from("elk")),
-        inline: false,
         output_dir: PathBuf::from("d2-img"),
-        fonts: None,
-        theme_id: None,
-        dark_theme_id:None,
+        ..Config::default()
     }
         ; "custom"
     )]
+    #[test_case(
+        r#"
+split-delimiter = "---"
+"#
+    => Config {
+        split_delimiter: Some(String::from("---")),
+        ..Config::default()
+    }
+        ; "split delimiter"
+    )]
+    #[test_case(
+        "
+sketch = true
+"
+    => Config {
+        sketch: true,
+        ..Config::default()
+    }
+        ; "sketch"
+    )]
+    #[test_case(
+        "
+scale = 2.5
+"
+    => Config {
+        scale: Some(Scale::try_from(2.5).unwrap()),
+        ..Config::default()
+    }
+        ; "scale"
+    )]
     fn parse(input: &str) -> Config {
         toml::from_str(input).unwrap()
     }
+
+    #[test_case("scale = 2" => true; "integer")]
+    #[test_case("scale = 0.5" => true; "fraction")]
+    #[test_case("scale = 0" => false; "zero")]
+    #[test_case("scale = -2.0" => false; "negative")]
+    #[test_case("scale = nan" => false; "not a number")]
+    fn parse_scale(input: &str) -> bool {
+        toml::from_str::<Config>(input).is_ok()
+    }
+
+    #[test_case(r##"autocrop-background = "#1a2B3c""## => Some([0x1a, 0x2b, 0x3c, 255]); "rgb")]
+    #[test_case(r##"autocrop-background = "#ffffff00""## => Some([255, 255, 255, 0]); "rgba")]
+    #[test_case(r#"autocrop-background = "ffffff""# => None; "missing hash")]
+    #[test_case(r##"autocrop-background = "#fff""## => None; "short")]
+    #[test_case(r##"autocrop-background = "#gggggg""## => None; "not hex")]
+    #[test_case(r##"autocrop-background = "#ffffé""## => None; "not ascii")]
+    fn parse_color(input: &str) -> Option<[u8; 4]> {
+        toml::from_str::<Config>(input)
+            .ok()
+            .map(|config| config.autocrop_background.unwrap().0)
+    }
+
+    #[test]
+    fn config_file_merges_under_inline_keys() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(
+            root.path().join("d2.toml"),
+            "layout = \"elk\"\ninline = true\n",
+        )
+        .unwrap();
+
+        let table: toml::Table =
+            toml::from_str("config-file = \"d2.toml\"\ninline = false\n").unwrap();
+        let config = Config::from_table(table, root.path()).unwrap();
+
+        assert_eq!(config.layout.as_deref(), Some("elk"));
+        assert!(
+            !config.inline,
+            "book.toml keys should override the config file"
+        );
+    }
+
+    #[test_case("{caption}" => true; "default")]
+    #[test_case("Figure {section}-{index}: {caption} ({chapter})" => true; "all placeholders")]
+    #[test_case("Diagram {title}" => false; "unknown placeholder")]
+    #[test_case("Diagram {caption" => false; "unclosed placeholder")]
+    fn validate_alt_template(template: &str) -> bool {
+        Config {
+            alt_template: template.to_string(),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case("Draft {date} ({sha})" => true; "known placeholders")]
+    #[test_case("Draft {caption}" => false; "unknown placeholder")]
+    fn validate_watermark(watermark: &str) -> bool {
+        Config {
+            watermark: Some(watermark.to_string()),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case("d2/{lang}" => true; "language")]
+    #[test_case("d2/{chapter}" => false; "unknown placeholder")]
+    fn validate_output_dir(output_dir: &str) -> bool {
+        Config {
+            output_dir: PathBuf::from(output_dir),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case("0.7.1" => true; "release")]
+    #[test_case("v0.8" => true; "short with prefix")]
+    #[test_case("latest" => false; "not a version")]
+    fn validate_min_d2_version(version: &str) -> bool {
+        Config {
+            min_d2_version: Some(version.to_string()),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case(None => true; "without checkpoint")]
+    #[test_case(Some("target/d2-progress") => false; "with checkpoint")]
+    fn validate_hashed_filenames(checkpoint_file: Option<&str>) -> bool {
+        Config {
+            hashed_filenames: true,
+            checkpoint_file: checkpoint_file.map(PathBuf::from),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case(1 => true; "serial")]
+    #[test_case(32 => true; "above default cap")]
+    #[test_case(0 => false; "zero")]
+    fn validate_max_concurrency(max_concurrency: usize) -> bool {
+        Config {
+            max_concurrency: Some(max_concurrency),
+            ..Config::default()
+        }
+        .validate()
+        .is_ok()
+    }
+
+    #[test_case("inline = false" => None; "embedded without override")]
+    #[test_case("inline = false\nembedded-theme-id = \"3\"" => Some("3".into()); "embedded")]
+    #[test_case("inline = true\nembedded-theme-id = \"3\"" => None; "inline ignores embedded")]
+    #[test_case("inline = true\ninline-theme-id = \"4\"" => Some("4".into()); "inline")]
+    #[test_case("js-data-file = \"d2.js\"\ninline-theme-id = \"4\"" => Some("4".into()); "js data")]
+    fn mode_theme_id(input: &str) -> Option<String> {
+        let config: Config = toml::from_str(input).unwrap();
+        config.mode_theme_id().cloned()
+    }
+
+    #[test]
+    fn check_fonts() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("regular.ttf"), "").unwrap();
+        let fonts = super::Fonts {
+            regular: PathBuf::from("regular.ttf"),
+            italic: PathBuf::from("italic.ttf"),
+            bold: root.path().join("regular.ttf"),
+            semibold: None,
+        };
+        let mut config = Config {
+            fonts: Some(fonts.clone()),
+            ..Config::default()
+        };
+        let error = config.check_fonts(root.path()).unwrap_err().to_string();
+        assert!(error.contains("italic.ttf"), "{error}");
+        assert!(!error.contains("regular.ttf"), "{error}");
+
+        config.font_fallback = true;
+        config.check_fonts(root.path()).unwrap();
+        assert_eq!(config.fonts, None);
+
+        fs::write(root.path().join("italic.ttf"), "").unwrap();
+        config.fonts = Some(fonts.clone());
+        config.check_fonts(root.path()).unwrap();
+        assert_eq!(config.fonts, Some(fonts));
+    }
+
+    #[test]
+    fn check_fonts_ttf() {
+        let root = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            fonts: Some(super::Fonts {
+                regular: PathBuf::from("regular.otf"),
+                italic: PathBuf::from("italic.TTF"),
+                bold: PathBuf::from("bold"),
+                semibold: Some(PathBuf::from("semibold.woff")),
+            }),
+            // Not a missing file, so no fallback either
+            font_fallback: true,
+            ..Config::default()
+        };
+        let error = config.check_fonts(root.path()).unwrap_err().to_string();
+        assert!(
+            error.contains("regular.otf, bold, semibold.woff"),
+            "{error}"
+        );
+        assert!(!error.contains("italic.TTF"), "{error}");
+    }
+
+    #[test]
+    fn check_asset_root() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("assets")).unwrap();
+        let mut config = Config {
+            asset_root: Some(PathBuf::from("assets")),
+            ..Config::default()
+        };
+        config.check_asset_root(root.path()).unwrap();
+
+        config.asset_root = Some(PathBuf::from("icons"));
+        let error = config
+            .check_asset_root(root.path())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("icons"), "{error}");
+    }
+
+    #[test]
+    fn config_file_missing() {
+        let root = tempfile::tempdir().unwrap();
+        let table: toml::Table = toml::from_str("config-file = \"missing.toml\"").unwrap();
+        assert!(Config::from_table(table, root.path()).is_err());
+    }
 }
@@ -0,0 +1,71 @@
+//! Fetching diagram sources referenced by URL
+//!
+//! A code block written as ```` ```d2 url="https://example.com/arch.d2" ````
+//! is rendered from the fetched source instead of its own content. Network
+//! access is only compiled in with the `fetch` cargo feature, keeping default
+//! builds offline.
+
+#[cfg(feature = "fetch")]
+use std::time::Duration;
+
+/// Timeout for fetching a diagram source
+#[cfg(feature = "fetch")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches a diagram's source from a URL
+///
+/// # Errors
+/// Returns an error if the request fails, the server responds with an error
+/// status or the body isn't valid UTF-8.
+#[cfg(feature = "fetch")]
+pub fn fetch(url: &str) -> anyhow::Result<String> {
+    Ok(ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()
+        .map_err(Box::new)?
+        .into_string()?)
+}
+
+/// Fetches a diagram's source from a URL
+///
+/// # Errors
+/// Always returns an error, as this build can't access the network.
+#[cfg(not(feature = "fetch"))]
+pub fn fetch(url: &str) -> anyhow::Result<String> {
+    anyhow::bail!(
+        "can't fetch {url}: mdbook-d2-png was built without the `fetch` feature \
+         (reinstall with `cargo install mdbook-d2-png --features fetch`)"
+    )
+}
+
+#[cfg(all(test, feature = "fetch"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for status in ["200 OK", "404 Not Found"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                let body = "a -> b";
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+
+        assert_eq!(fetch(&format!("http://{address}/a.d2")).unwrap(), "a -> b");
+        assert!(fetch(&format!("http://{address}/missing.d2")).is_err());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Parsing of D2 code block info strings
+//!
+//! The first word of an info string is the block's language; any further
+//! words are bare tokens (e.g. `legend`) or `key=value` attributes, whose
+//! value may be wrapped in double quotes (e.g. `url="https://..."`). Quoted
+//! values may contain spaces (e.g. `alt="request flow"`).
+
+/// Splits an info string into words, keeping double-quoted spaces
+fn words(info: &str) -> impl Iterator<Item = &str> {
+    let mut in_quotes = false;
+    info.split(move |c: char| {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        c.is_whitespace() && !in_quotes
+    })
+    .filter(|word| !word.is_empty())
+}
+
+/// Returns the language of a code block's info string
+///
+/// # Arguments
+/// * `info` - The code block's info string
+pub fn language(info: &str) -> Option<&str> {
+    words(info).next()
+}
+
+/// Returns whether a code block's info string contains a bare token
+///
+/// # Arguments
+/// * `info` - The code block's info string
+/// * `token` - The token to look for
+pub fn has_token(info: &str, token: &str) -> bool {
+    words(info).skip(1).any(|word| word == token)
+}
+
+/// Returns the value of an attribute in a code block's info string, if set
+///
+/// Empty values are treated as unset.
+///
+/// # Arguments
+/// * `info` - The code block's info string
+/// * `key` - The attribute's name
+pub fn attribute<'i>(info: &'i str, key: &str) -> Option<&'i str> {
+    words(info)
+        .skip(1)
+        .find_map(|word| word.strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim_matches('"'))
+        .filter(|value| !value.is_empty())
+}
+
+/// Returns all `key=value` attributes of a code block's info string, in order
+///
+/// Values are unquoted as for [`attribute`]; bare tokens are skipped.
+///
+/// # Arguments
+/// * `info` - The code block's info string
+pub fn attributes(info: &str) -> impl Iterator<Item = (&str, &str)> {
+    words(info)
+        .skip(1)
+        .filter_map(|word| word.split_once('='))
+        .map(|(key, value)| (key, value.trim_matches('"')))
+}
+
+/// Returns the comma-separated values of an attribute, e.g. `tag=core,api`
+///
+/// # Arguments
+/// * `info` - The code block's info string
+/// * `key` - The attribute's name
+pub fn attribute_list<'i>(info: &'i str, key: &str) -> impl Iterator<Item = &'i str> {
+    attribute(info, key)
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute() {
+        assert_eq!(attribute("d2", "url"), None);
+        assert_eq!(
+            attribute(r#"d2 url="https://x.test/a.d2""#, "url"),
+            Some("https://x.test/a.d2")
+        );
+        assert_eq!(
+            attribute("d2 url=https://x.test/a.d2", "url"),
+            Some("https://x.test/a.d2")
+        );
+        assert_eq!(attribute(r#"d2 myurl="https://x.test/a.d2""#, "url"), None);
+        assert_eq!(attribute(r#"d2 urls="https://x.test/a.d2""#, "url"), None);
+        assert_eq!(attribute(r#"d2 url="""#, "url"), None);
+        assert_eq!(
+            attribute(r#"d2 alt="request flow" legend"#, "alt"),
+            Some("request flow")
+        );
+    }
+
+    #[test]
+    fn test_has_token() {
+        assert!(has_token("d2 legend", "legend"));
+        assert!(!has_token("legend", "legend"));
+        assert!(!has_token("d2 legends", "legend"));
+        assert!(!has_token(r#"d2 alt="a legend""#, "legend"));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let attributes: Vec<_> = attributes(r#"d2 legend pad=40 theme="200""#).collect();
+        assert_eq!(attributes, [("pad", "40"), ("theme", "200")]);
+    }
+
+    #[test]
+    fn test_attribute_list() {
+        assert_eq!(attribute_list("d2", "tag").count(), 0);
+        assert_eq!(
+            attribute_list("d2 tag=core", "tag").collect::<Vec<_>>(),
+            ["core"]
+        );
+        assert_eq!(
+            attribute_list(r#"d2 tag="core,api,""#, "tag").collect::<Vec<_>>(),
+            ["core", "api"]
+        );
+    }
+}
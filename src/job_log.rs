@@ -0,0 +1,78 @@
+//! Per-job log capture, so output from parallel renders can be replayed in order
+
+use std::cell::RefCell;
+
+use log::Level;
+
+/// Log messages captured while rendering a single diagram
+pub type JobLog = Vec<(Level, String)>;
+
+thread_local! {
+    /// Messages of the job running on this thread, if capture is active
+    static BUFFER: RefCell<Option<JobLog>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, capturing the messages it logs through [`log`] on this thread
+///
+/// # Arguments
+/// * `f` - The job to run
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, JobLog) {
+    BUFFER.with(|buffer| *buffer.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let messages = BUFFER
+        .with(|buffer| buffer.borrow_mut().take())
+        .unwrap_or_default();
+    (result, messages)
+}
+
+/// Logs a message, or buffers it if a job on this thread is being captured
+///
+/// # Arguments
+/// * `level` - The level of the message
+/// * `message` - The message to log
+pub fn log(level: Level, message: String) {
+    let uncaptured = BUFFER.with(|buffer| match buffer.borrow_mut().as_mut() {
+        Some(messages) => {
+            messages.push((level, message));
+            None
+        }
+        None => Some(message),
+    });
+    if let Some(message) = uncaptured {
+        log::log!(level, "{message}");
+    }
+}
+
+/// Emits the messages captured for a job
+///
+/// # Arguments
+/// * `messages` - The captured messages, in the order they were logged
+pub fn replay(messages: JobLog) {
+    for (level, message) in messages {
+        log::log!(level, "{message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_buffers_messages() {
+        let ((), messages) = capture(|| {
+            log(Level::Warn, "first".into());
+            log(Level::Info, "second".into());
+        });
+        assert_eq!(
+            messages,
+            vec![
+                (Level::Warn, "first".into()),
+                (Level::Info, "second".into())
+            ]
+        );
+
+        // Capture ends with the job
+        let ((), messages) = capture(|| {});
+        assert!(messages.is_empty());
+    }
+}
@@ -8,10 +8,13 @@
 )]
 #![warn(clippy::pedantic, clippy::nursery)]
 
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
-use log::error;
+use log::{error, info, warn, Level};
 use mdbook::book::{Book, Chapter, SectionNumber};
 use mdbook::errors::Error;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
@@ -21,30 +24,191 @@ use pulldown_cmark_to_cmark::cmark;
 use rayon::prelude::*;
 
 mod backend;
-use backend::{Backend, RenderContext};
+pub use backend::RenderContext;
+use backend::{Backend, RenderedImage};
+
+mod baseline;
+use baseline::BaselineDiagrams;
+
+mod cache;
+
+mod checkpoint;
+use checkpoint::Checkpoint;
 
 mod config;
+use config::{DedupScope, RenderErrorBehavior};
+
+mod fetch;
+
+mod info;
+
+mod job_log;
+use job_log::JobLog;
+
+mod manifest;
+
+mod numbering;
+use numbering::FigureNumbering;
+
+mod png;
+
+mod ramp;
+use ramp::ConcurrencyRamp;
+
+mod raster;
+
+mod svg;
+
+mod weight;
+use weight::WeightLimiter;
+
+mod renderer;
+pub use renderer::DiagramRenderer;
+use renderer::DIAGRAM_OPTIONS;
 
 /// The name of this preprocessor
 const PREPROCESSOR_NAME: &str = "d2-png";
 
-/// The code block language identifier for D2 diagrams
-const D2_CODE_BLOCK_LANG: &str = "d2";
+/// Info string token rendering the end of a code block as a separate legend
+const LEGEND_TOKEN: &str = "legend";
+
+/// Info string attribute referencing a diagram's source by URL
+const URL_ATTRIBUTE: &str = "url";
 
-/// Maximum number of concurrent D2 processes
+/// Info string attribute referencing a diagram's source file, relative to the chapter
+const FILE_ATTRIBUTE: &str = "file";
+
+/// Info string attribute naming a diagram's output file
+const STABLE_ID_ATTRIBUTE: &str = "stable_id";
+
+/// Info string attribute listing a diagram's tags, for `only-tags`
+const TAG_ATTRIBUTE: &str = "tag";
+
+/// Info string attribute setting a diagram's alt text
+const ALT_ATTRIBUTE: &str = "alt";
+
+/// Info string attribute setting the caption of a diagram's `<figure>`
+const CAPTION_ATTRIBUTE: &str = "caption";
+
+/// Info string attribute setting a diagram's display width in pixels
+const WIDTH_ATTRIBUTE: &str = "width";
+
+/// Info string attribute setting a diagram's display height in pixels
+const HEIGHT_ATTRIBUTE: &str = "height";
+
+/// Prefix of a leading D2 comment setting a diagram's alt text
+const ALT_COMMENT_PREFIX: &str = "# alt:";
+
+/// Info string token designating a chapter's Open Graph image
+const OG_IMAGE_TOKEN: &str = "og-image";
+
+/// Info string token opting a diagram into the render cache with `cache-mode = "opt-in"`
+const CACHE_TOKEN: &str = "cache";
+
+/// Maximum number of concurrent D2 processes, unless `max-concurrency` is set
 ///
 /// D2 is CPU-intensive, so we cap concurrent processes to prevent resource exhaustion.
 /// This value balances parallelism with system resource constraints.
 const MAX_CONCURRENT_D2_PROCESSES: usize = 8;
 
 /// [D2] diagram generator [`Preprocessor`] for [`MdBook`](https://rust-lang.github.io/mdBook/).
-#[derive(Default, Clone, Copy, Debug)]
-pub struct D2;
+///
+/// Diagrams are rendered with the D2 binary unless a custom
+/// [`DiagramRenderer`] is supplied with [`D2::with_renderer`].
+#[derive(Default, Clone, Debug)]
+pub struct D2 {
+    /// Custom rendering engine replacing the D2 binary
+    renderer: Option<Arc<dyn DiagramRenderer>>,
+}
+
+impl D2 {
+    /// Creates a preprocessor rendering diagrams with the D2 binary
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a preprocessor rendering diagrams with a custom engine
+    ///
+    /// # Arguments
+    /// * `renderer` - The engine turning diagram source into PNG bytes
+    #[must_use]
+    pub fn with_renderer(renderer: impl DiagramRenderer + 'static) -> Self {
+        Self {
+            renderer: Some(Arc::new(renderer)),
+        }
+    }
+}
+
+/// Removes diagram images an earlier build of a book generated but the latest didn't
+///
+/// Images of deleted, moved or renumbered diagrams otherwise pile up in the
+/// output directory. The files generated by the latest and earlier builds
+/// are read from the manifest written there; other files are left alone.
+///
+/// # Arguments
+/// * `root` - The book's root directory, containing `book.toml`
+/// * `dry_run` - Only list the stale images instead of removing them
+///
+/// # Returns
+/// The stale images, removed unless `dry_run` is set
+///
+/// # Errors
+/// Returns an error if the book's configuration can't be loaded, the book
+/// hasn't been built yet, its latest build left diagrams out, or an image
+/// can't be removed.
+pub fn clean(root: &Path, dry_run: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let book_config = mdbook::Config::from_disk(root.join("book.toml"))?;
+    Backend::from_book_config(&book_config, root, None)?.clean(dry_run)
+}
+
+/// Renders a single diagram with a book's configuration
+///
+/// Lets a diagram that fails inside a build be debugged on its own: the D2
+/// options, fonts, post-processing and so on come from the book's
+/// `book.toml`, but the render cache and failure dumps are disabled, so
+/// nothing is written to the book. D2 runs in the directory of the diagram's
+/// file, if any, so `@import`s resolve.
+///
+/// # Arguments
+/// * `root` - The book's root directory, containing `book.toml`
+/// * `file` - The diagram's file, or `None` if it was read from stdin
+/// * `content` - The D2 diagram content
+///
+/// # Returns
+/// The rendered image, or the error, along with the messages logged while
+/// rendering, such as D2's warnings
+pub fn render_diagram(
+    root: &Path,
+    file: Option<&Path>,
+    content: &str,
+) -> (anyhow::Result<Vec<u8>>, Vec<String>) {
+    let (result, log) = job_log::capture(|| {
+        let book_config = mdbook::Config::from_disk(root.join("book.toml"))?;
+        let mut config = Backend::load_config(&book_config, root)?;
+        config.cache = false;
+        config.dump_failures = false;
+        let backend = Backend::new(config, root, root.join(&book_config.book.src), None);
+        backend.preflight()?;
+        let path = match file {
+            Some(file) => std::path::absolute(file)?,
+            None => std::env::current_dir()?.join("stdin"),
+        };
+        let name = file.map_or_else(|| String::from("stdin"), |file| file.display().to_string());
+        backend.render_png(&RenderContext::new(&path, &name, None, 1), content)
+    });
+    let messages = log
+        .into_iter()
+        .map(|(level, message)| format!("{level}: {message}"))
+        .collect();
+    (result, messages)
+}
 
 /// A render job for a D2 diagram
 ///
 /// Contains all information needed to render a diagram in parallel.
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
 struct RenderJob {
     /// Path to the chapter file (for relative path calculation)
     chapter_path: PathBuf,
@@ -56,6 +220,89 @@ struct RenderJob {
     content: String,
     /// 1-based index of this diagram within its chapter
     diagram_index: usize,
+    /// 1-based index of this diagram within a split code block
+    sub_index: Option<usize>,
+    /// Whether this diagram is the legend of a `legend` code block
+    legend: bool,
+    /// Permanent identifier naming the diagram's output file, if any
+    stable_id: Option<String>,
+    /// Figure number within the configured numbering scope
+    figure: String,
+    /// Whether the diagram changed since the `changed-since` baseline
+    changed: bool,
+    /// Whether the diagram is its chapter's Open Graph image
+    og_image: bool,
+    /// Whether the diagram opted into the render cache
+    cache: bool,
+    /// Options set in the code block's info string, e.g. `pad=40`
+    options: Vec<(String, String)>,
+    /// Alt text from the info string or a leading comment, if any
+    alt: Option<String>,
+    /// Caption of the diagram's `<figure>`, if any
+    figcaption: Option<String>,
+    /// Display width and height in pixels set in the info string
+    size: (Option<u32>, Option<u32>),
+    /// Why the diagram's `url` or `file` source couldn't be resolved, if it
+    /// couldn't; the job then fails without rendering
+    source_error: Option<String>,
+    /// What to do if the diagram fails
+    on_error: RenderErrorBehavior,
+}
+
+impl RenderJob {
+    /// Returns the value of an option set in the code block's info string
+    fn option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the render context identifying this job's diagram
+    fn render_context(&self) -> RenderContext<'_> {
+        RenderContext::new(
+            &self.chapter_path,
+            &self.chapter_name,
+            self.section.as_ref(),
+            self.diagram_index,
+        )
+        .with_sub_index(self.sub_index)
+        .with_legend(self.legend)
+        .with_stable_id(self.stable_id.as_deref())
+        .with_figure(Some(&self.figure))
+        .with_changed(self.changed)
+        .with_og_image(self.og_image)
+        .with_cache(self.cache)
+        .with_options(&self.options)
+        .with_alt(self.alt.as_deref())
+        .with_figcaption(self.figcaption.as_deref())
+        .with_size(self.size.0, self.size.1)
+    }
+}
+
+/// Outcome of rendering a single diagram: its image events or an error message
+type RenderResult = Result<Vec<Event<'static>>, String>;
+
+/// Rendered diagrams by chapter index: each diagram's job index, diagram index and events
+type ChapterResults = std::collections::HashMap<usize, Vec<(usize, usize, Vec<Event<'static>>)>>;
+
+/// A finished render job
+#[derive(Debug)]
+struct RenderedJob {
+    /// Index of the chapter among all chapters, in book order
+    chapter_idx: usize,
+    /// Index of the job within its chapter
+    job_idx: usize,
+    /// Name of the chapter (for error messages)
+    chapter_name: String,
+    /// 1-based index of the diagram within its chapter
+    diagram_index: usize,
+    /// The rendered image events or an error message
+    result: RenderResult,
+    /// What to do if the diagram failed
+    on_error: RenderErrorBehavior,
+    /// Messages logged while rendering, replayed in book order
+    log: JobLog,
 }
 
 impl Preprocessor for D2 {
@@ -64,81 +311,58 @@ impl Preprocessor for D2 {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let backend = Arc::new(Backend::from_context(ctx));
+        let backend = Arc::new(Backend::from_book_config(
+            &ctx.config,
+            &ctx.root,
+            self.renderer.clone(),
+        )?);
+        backend.check_changed_since()?;
 
         // Pass 1: Collect all render jobs from all chapters
-        let mut chapter_jobs: Vec<(usize, Vec<RenderJob>)> = Vec::new();
-        let mut chapter_indices: Vec<usize> = Vec::new();
+        let all_jobs = collect_book_jobs(&mut book, &backend)?;
 
-        book.for_each_mut(|section| {
-            if let BookItem::Chapter(chapter) = section {
-                let jobs = collect_render_jobs(chapter);
-                if !jobs.is_empty() {
-                    chapter_indices.push(chapter_jobs.len());
-                    chapter_jobs.push((chapter_indices.len() - 1, jobs));
-                }
-            }
-        });
+        check_stable_ids(&all_jobs)?;
 
-        // Flatten all jobs for parallel processing
-        let all_jobs: Vec<(usize, usize, RenderJob)> = chapter_jobs
-            .into_iter()
-            .flat_map(|(chapter_idx, jobs)| {
-                jobs.into_iter()
-                    .enumerate()
-                    .map(move |(job_idx, job)| (chapter_idx, job_idx, job))
-            })
-            .collect();
+        if backend.warn_duplicates() && backend.log_enabled(Level::Warn) {
+            warn_duplicate_diagrams(&all_jobs);
+        }
 
-        // Pass 2: Render all diagrams in parallel with bounded concurrency
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_cpus().min(MAX_CONCURRENT_D2_PROCESSES))
-            .build()
-            .expect("Failed to create thread pool for D2 rendering");
-
-        let rendered_results: Vec<(usize, usize, Result<Vec<Event<'static>>, String>)> =
-            pool.install(|| {
-                all_jobs
-                    .into_par_iter()
-                    .map(|(chapter_idx, job_idx, job)| {
-                        let render_ctx = RenderContext::new(
-                            &job.chapter_path,
-                            &job.chapter_name,
-                            job.section.as_ref(),
-                            job.diagram_index,
+        if !all_jobs.is_empty() {
+            backend.check_output_dir()?;
+            let version = backend.preflight()?;
+            if backend.log_enabled(Level::Info) {
+                match version {
+                    Some(version) => {
+                        info!(
+                            "Rendering {} D2 diagram(s) with d2 {version}",
+                            all_jobs.len()
                         );
-
-                        let result = backend
-                            .render(&render_ctx, &job.content)
-                            .map_err(|e| e.to_string());
-
-                        (chapter_idx, job_idx, result)
-                    })
-                    .collect()
-            });
-
-        // Group results by chapter for stitching
-        let mut results_by_chapter: std::collections::HashMap<usize, Vec<(usize, Vec<Event<'static>>)>> =
-            std::collections::HashMap::new();
-
-        for (chapter_idx, job_idx, result) in rendered_results {
-            let events = match result {
-                Ok(events) => events,
-                Err(e) => {
-                    error!("Failed to render D2 diagram: {e}");
-                    Vec::new()
+                    }
+                    None => info!("Rendering {} D2 diagram(s)", all_jobs.len()),
                 }
-            };
-            results_by_chapter
-                .entry(chapter_idx)
-                .or_default()
-                .push((job_idx, events));
+            }
         }
 
-        // Sort results within each chapter by job index
-        for results in results_by_chapter.values_mut() {
-            results.sort_by_key(|(idx, _)| *idx);
+        // Pass 2: Render all diagrams in parallel with bounded concurrency
+        let checkpoint = backend.open_checkpoint()?;
+        let rendered_results = render_jobs(&backend, &all_jobs, checkpoint.as_ref())?;
+        backend.write_pending_files(checkpoint.as_ref())?;
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.finish()?;
         }
+        backend.write_js_data()?;
+        // Diagrams left out of the build may still use their images from earlier builds
+        let partial = !backend.only_tags().is_empty()
+            || backend
+                .max_diagrams()
+                .is_some_and(|max| all_jobs.len() > max)
+            || rendered_results
+                .iter()
+                .any(|rendered| rendered.result.is_err());
+        backend.write_manifest(partial)?;
+
+        // Group results by chapter for stitching
+        let mut results_by_chapter = group_by_chapter(rendered_results)?;
 
         // Pass 3: Stitch results back into chapters
         let mut chapter_counter = 0;
@@ -146,23 +370,29 @@ impl Preprocessor for D2 {
             if let BookItem::Chapter(chapter) = section {
                 let chapter_results = results_by_chapter.remove(&chapter_counter);
                 chapter_counter += 1;
+                let has_diagrams = chapter_results.is_some();
 
                 let rendered_events: Vec<Vec<Event<'static>>> = chapter_results
                     .map(|mut results| {
-                        results.sort_by_key(|(idx, _)| *idx);
-                        results.into_iter().map(|(_, events)| events).collect()
+                        results.sort_by_key(|(idx, _, _)| *idx);
+                        merge_block_results(results)
                     })
                     .unwrap_or_default();
 
                 let events = stitch_events(
                     chapter,
-                    Parser::new_ext(&chapter.content, Options::all()),
+                    &backend,
+                    Parser::new_ext(&chapter.content, Options::all()).into_offset_iter(),
                     rendered_events,
                 );
 
                 let mut buf = String::with_capacity(chapter.content.len() + 128);
                 cmark(events, &mut buf)
                     .expect("Failed to convert markdown events back to markdown");
+                if has_diagrams && backend.zoomable() {
+                    buf.push_str("\n\n");
+                    buf.push_str(backend::ZOOM_SNIPPET);
+                }
                 chapter.content = buf;
             }
         });
@@ -171,85 +401,883 @@ impl Preprocessor for D2 {
     }
 }
 
+/// Collects the render jobs of all chapters, in book order
+///
+/// Each job is returned with its chapter's position among all chapters, so
+/// results can be stitched back into the right chapter, and its index
+/// within that chapter.
+///
+/// # Errors
+/// Returns the first error collecting a chapter's jobs.
+fn collect_book_jobs(
+    book: &mut Book,
+    backend: &Backend,
+) -> Result<Vec<(usize, usize, RenderJob)>, Error> {
+    let mut chapter_jobs: Vec<(usize, Vec<RenderJob>)> = Vec::new();
+    let mut chapter_count = 0;
+    let mut collect_error = None;
+    let mut numbering = FigureNumbering::new(backend.numbering_scope());
+
+    book.for_each_mut(|section| match section {
+        BookItem::PartTitle(_) => numbering.start_part(),
+        BookItem::Chapter(chapter) => {
+            let chapter_idx = chapter_count;
+            chapter_count += 1;
+            match collect_render_jobs(chapter, backend) {
+                Ok(mut jobs) => {
+                    number_figures(&mut numbering, chapter, &mut jobs);
+                    mark_changed(backend, chapter, &mut jobs);
+                    if !jobs.is_empty() {
+                        chapter_jobs.push((chapter_idx, jobs));
+                    }
+                }
+                Err(e) => {
+                    collect_error.get_or_insert(e);
+                }
+            }
+        }
+        BookItem::Separator => {}
+    });
+    if let Some(e) = collect_error {
+        return Err(e);
+    }
+
+    // Flatten all jobs for parallel processing
+    Ok(chapter_jobs
+        .into_iter()
+        .flat_map(|(chapter_idx, jobs)| {
+            jobs.into_iter()
+                .enumerate()
+                .map(move |(job_idx, job)| (chapter_idx, job_idx, job))
+        })
+        .collect())
+}
+
+/// Renders all diagrams in parallel with bounded concurrency
+///
+/// Results are returned in job order, i.e. in book order. Messages logged
+/// while rendering are captured per job so they can be replayed in that
+/// order instead of interleaving.
+///
+/// # Errors
+/// Returns an error if a total timeout is configured and exceeded, after
+/// skipping the diagrams that hadn't started rendering by then.
+fn render_jobs(
+    backend: &Backend,
+    jobs: &[(usize, usize, RenderJob)],
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<RenderedJob>, Error> {
+    let threads = render_threads(backend);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("Failed to create thread pool for D2 rendering");
+    let ramp = backend
+        .concurrency_ramp()
+        .map(|duration| ConcurrencyRamp::new(duration, threads));
+    let limiter = backend.max_concurrent_nodes().map(WeightLimiter::new);
+    let layout_limiters: std::collections::HashMap<&str, WeightLimiter> = backend
+        .layout_concurrency()
+        .iter()
+        .map(|(layout, max)| (layout.as_str(), WeightLimiter::new(*max)))
+        .collect();
+    let deadline = backend
+        .total_timeout()
+        .map(|timeout| Instant::now() + timeout);
+    let skipped = AtomicUsize::new(0);
+    let budget = backend.max_diagrams().unwrap_or(usize::MAX);
+    let over_budget = jobs.len().saturating_sub(budget);
+
+    let originals = dedup_originals(jobs, backend.dedup_scope());
+    let mut shared = vec![false; jobs.len()];
+    for original in originals.iter().flatten() {
+        shared[*original] = true;
+    }
+
+    let (mut results, images): (Vec<RenderedJob>, Vec<Option<RenderedImage>>) =
+        pool.install(|| {
+            jobs.par_iter()
+                .enumerate()
+                .map(|(index, (chapter_idx, job_idx, job))| {
+                    let (result, log) = if index >= budget {
+                        (Ok((backend::skipped_diagram_events(), None)), JobLog::new())
+                    } else if originals[index].is_some() {
+                        // Filled in from the original's image below
+                        (Ok((Vec::new(), None)), JobLog::new())
+                    } else if let Some(e) = &job.source_error {
+                        (Err(e.clone()), JobLog::new())
+                    } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        skipped.fetch_add(1, Ordering::Relaxed);
+                        (Ok((Vec::new(), None)), JobLog::new())
+                    } else {
+                        let _layout = layout_limiters
+                            .get(backend.effective_layout(&job.content, job.option("layout")))
+                            .map(|limiter| limiter.acquire(1));
+                        let _weight = limiter
+                            .as_ref()
+                            .map(|limiter| limiter.acquire(Backend::diagram_weight(&job.content)));
+                        let _permit = ramp.as_ref().map(ConcurrencyRamp::acquire);
+                        job_log::capture(|| {
+                            backend
+                                .render(&job.render_context(), &job.content, checkpoint)
+                                .map(|(events, image)| (events, shared[index].then_some(image)))
+                                .map_err(|e| e.to_string())
+                        })
+                    };
+
+                    let (result, image) = match result {
+                        Ok((events, image)) => (Ok(events), image),
+                        Err(e) => (Err(e), None),
+                    };
+                    let rendered = RenderedJob {
+                        chapter_idx: *chapter_idx,
+                        job_idx: *job_idx,
+                        chapter_name: job.chapter_name.clone(),
+                        diagram_index: job.diagram_index,
+                        result,
+                        on_error: job.on_error,
+                        log,
+                    };
+                    (rendered, image)
+                })
+                .unzip()
+        });
+
+    // Duplicates show their original's image, or share its failure
+    for (index, original) in originals.into_iter().enumerate() {
+        let Some(original) = original.filter(|_| index < budget) else {
+            continue;
+        };
+        let (_, _, job) = &jobs[index];
+        results[index].result = match (&results[original].result, &images[original]) {
+            (Ok(_), Some(image)) => Ok(backend.render_duplicate(
+                &job.render_context(),
+                &jobs[original].2.render_context(),
+                &job.content,
+                image,
+            )),
+            (Err(e), _) => Err(e.clone()),
+            // The original was skipped past the total timeout
+            (Ok(_), None) => Ok(Vec::new()),
+        };
+    }
+
+    if over_budget > 0 && backend.log_enabled(Level::Warn) {
+        warn!("{over_budget} D2 diagram(s) skipped for fast preview (max-diagrams = {budget})");
+    }
+
+    let skipped = skipped.into_inner();
+    if let (Some(timeout), 1..) = (backend.total_timeout(), skipped) {
+        return Err(Error::msg(format!(
+            "Rendering D2 diagrams exceeded the total timeout of {} seconds; \
+             {skipped} diagram(s) were not rendered",
+            timeout.as_secs()
+        )));
+    }
+    Ok(results)
+}
+
+/// Groups rendered results by chapter, sorted by job index within each chapter
+///
+/// Failed diagrams are logged and replaced according to their `on-error`
+/// behavior.
+///
+/// # Errors
+/// Returns an error listing the diagrams that failed with `on-error = "fail"`.
+fn group_by_chapter(rendered_results: Vec<RenderedJob>) -> Result<ChapterResults, Error> {
+    let mut results_by_chapter = ChapterResults::new();
+
+    let mut failures = Vec::new();
+    for rendered in rendered_results {
+        job_log::replay(rendered.log);
+        let events = match rendered.result {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to render D2 diagram: {e}");
+                if rendered.on_error == RenderErrorBehavior::Fail {
+                    failures.push(format!(
+                        "{} (#{})",
+                        rendered.chapter_name, rendered.diagram_index
+                    ));
+                }
+                match rendered.on_error {
+                    RenderErrorBehavior::Embed => backend::error_events(&e),
+                    RenderErrorBehavior::Skip | RenderErrorBehavior::Fail => Vec::new(),
+                }
+            }
+        };
+        results_by_chapter
+            .entry(rendered.chapter_idx)
+            .or_default()
+            .push((rendered.job_idx, rendered.diagram_index, events));
+    }
+
+    if !failures.is_empty() {
+        return Err(Error::msg(format!(
+            "{} D2 diagram(s) failed to render: {}",
+            failures.len(),
+            failures.join(", ")
+        )));
+    }
+
+    // Sort results within each chapter by job index
+    for results in results_by_chapter.values_mut() {
+        results.sort_by_key(|(idx, _, _)| *idx);
+    }
+    Ok(results_by_chapter)
+}
+
+/// Maps each job to the earlier identical job whose image it reuses, if any
+///
+/// Jobs are identical when their content and options are, within the same
+/// chapter or anywhere in the book depending on the scope. Diagrams importing
+/// files or using local icons must also be in the same directory, as those
+/// paths resolve against it. Diagrams with a `stable_id` keep their own file
+/// and, like diagrams whose source couldn't be resolved, are never
+/// deduplicated.
+fn dedup_originals(jobs: &[(usize, usize, RenderJob)], scope: DedupScope) -> Vec<Option<usize>> {
+    let mut firsts = std::collections::HashMap::new();
+    jobs.iter()
+        .enumerate()
+        .map(|(index, (chapter_idx, _, job))| {
+            let chapter = match scope {
+                DedupScope::None => return None,
+                DedupScope::Chapter => Some(*chapter_idx),
+                DedupScope::Book => None,
+            };
+            if job.stable_id.is_some() || job.source_error.is_some() {
+                return None;
+            }
+            let dir = backend::uses_local_files(&job.content)
+                .then(|| job.chapter_path.parent())
+                .flatten();
+            let first = *firsts
+                .entry((chapter, dir, job.content.as_str(), job.options.as_slice()))
+                .or_insert(index);
+            (first != index).then_some(first)
+        })
+        .collect()
+}
+
+/// Returns the number of diagrams rendered at once
+fn render_threads(backend: &Backend) -> usize {
+    if backend.sequential() {
+        1
+    } else {
+        backend
+            .max_concurrency()
+            .unwrap_or_else(|| num_cpus().min(MAX_CONCURRENT_D2_PROCESSES))
+    }
+}
+
 /// Returns the number of available CPUs
 fn num_cpus() -> usize {
-    std::thread::available_parallelism()
-        .map(std::num::NonZero::get)
-        .unwrap_or(1)
+    std::thread::available_parallelism().map_or(1, std::num::NonZero::get)
+}
+
+/// Merges the rendered events of split code blocks
+///
+/// Takes a chapter's results sorted by job index and concatenates the events
+/// of jobs sharing a diagram index, yielding one entry per code block.
+fn merge_block_results(
+    results: Vec<(usize, usize, Vec<Event<'static>>)>,
+) -> Vec<Vec<Event<'static>>> {
+    let mut blocks: Vec<(usize, Vec<Event<'static>>)> = Vec::new();
+    for (_, diagram_index, events) in results {
+        match blocks.last_mut() {
+            Some((last_index, block)) if *last_index == diagram_index => block.extend(events),
+            _ => blocks.push((diagram_index, events)),
+        }
+    }
+    blocks.into_iter().map(|(_, events)| events).collect()
+}
+
+/// Groups render jobs whose diagram content is byte-identical
+///
+/// Returns only groups with more than one job, in order of first appearance.
+fn duplicate_diagrams(jobs: &[(usize, usize, RenderJob)]) -> Vec<Vec<&RenderJob>> {
+    let mut group_indices: std::collections::HashMap<&str, usize> =
+        std::collections::HashMap::new();
+    let mut groups: Vec<Vec<&RenderJob>> = Vec::new();
+    for (_, _, job) in jobs {
+        let index = *group_indices.entry(&job.content).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[index].push(job);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// Checks that no two code blocks share a `stable_id`
+///
+/// # Errors
+/// Returns an error naming both diagrams if a `stable_id` is used twice, as
+/// they would overwrite each other's output file.
+fn check_stable_ids(jobs: &[(usize, usize, RenderJob)]) -> Result<(), Error> {
+    let mut blocks: std::collections::HashMap<&str, (usize, &RenderJob)> =
+        std::collections::HashMap::new();
+    for (chapter_idx, _, job) in jobs {
+        let Some(stable_id) = job.stable_id.as_deref() else {
+            continue;
+        };
+        let (first_chapter_idx, first) = *blocks.entry(stable_id).or_insert((*chapter_idx, job));
+        if (first_chapter_idx, first.diagram_index) != (*chapter_idx, job.diagram_index) {
+            return Err(Error::msg(format!(
+                "D2 diagrams {} (#{}) and {} (#{}) share the stable_id `{stable_id}`",
+                first.chapter_name, first.diagram_index, job.chapter_name, job.diagram_index
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether a `stable_id` is safe to use as a file name
+fn is_valid_stable_id(stable_id: &str) -> bool {
+    !stable_id.starts_with('.')
+        && stable_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Logs a warning for each set of byte-identical diagrams
+fn warn_duplicate_diagrams(jobs: &[(usize, usize, RenderJob)]) {
+    for duplicates in duplicate_diagrams(jobs) {
+        let locations: Vec<String> = duplicates
+            .iter()
+            .map(|job| format!("{} (#{})", job.chapter_name, job.diagram_index))
+            .collect();
+        warn!(
+            "Identical D2 diagram appears {} times, possibly a copy-paste error: {}",
+            duplicates.len(),
+            locations.join(", ")
+        );
+    }
+}
+
+/// Assigns figure numbers to a chapter's render jobs
+///
+/// # Arguments
+/// * `numbering` - The book's figure numbering, advanced past the chapter
+/// * `chapter` - The chapter the jobs were collected from
+/// * `jobs` - The chapter's render jobs
+fn number_figures(numbering: &mut FigureNumbering, chapter: &Chapter, jobs: &mut [RenderJob]) {
+    let blocks = jobs.iter().map(|job| job.diagram_index).max().unwrap_or(0);
+    let figures = numbering.chapter(chapter.number.as_ref(), blocks);
+    for job in jobs {
+        job.figure = figures.figure(job.diagram_index, job.sub_index);
+    }
+}
+
+/// Marks a chapter's diagrams that changed since the `changed-since` baseline
+///
+/// A diagram counts as changed unless its source, ignoring surrounding
+/// whitespace, equals one of the diagrams in the D2 code blocks of the
+/// chapter file's baseline version. Baseline blocks are split into diagrams
+/// and legends like the current ones first.
+///
+/// # Arguments
+/// * `backend` - The backend holding the baseline configuration
+/// * `chapter` - The chapter the jobs were collected from
+/// * `jobs` - The chapter's render jobs
+fn mark_changed(backend: &Backend, chapter: &Chapter, jobs: &mut [RenderJob]) {
+    let Some(baseline) = chapter
+        .source_path
+        .as_deref()
+        .and_then(|path| backend.baseline_file(path))
+    else {
+        return;
+    };
+    let diagrams = BaselineDiagrams::new(d2_block_contents(backend, &baseline).iter().flat_map(
+        |block| {
+            // Whether a baseline block was split or tagged `legend` isn't known, so try each
+            let (diagrams, legend) = backend.split_legend(block);
+            backend
+                .split_diagrams(block)
+                .into_iter()
+                .chain(diagrams)
+                .chain(legend)
+                .chain(std::iter::once(block.clone()))
+        },
+    ));
+    for job in jobs {
+        job.changed = !diagrams.contains(&job.content);
+    }
+}
+
+/// Returns the contents of the D2 code blocks in a markdown document
+fn d2_block_contents(backend: &Backend, markdown: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+    for event in Parser::new_ext(markdown, Options::all()) {
+        match &event {
+            Event::Text(text) => {
+                if let Some(block) = &mut current {
+                    block.push_str(text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ if d2_block_info(backend, &event).is_some() => current = Some(String::new()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// Options of a D2 code block, set in its info string
+#[derive(Debug, Default)]
+struct BlockOptions {
+    /// URL the diagram source is fetched from, replacing the block's content
+    url: Option<String>,
+    /// File the diagram source is read from, relative to the chapter
+    file: Option<String>,
+    /// Whether the block is tagged `legend`
+    legend: bool,
+    /// File name the diagram is written to, instead of its section number
+    stable_id: Option<String>,
+    /// Whether the block is tagged `og-image`
+    og_image: bool,
+    /// Whether the block is tagged `cache`
+    cache: bool,
+    /// Per-diagram options overriding the configuration, e.g. `pad=40`
+    options: Vec<(String, String)>,
+    /// The diagram's alt text, if set
+    alt: Option<String>,
+    /// Caption wrapping the diagram in a `<figure>`, if set
+    figcaption: Option<String>,
+    /// Display width and height in pixels, if set
+    size: (Option<u32>, Option<u32>),
+}
+
+impl BlockOptions {
+    /// Parses the options from a code block's info string
+    ///
+    /// Attributes that are neither handled by the preprocessor nor a known
+    /// per-diagram option are ignored with a warning.
+    ///
+    /// # Errors
+    /// Returns an error if the block's `stable_id` isn't a safe file name, or
+    /// its `width` or `height` isn't a whole number.
+    fn parse(
+        info: &str,
+        chapter_name: &str,
+        diagram_index: usize,
+        backend: &Backend,
+    ) -> Result<Self, Error> {
+        let stable_id = info::attribute(info, STABLE_ID_ATTRIBUTE).map(str::to_owned);
+        if let Some(stable_id) = stable_id.as_deref().filter(|id| !is_valid_stable_id(id)) {
+            return Err(Error::msg(format!(
+                "Invalid stable_id `{stable_id}` for D2 diagram ({chapter_name}, #{diagram_index}): \
+                 use only letters, digits, `-`, `_` and `.`"
+            )));
+        }
+        let mut options = Vec::new();
+        for (key, value) in info::attributes(info) {
+            if DIAGRAM_OPTIONS.contains(&key) {
+                options.push((key.to_string(), value.to_string()));
+            } else if ![
+                URL_ATTRIBUTE,
+                FILE_ATTRIBUTE,
+                STABLE_ID_ATTRIBUTE,
+                TAG_ATTRIBUTE,
+                ALT_ATTRIBUTE,
+                CAPTION_ATTRIBUTE,
+                WIDTH_ATTRIBUTE,
+                HEIGHT_ATTRIBUTE,
+            ]
+            .contains(&key)
+                && backend.log_enabled(Level::Warn)
+            {
+                warn!(
+                    "Ignoring unknown option `{key}` of D2 diagram ({chapter_name}, #{diagram_index}); \
+                     known options are {}",
+                    DIAGRAM_OPTIONS.join(", ")
+                );
+            }
+        }
+        Ok(Self {
+            url: info::attribute(info, URL_ATTRIBUTE).map(str::to_owned),
+            file: info::attribute(info, FILE_ATTRIBUTE).map(str::to_owned),
+            legend: info::has_token(info, LEGEND_TOKEN),
+            stable_id,
+            og_image: info::has_token(info, OG_IMAGE_TOKEN),
+            cache: info::has_token(info, CACHE_TOKEN),
+            options,
+            alt: info::attribute(info, ALT_ATTRIBUTE).map(str::to_owned),
+            figcaption: info::attribute(info, CAPTION_ATTRIBUTE).map(str::to_owned),
+            size: (
+                pixels(info, WIDTH_ATTRIBUTE, chapter_name, diagram_index)?,
+                pixels(info, HEIGHT_ATTRIBUTE, chapter_name, diagram_index)?,
+            ),
+        })
+    }
+}
+
+/// Parses a size attribute of a code block's info string, in pixels
+///
+/// # Errors
+/// Returns an error if the attribute isn't a whole number.
+fn pixels(
+    info: &str,
+    key: &str,
+    chapter_name: &str,
+    diagram_index: usize,
+) -> Result<Option<u32>, Error> {
+    info::attribute(info, key)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                Error::msg(format!(
+                    "Invalid {key} `{value}` for D2 diagram ({chapter_name}, #{diagram_index}): \
+                     use a whole number of pixels"
+                ))
+            })
+        })
+        .transpose()
+}
+
+/// Creates the render jobs of a D2 code block
+///
+/// # Arguments
+/// * `chapter` - The chapter holding the block
+/// * `source_path` - The chapter file's path, relative to the source directory
+/// * `content` - The diagram source
+/// * `diagram_index` - The block's index in the chapter
+/// * `block` - The block's options
+/// * `failure` - Why the block's `url` or `file` source couldn't be resolved,
+///   with what to do about it, if it couldn't
+/// * `backend` - The backend splitting the source
+fn block_jobs(
+    chapter: &Chapter,
+    source_path: &Path,
+    content: &str,
+    diagram_index: usize,
+    block: &BlockOptions,
+    failure: Option<(String, RenderErrorBehavior)>,
+    backend: &Backend,
+) -> Vec<RenderJob> {
+    let (source_error, on_error) = failure.map_or_else(
+        || (None, backend.on_error()),
+        |(e, on_error)| (Some(e), on_error),
+    );
+    let (diagrams, legend) = if source_error.is_some() {
+        // A diagram whose source couldn't be resolved fails as a whole
+        (vec![content.to_string()], None)
+    } else if block.legend {
+        backend.split_legend(content)
+    } else {
+        (backend.split_diagrams(content), None)
+    };
+    let is_split = diagrams.len() + usize::from(legend.is_some()) > 1;
+    let parts = diagrams
+        .into_iter()
+        .map(|content| (content, false))
+        .chain(legend.map(|content| (content, true)));
+    parts
+        .enumerate()
+        .map(|(i, (content, legend))| {
+            let alt = block.alt.clone().or_else(|| alt_comment(&content));
+            RenderJob {
+                chapter_path: source_path.to_path_buf(),
+                chapter_name: chapter.name.clone(),
+                section: chapter.number.clone(),
+                content,
+                diagram_index,
+                sub_index: is_split.then_some(i + 1),
+                legend,
+                stable_id: block.stable_id.clone(),
+                figure: String::new(),
+                changed: false,
+                og_image: false,
+                cache: block.cache,
+                options: block.options.clone(),
+                alt,
+                // A legend belongs to the figure of its diagram
+                figcaption: block.figcaption.clone().filter(|_| !legend),
+                size: block.size,
+                source_error: source_error.clone(),
+                on_error,
+            }
+        })
+        .collect()
 }
 
 /// Collects all D2 render jobs from a chapter
 ///
 /// Scans through markdown events to find D2 code blocks and creates render jobs for each.
-fn collect_render_jobs(chapter: &Chapter) -> Vec<RenderJob> {
+/// Code blocks split into several diagrams produce one job per diagram, and
+/// blocks tagged `legend` an extra job for their legend. Blocks with a `url`
+/// or `file` attribute are rendered from the fetched or read source instead
+/// of their content; if that source can't be fetched or read, the diagram's
+/// job fails like a diagram that fails to render, following `on-error` (or
+/// `on-missing-file` for a `file` that can't be read). The
+/// first block tagged `og-image` becomes the chapter's Open Graph image.
+///
+/// # Errors
+/// Returns an error if a code block's options are invalid, or the chapter
+/// holds more diagrams than the configured per-chapter maximum.
+fn collect_render_jobs(chapter: &Chapter, backend: &Backend) -> Result<Vec<RenderJob>, Error> {
     let source_path = chapter
         .source_path
         .as_ref()
         .expect("Chapter source path should always be set by mdBook");
 
-    let events = Parser::new_ext(&chapter.content, Options::all());
+    let events = Parser::new_ext(&chapter.content, Options::all()).into_offset_iter();
 
     let mut jobs = Vec::new();
     let mut in_block = false;
+    let mut in_table_cell = false;
     let mut diagram_content = String::new();
+    let mut block = BlockOptions::default();
+    let mut og_image_index = None;
     let mut diagram_index = 0usize;
 
-    for event in events {
-        if is_d2_block_start(&event) {
+    for (event, range) in events {
+        if let Some(info) = d2_block_info(backend, &event).filter(|info| is_selected(backend, info))
+        {
             in_block = true;
             diagram_content.clear();
             diagram_index += 1;
+            block = BlockOptions::parse(info, &chapter.name, diagram_index, backend)?;
+            if block.og_image {
+                og_image_index.get_or_insert(diagram_index);
+            }
         } else if in_block {
             if let Event::Text(content) = &event {
                 diagram_content.push_str(content);
             } else if matches!(event, Event::End(TagEnd::CodeBlock)) {
                 in_block = false;
-                jobs.push(RenderJob {
-                    chapter_path: source_path.clone(),
-                    chapter_name: chapter.name.clone(),
-                    section: chapter.number.clone(),
-                    content: std::mem::take(&mut diagram_content),
+                // A `file` that can't be read has its own behavior; a `url` takes precedence
+                let on_error = if block.url.is_none() && block.file.is_some() {
+                    backend.on_missing_file()
+                } else {
+                    backend.on_error()
+                };
+                let (external, failure) = match external_source(
+                    &mut block,
+                    source_path,
+                    &chapter.name,
+                    diagram_index,
+                    backend,
+                ) {
+                    Ok(external) => (external, None),
+                    Err(e) => (None, Some((e.to_string(), on_error))),
+                };
+                let content = external.as_deref().unwrap_or(&diagram_content);
+                jobs.extend(block_jobs(
+                    chapter,
+                    source_path,
+                    content,
                     diagram_index,
-                });
+                    &block,
+                    failure,
+                    backend,
+                ));
+            }
+        } else {
+            track_table_cell(&event, &mut in_table_cell);
+            if let Some(content) = (in_table_cell && is_selected(backend, ""))
+                .then(|| table_cell_diagram(backend, &event, &chapter.content[range]))
+                .flatten()
+            {
+                diagram_index += 1;
+                let block = BlockOptions::default();
+                jobs.extend(block_jobs(
+                    chapter,
+                    source_path,
+                    content,
+                    diagram_index,
+                    &block,
+                    None,
+                    backend,
+                ));
             }
         }
     }
 
-    jobs
+    // Only the first image of the first `og-image` block is the chapter's
+    if let Some(job) =
+        og_image_index.and_then(|index| jobs.iter_mut().find(|job| job.diagram_index == index))
+    {
+        job.og_image = true;
+    }
+
+    if let Some(max) = backend.max_diagrams_per_chapter() {
+        if jobs.len() > max {
+            return Err(Error::msg(format!(
+                "Chapter '{}' contains {} D2 diagrams, more than max-diagrams-per-chapter ({max}). \
+                 Is it generated by a misbehaving tool?",
+                chapter.name,
+                jobs.len()
+            )));
+        }
+    }
+    Ok(jobs)
 }
 
-/// Checks if an event marks the start of a D2 code block
-fn is_d2_block_start(event: &Event) -> bool {
-    matches!(
-        event,
-        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))) if lang.as_ref() == D2_CODE_BLOCK_LANG
-    )
+/// Returns a diagram's source referenced by its `url` or `file` attribute, if any
+///
+/// A `url` takes precedence over a `file`.
+///
+/// # Arguments
+/// * `block` - The code block's options, whose reference is taken
+/// * `chapter_path` - The chapter file's path, relative to the source directory
+/// * `chapter_name` - The chapter's name, for error messages
+/// * `diagram_index` - The diagram's index in the chapter, for error messages
+/// * `backend` - The backend knowing the source directory
+///
+/// # Errors
+/// Returns an error if the source can't be fetched or read.
+fn external_source(
+    block: &mut BlockOptions,
+    chapter_path: &Path,
+    chapter_name: &str,
+    diagram_index: usize,
+    backend: &Backend,
+) -> Result<Option<String>, Error> {
+    if let Some(url) = block.url.take() {
+        return fetch::fetch(&url).map(Some).map_err(|e| {
+            Error::msg(format!(
+                "Failed to fetch D2 diagram ({chapter_name}, #{diagram_index}) from {url}: {e:#}"
+            ))
+        });
+    }
+    block
+        .file
+        .take()
+        .map(|file| {
+            backend.read_diagram_file(chapter_path, &file).map_err(|e| {
+                Error::msg(format!(
+                    "Failed to read D2 diagram ({chapter_name}, #{diagram_index}) from {file}: {e:#}"
+                ))
+            })
+        })
+        .transpose()
+}
+
+/// Updates the "inside a table cell" state from a markdown event
+fn track_table_cell(event: &Event, in_table_cell: &mut bool) {
+    match event {
+        Event::Start(Tag::TableCell) => *in_table_cell = true,
+        Event::End(TagEnd::TableCell) => *in_table_cell = false,
+        _ => {}
+    }
+}
+
+/// Returns the alt text set by a diagram's leading `# alt: ...` comment, if any
+///
+/// Only comments before the first declaration are considered.
+fn alt_comment(content: &str) -> Option<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .find_map(|line| line.strip_prefix(ALT_COMMENT_PREFIX))
+        .map(str::trim)
+        .filter(|alt| !alt.is_empty())
+        .map(str::to_owned)
+}
+
+/// Returns the source of a D2 diagram written as a code span in a table cell
+///
+/// Fenced code blocks can't appear inside table cells, so a diagram there is
+/// written as a code span opened with (at least) three backticks and a D2
+/// language tag, e.g. ```` ```d2 x -> y; y -> z``` ````. Ordinary code spans
+/// are left alone.
+///
+/// # Arguments
+/// * `backend` - The backend knowing the D2 language tags
+/// * `event` - A markdown event inside a table cell
+/// * `raw` - The markdown source the event was parsed from
+fn table_cell_diagram<'e>(backend: &Backend, event: &'e Event, raw: &str) -> Option<&'e str> {
+    let Event::Code(code) = event else {
+        return None;
+    };
+    if !raw.starts_with("```") {
+        return None;
+    }
+    code.split_once(char::is_whitespace)
+        .filter(|(language, _)| backend.is_d2_language(language))
+        .map(|(_, content)| content.trim())
+}
+
+/// Removes the paragraph wrapping rendered image events
+///
+/// Table cells only hold inline content, so diagrams rendered there must not
+/// open a paragraph.
+fn unwrap_paragraph(mut events: Vec<Event<'static>>) -> Vec<Event<'static>> {
+    if matches!(events.first(), Some(Event::Start(Tag::Paragraph)))
+        && matches!(events.last(), Some(Event::End(TagEnd::Paragraph)))
+    {
+        events.pop();
+        events.remove(0);
+    }
+    events
+}
+
+/// Returns whether a D2 code block is selected for rendering by `only-tags`
+///
+/// Diagrams in table cells have no info string and are passed an empty one,
+/// so they are rendered only when every diagram is.
+///
+/// # Arguments
+/// * `backend` - The backend holding the selected tags
+/// * `info` - The code block's info string
+fn is_selected(backend: &Backend, info: &str) -> bool {
+    let only_tags = backend.only_tags();
+    only_tags.is_empty()
+        || info::attribute_list(info, TAG_ATTRIBUTE)
+            .any(|tag| only_tags.iter().any(|only| only == tag))
+}
+
+/// Returns the info string of a D2 code block's start event
+///
+/// The block's language is the first word of its info string, which must be
+/// `code-block-lang` or one of `code-block-aliases`; any further words are
+/// attributes such as `url="..."` or tokens such as `legend`
+/// (see [`info`]).
+fn d2_block_info<'e>(backend: &Backend, event: &'e Event) -> Option<&'e str> {
+    match event {
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))
+            if info::language(info).is_some_and(|language| backend.is_d2_language(language)) =>
+        {
+            Some(info)
+        }
+        _ => None,
+    }
 }
 
 /// Stitches pre-rendered diagram events back into the markdown event stream
 ///
-/// Replaces D2 code blocks with their pre-rendered image events in order.
+/// Replaces D2 code blocks (and D2 code spans in table cells) with their
+/// pre-rendered image events in order; blocks not selected by `only-tags` are
+/// left as code blocks.
+/// All other events, including raw HTML such as `<details>` wrappers, pass
+/// through untouched, so an image stays inside the HTML element that
+/// surrounded its code block.
 fn stitch_events<'a>(
-    _chapter: &'a Chapter,
-    events: impl Iterator<Item = Event<'a>> + 'a,
+    chapter: &'a Chapter,
+    backend: &Backend,
+    events: impl Iterator<Item = (Event<'a>, Range<usize>)> + 'a,
     mut rendered_events: Vec<Vec<Event<'static>>>,
 ) -> impl Iterator<Item = Event<'a>> + 'a {
     // Reverse so we can pop from the back (more efficient than removing from front)
     rendered_events.reverse();
 
     let mut in_block = false;
+    let mut in_table_cell = false;
     let mut pending_events: Option<Vec<Event<'static>>> = None;
 
     // Use a closure to process events with state
     let mut result_events: Vec<Event<'a>> = Vec::new();
 
-    for event in events {
+    for (event, range) in events {
         // First, emit any pending events from a previous diagram
         if let Some(events) = pending_events.take() {
             result_events.extend(events);
         }
 
-        if is_d2_block_start(&event) {
+        if d2_block_info(backend, &event).is_some_and(|info| is_selected(backend, info)) {
             in_block = true;
             // Skip the start event
         } else if in_block {
@@ -263,7 +1291,18 @@ fn stitch_events<'a>(
                 }
             }
         } else {
-            result_events.push(event);
+            track_table_cell(&event, &mut in_table_cell);
+            if in_table_cell
+                && is_selected(backend, "")
+                && table_cell_diagram(backend, &event, &chapter.content[range]).is_some()
+            {
+                // Diagrams in table cells are emitted in place, without a paragraph
+                if let Some(events) = rendered_events.pop() {
+                    result_events.extend(unwrap_paragraph(events));
+                }
+            } else {
+                result_events.push(event);
+            }
         }
     }
 
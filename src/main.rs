@@ -1,4 +1,6 @@
-use std::{io, process};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{fs, io, process};
 
 use clap::Parser;
 use log::{error, warn};
@@ -51,16 +53,50 @@ pub enum Command {
         #[clap(help = "Renderer name (e.g. html)")]
         renderer: String,
     },
+    #[clap(
+        about = "Render a single diagram with the book's configuration",
+        long_about = "Renders a .d2 file (or stdin) with the options in book.toml and writes the image to a file or stdout, printing D2's messages. The render cache and failure dumps are disabled, so nothing is written to the book and a diagram failing inside a build can be debugged on its own."
+    )]
+    Render {
+        #[clap(help = "D2 file to render; reads stdin if omitted")]
+        file: Option<PathBuf>,
+        #[clap(
+            long,
+            default_value = ".",
+            help = "Book root directory, containing book.toml"
+        )]
+        dir: PathBuf,
+        #[clap(short, long, help = "Image file to write; writes to stdout if omitted")]
+        output: Option<PathBuf>,
+    },
+    #[clap(
+        about = "Remove stale diagram images",
+        long_about = "Removes images in the output directory that the latest build didn't generate, e.g. those of deleted diagrams. The generated files are read from the manifest.json the build wrote to the output directory."
+    )]
+    Clean {
+        #[clap(
+            long,
+            default_value = ".",
+            help = "Book root directory, containing book.toml"
+        )]
+        dir: PathBuf,
+        #[clap(long, help = "List the stale images without removing them")]
+        dry_run: bool,
+    },
 }
 
 fn main() {
     let args = Args::parse();
 
     // Users will want to construct their own preprocessor here
-    let preprocessor = D2;
+    let preprocessor = D2::new();
 
     if let Some(Command::Supports { renderer }) = args.command {
         handle_supports(&preprocessor, &renderer);
+    } else if let Some(Command::Render { file, dir, output }) = args.command {
+        handle_render(&dir, file.as_deref(), output.as_deref());
+    } else if let Some(Command::Clean { dir, dry_run }) = args.command {
+        handle_clean(&dir, dry_run);
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
         error!("Preprocessing failed: {e}");
         process::exit(1);
@@ -68,16 +104,19 @@ fn main() {
 }
 
 fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())
-        .map_err(|e| {
-            Error::msg(format!(
-                "Failed to parse mdBook input: {e}. \
+    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin()).map_err(|e| {
+        Error::msg(format!(
+            "Failed to parse mdBook input: {e}. \
                  This preprocessor should be called by mdBook, not directly."
-            ))
-        })?;
+        ))
+    })?;
 
-    let book_version = Version::parse(&ctx.mdbook_version)
-        .map_err(|e| Error::msg(format!("Invalid mdBook version '{}': {}", ctx.mdbook_version, e)))?;
+    let book_version = Version::parse(&ctx.mdbook_version).map_err(|e| {
+        Error::msg(format!(
+            "Invalid mdBook version '{}': {}",
+            ctx.mdbook_version, e
+        ))
+    })?;
     let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)
         .map_err(|e| Error::msg(format!("Invalid version requirement: {e}")))?;
 
@@ -90,14 +129,13 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
         );
     }
 
-    let processed_book = pre.run(&ctx, book)
-        .map_err(|e| {
-            Error::msg(format!(
-                "Failed to process book with {} preprocessor: {}",
-                pre.name(),
-                e
-            ))
-        })?;
+    let processed_book = pre.run(&ctx, book).map_err(|e| {
+        Error::msg(format!(
+            "Failed to process book with {} preprocessor: {}",
+            pre.name(),
+            e
+        ))
+    })?;
 
     serde_json::to_writer(io::stdout(), &processed_book)
         .map_err(|e| Error::msg(format!("Failed to write output JSON: {e}")))?;
@@ -115,3 +153,47 @@ fn handle_supports(pre: &dyn Preprocessor, renderer: &str) -> ! {
         process::exit(1);
     }
 }
+
+fn handle_clean(dir: &Path, dry_run: bool) -> ! {
+    match mdbook_d2_png::clean(dir, dry_run) {
+        Ok(stale) => {
+            let action = if dry_run { "Would remove" } else { "Removed" };
+            for path in &stale {
+                println!("{action} {}", path.display());
+            }
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Cleaning failed: {e:#}");
+            process::exit(1);
+        }
+    }
+}
+
+fn handle_render(dir: &Path, file: Option<&Path>, output: Option<&Path>) -> ! {
+    let content = match file {
+        Some(file) => fs::read_to_string(file),
+        None => io::read_to_string(io::stdin()),
+    };
+    let content = content.unwrap_or_else(|e| {
+        eprintln!("Failed to read the diagram: {e}");
+        process::exit(1);
+    });
+
+    let (result, messages) = mdbook_d2_png::render_diagram(dir, file, &content);
+    for message in messages {
+        eprintln!("{message}");
+    }
+    let written = result.and_then(|image| {
+        match output {
+            Some(output) => fs::write(output, image)?,
+            None => io::stdout().write_all(&image)?,
+        }
+        Ok(())
+    });
+    if let Err(e) = written {
+        eprintln!("Rendering failed: {e:#}");
+        process::exit(1);
+    }
+    process::exit(0);
+}
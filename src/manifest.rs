@@ -0,0 +1,224 @@
+//! Recording the diagram files a build generated
+//!
+//! When diagrams are written as files, every file a build generated is
+//! listed in `manifest.json` in the output directory, with the chapter and
+//! diagram it shows, a hash of the diagram's source and a hash of the file. Downstream tooling
+//! can use it to audit which pages have diagrams, and `mdbook-d2-png clean`
+//! compares the directory against it to remove images left behind by
+//! diagrams that were since deleted, moved or renumbered.
+//!
+//! Only files some build generated are ever removed: the manifest also
+//! remembers every file earlier builds listed, so images placed in the
+//! directory by hand are left alone. Builds that left diagrams out, e.g.
+//! previews limited by `only-tags`, are marked as partial, and `clean`
+//! refuses to run after them.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// Name of the manifest file in the output directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// The diagram files generated by the latest build
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The generated files, sorted by path
+    pub files: Vec<ManifestEntry>,
+    /// Paths of every file listed by this or an earlier build, sorted
+    #[serde(default)]
+    pub history: Vec<String>,
+    /// Whether the build left diagrams out, so `files` may miss images still in use
+    #[serde(default)]
+    pub partial: bool,
+}
+
+/// A generated diagram file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestEntry {
+    /// Path relative to the source directory, e.g. `d2/1.2.3.png`
+    pub path: String,
+    /// Name of the chapter containing the diagram
+    pub chapter: String,
+    /// The chapter's section number, e.g. `1.2`, if it is numbered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    /// Index of the diagram within its chapter, starting at 1
+    pub diagram_index: usize,
+    /// Index of the part within a split code block, starting at 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_index: Option<usize>,
+    /// SHA-256 of the diagram's source and info string options, in hex
+    pub hash: String,
+    /// SHA-256 of the file as written, in hex, e.g. for CDN integrity checks
+    #[serde(default)]
+    pub integrity: String,
+}
+
+impl Manifest {
+    /// Creates the manifest of a build, carrying over an earlier build's history
+    ///
+    /// # Arguments
+    /// * `files` - The files the build generated, sorted by path
+    /// * `previous` - The manifest of the previous build, if any
+    /// * `partial` - Whether the build left diagrams out
+    pub fn new(files: Vec<ManifestEntry>, previous: Option<Self>, partial: bool) -> Self {
+        let mut history = previous
+            .map(|previous| previous.history)
+            .unwrap_or_default();
+        history.extend(files.iter().map(|entry| entry.path.clone()));
+        history.sort();
+        history.dedup();
+        Self {
+            files,
+            history,
+            partial,
+        }
+    }
+
+    /// Reads the manifest from an output directory, if a build wrote one
+    ///
+    /// # Arguments
+    /// * `dir` - The output directory
+    ///
+    /// # Errors
+    /// Returns an error if the manifest exists but can't be read or parsed.
+    pub fn read(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read manifest {}", path.display()))
+            }
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid manifest {}", path.display()))
+    }
+
+    /// Writes the manifest to an output directory
+    ///
+    /// # Arguments
+    /// * `dir` - The output directory, which must exist
+    ///
+    /// # Errors
+    /// Returns an error if the manifest can't be written.
+    pub fn write(&self, dir: &Path) -> anyhow::Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json + "\n")
+            .with_context(|| format!("Failed to write manifest {}", path.display()))
+    }
+
+    /// Returns the files in an output directory an earlier build generated
+    /// but the latest one didn't
+    ///
+    /// Files no manifest ever listed, e.g. images added by hand, aren't
+    /// considered.
+    ///
+    /// # Arguments
+    /// * `dir` - The output directory
+    ///
+    /// # Errors
+    /// Returns an error if the latest build was partial, so images it didn't
+    /// list may still be in use.
+    pub fn stale_files(&self, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        if self.partial {
+            bail!(
+                "The latest build left diagrams out (e.g. with `only-tags`, `max-diagrams` or failed \
+                 diagrams), so its {MANIFEST_FILE} doesn't list every image in use. Run a full build first"
+            );
+        }
+        let current: HashSet<&str> = self.files.iter().map(|entry| entry.path.as_str()).collect();
+        let mut stale: Vec<PathBuf> = self
+            .history
+            .iter()
+            .filter(|path| !current.contains(path.as_str()))
+            .filter_map(|path| Path::new(path).file_name())
+            .map(|name| dir.join(name))
+            .filter(|path| path.is_file())
+            .collect();
+        stale.sort();
+        Ok(stale)
+    }
+
+    /// Forgets removed files, so only files still on disk stay in the history
+    ///
+    /// # Arguments
+    /// * `removed` - The removed files, as returned by [`Manifest::stale_files`]
+    pub fn forget(&mut self, removed: &[PathBuf]) {
+        let removed: HashSet<_> = removed.iter().filter_map(|path| path.file_name()).collect();
+        self.history.retain(|path| {
+            Path::new(path)
+                .file_name()
+                .is_none_or(|name| !removed.contains(name))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a manifest entry for a generated file
+    fn entry(path: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: path.into(),
+            chapter: "Chapter 1".into(),
+            section: Some("1".into()),
+            diagram_index: 1,
+            sub_index: None,
+            hash: "0123456789abcdef".into(),
+            integrity: "fedcba9876543210".into(),
+        }
+    }
+
+    #[test]
+    fn test_stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Manifest::read(dir.path()).unwrap(), None);
+
+        for file in ["1.1.png", "1.2.png", "2.1.svg", "logo.png"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        let first = Manifest::new(
+            vec![
+                entry("d2/1.1.png"),
+                entry("d2/1.2.png"),
+                entry("d2/2.1.svg"),
+            ],
+            None,
+            false,
+        );
+        let mut manifest = Manifest::new(vec![entry("d2/1.1.png")], Some(first), false);
+        manifest.write(dir.path()).unwrap();
+        assert_eq!(
+            Manifest::read(dir.path()).unwrap().as_ref(),
+            Some(&manifest)
+        );
+
+        // Images no build generated, like the hand-made logo, are kept
+        let stale = manifest.stale_files(dir.path()).unwrap();
+        assert_eq!(
+            stale,
+            [dir.path().join("1.2.png"), dir.path().join("2.1.svg")]
+        );
+
+        manifest.forget(&stale);
+        assert_eq!(manifest.history, ["d2/1.1.png"]);
+    }
+
+    #[test]
+    fn test_stale_files_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Manifest::new(vec![entry("d2/1.1.png"), entry("d2/1.2.png")], None, false);
+        let manifest = Manifest::new(vec![entry("d2/1.1.png")], Some(first), true);
+        let error = manifest.stale_files(dir.path()).unwrap_err().to_string();
+        assert!(error.contains("left diagrams out"), "{error}");
+    }
+}
@@ -0,0 +1,160 @@
+//! Figure numbering across the book
+
+use mdbook::book::SectionNumber;
+
+use crate::config::NumberingScope;
+
+/// Assigns figure numbers to chapters' diagrams, visited in book order
+#[derive(Debug)]
+pub struct FigureNumbering {
+    /// Scope within which figure numbers count up
+    scope: NumberingScope,
+    /// Number of code blocks numbered so far, for book-wide numbering
+    blocks: usize,
+    /// Top-level chapter number preceding the current part
+    part_offset: u32,
+    /// Whether a part has started without a numbered chapter yet
+    new_part: bool,
+}
+
+/// Figure numbering of a single chapter's diagrams
+#[derive(Debug)]
+pub struct ChapterFigures {
+    /// Section number prefixing the chapter's figure numbers, if any
+    prefix: Option<String>,
+    /// Number of code blocks preceding the chapter's in the scope
+    offset: usize,
+}
+
+impl FigureNumbering {
+    /// Creates a numbering starting at the beginning of the book
+    ///
+    /// # Arguments
+    /// * `scope` - Scope within which figure numbers count up
+    pub const fn new(scope: NumberingScope) -> Self {
+        Self {
+            scope,
+            blocks: 0,
+            part_offset: 0,
+            new_part: false,
+        }
+    }
+
+    /// Marks the start of a new part of the book
+    pub const fn start_part(&mut self) {
+        self.new_part = true;
+    }
+
+    /// Numbers the next chapter's figures
+    ///
+    /// Must be called for every chapter in book order, including chapters
+    /// without diagrams, so part boundaries are tracked correctly.
+    ///
+    /// # Arguments
+    /// * `section` - The chapter's section number, if it is numbered
+    /// * `blocks` - Number of D2 code blocks in the chapter
+    pub fn chapter(&mut self, section: Option<&SectionNumber>, blocks: usize) -> ChapterFigures {
+        let section = section.filter(|section| !section.is_empty());
+        let figures = match self.scope {
+            NumberingScope::Book => ChapterFigures {
+                prefix: None,
+                offset: self.blocks,
+            },
+            NumberingScope::Part => {
+                if let (true, Some(section)) = (self.new_part, section) {
+                    self.part_offset = section[0].saturating_sub(1);
+                    self.new_part = false;
+                }
+                let prefix = section.map(|section| {
+                    let mut relative = section.clone();
+                    relative[0] = relative[0].saturating_sub(self.part_offset);
+                    section_prefix(&relative)
+                });
+                ChapterFigures { prefix, offset: 0 }
+            }
+            NumberingScope::Chapter => ChapterFigures {
+                prefix: section.map(section_prefix),
+                offset: 0,
+            },
+        };
+        self.blocks += blocks;
+        figures
+    }
+}
+
+impl ChapterFigures {
+    /// Returns the figure number of a diagram in the chapter
+    ///
+    /// # Arguments
+    /// * `diagram_index` - 1-based index of the diagram's code block in the chapter
+    /// * `sub_index` - 1-based index of the diagram within a split code block
+    pub fn figure(&self, diagram_index: usize, sub_index: Option<usize>) -> String {
+        let mut figure = (self.offset + diagram_index).to_string();
+        if let Some(prefix) = &self.prefix {
+            figure = format!("{prefix}.{figure}");
+        }
+        if let Some(sub_index) = sub_index {
+            figure = format!("{figure}-{sub_index}");
+        }
+        figure
+    }
+}
+
+/// Formats a section number without its trailing dot, e.g. `1.2`
+fn section_prefix(section: &SectionNumber) -> String {
+    section.to_string().trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chapter_scope() {
+        let mut numbering = FigureNumbering::new(NumberingScope::Chapter);
+        let figures = numbering.chapter(Some(&SectionNumber(vec![3, 2])), 2);
+        assert_eq!(figures.figure(2, None), "3.2.2");
+        assert_eq!(figures.figure(1, Some(2)), "3.2.1-2");
+        assert_eq!(numbering.chapter(None, 1).figure(1, None), "1");
+    }
+
+    #[test]
+    fn test_part_scope() {
+        let mut numbering = FigureNumbering::new(NumberingScope::Part);
+        assert_eq!(
+            numbering
+                .chapter(Some(&SectionNumber(vec![1])), 1)
+                .figure(1, None),
+            "1.1"
+        );
+        numbering.start_part();
+        // Unnumbered chapters don't start the part's numbering
+        assert_eq!(numbering.chapter(None, 1).figure(1, None), "1");
+        assert_eq!(
+            numbering
+                .chapter(Some(&SectionNumber(vec![2])), 1)
+                .figure(1, None),
+            "1.1"
+        );
+        assert_eq!(
+            numbering
+                .chapter(Some(&SectionNumber(vec![3, 1])), 1)
+                .figure(2, None),
+            "2.1.2"
+        );
+    }
+
+    #[test]
+    fn test_book_scope() {
+        let mut numbering = FigureNumbering::new(NumberingScope::Book);
+        assert_eq!(
+            numbering
+                .chapter(Some(&SectionNumber(vec![1])), 2)
+                .figure(2, None),
+            "2"
+        );
+        numbering.start_part();
+        let figures = numbering.chapter(Some(&SectionNumber(vec![2])), 1);
+        assert_eq!(figures.figure(1, Some(1)), "3-1");
+    }
+}
@@ -0,0 +1,188 @@
+//! Minimal PNG chunk manipulation for post-processing rendered diagrams
+
+use anyhow::{bail, ensure};
+
+/// The 8-byte signature every PNG file starts with
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// sRGB rendering intent: perceptual
+const SRGB_INTENT_PERCEPTUAL: u8 = 0;
+
+/// A chunk within a PNG byte stream
+#[derive(Debug, Clone, Copy)]
+struct Chunk<'a> {
+    /// Four-letter chunk type (e.g. `IHDR`)
+    kind: &'a [u8],
+    /// Chunk payload
+    data: &'a [u8],
+    /// Offset just past the end of the chunk (after its CRC)
+    end: usize,
+}
+
+/// Splits a PNG byte stream into its chunks
+///
+/// # Errors
+/// Returns an error if the signature is missing or a chunk is truncated.
+fn chunks(bytes: &[u8]) -> anyhow::Result<Vec<Chunk<'_>>> {
+    ensure!(
+        bytes.starts_with(PNG_SIGNATURE),
+        "Not a PNG image: missing PNG signature"
+    );
+
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < bytes.len() {
+        let Some(header) = bytes.get(pos..pos + 8) else {
+            bail!("Truncated PNG chunk header at byte {pos}");
+        };
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let end = pos + 12 + length;
+        ensure!(end <= bytes.len(), "Truncated PNG chunk at byte {pos}");
+        chunks.push(Chunk {
+            kind: &header[4..8],
+            data: &bytes[pos + 8..end - 4],
+            end,
+        });
+        pos = end;
+    }
+    Ok(chunks)
+}
+
+/// Computes the CRC-32 (ISO 3309) used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Encodes a complete chunk (length, type, data and CRC)
+fn encode_chunk(kind: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(data.len()).expect("PNG chunk data exceeds 4 GiB");
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&length.to_be_bytes());
+    chunk.extend_from_slice(&kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// Reads a PNG's width and height (in pixels) from its `IHDR` chunk
+///
+/// # Errors
+/// Returns an error if the bytes are not a well-formed PNG.
+pub fn dimensions(bytes: &[u8]) -> anyhow::Result<(u32, u32)> {
+    let chunks = chunks(bytes)?;
+    let Some(header) = chunks
+        .first()
+        .filter(|chunk| chunk.kind == b"IHDR" && chunk.data.len() >= 8)
+    else {
+        bail!("Malformed PNG: first chunk is not IHDR");
+    };
+
+    let width = u32::from_be_bytes([
+        header.data[0],
+        header.data[1],
+        header.data[2],
+        header.data[3],
+    ]);
+    let height = u32::from_be_bytes([
+        header.data[4],
+        header.data[5],
+        header.data[6],
+        header.data[7],
+    ]);
+    Ok((width, height))
+}
+
+/// Marks a PNG as sRGB by inserting an `sRGB` chunk after the header
+///
+/// Images that already carry an `sRGB` or `iCCP` color profile chunk are
+/// returned unchanged.
+///
+/// # Errors
+/// Returns an error if the bytes are not a well-formed PNG.
+pub fn embed_srgb(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let chunks = chunks(bytes)?;
+    let Some(header) = chunks.first().filter(|chunk| chunk.kind == b"IHDR") else {
+        bail!("Malformed PNG: first chunk is not IHDR");
+    };
+
+    if chunks
+        .iter()
+        .any(|chunk| chunk.kind == b"sRGB" || chunk.kind == b"iCCP")
+    {
+        return Ok(bytes.to_vec());
+    }
+
+    let srgb = encode_chunk(*b"sRGB", &[SRGB_INTENT_PERCEPTUAL]);
+    let mut output = Vec::with_capacity(bytes.len() + srgb.len());
+    output.extend_from_slice(&bytes[..header.end]);
+    output.extend_from_slice(&srgb);
+    output.extend_from_slice(&bytes[header.end..]);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal 300x2 PNG byte stream from the given chunks
+    fn png(extra: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(encode_chunk(
+            *b"IHDR",
+            &[0, 0, 1, 44, 0, 0, 0, 2, 8, 6, 0, 0, 0],
+        ));
+        for chunk in extra {
+            bytes.extend(chunk);
+        }
+        bytes.extend(encode_chunk(*b"IEND", &[]));
+        bytes
+    }
+
+    #[test]
+    fn test_crc32() {
+        // CRC of an empty IEND chunk is a well-known constant
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(dimensions(&png(&[])).unwrap(), (300, 2));
+        assert!(dimensions(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn test_embed_srgb_inserts_chunk_after_header() {
+        let output = embed_srgb(&png(&[])).unwrap();
+        let kinds: Vec<_> = chunks(&output)
+            .unwrap()
+            .iter()
+            .map(|c| c.kind.to_vec())
+            .collect();
+        assert_eq!(
+            kinds,
+            [b"IHDR".to_vec(), b"sRGB".to_vec(), b"IEND".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_embed_srgb_keeps_existing_profile() {
+        let input = png(&[encode_chunk(*b"iCCP", b"profile\0\0")]);
+        assert_eq!(embed_srgb(&input).unwrap(), input);
+    }
+
+    #[test]
+    fn test_embed_srgb_rejects_non_png() {
+        assert!(embed_srgb(b"<svg/>").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Gradual ramp-up of concurrent diagram renders
+
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How often a waiting render re-checks whether the ramp has widened
+const RAMP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Limits concurrent renders, widening linearly from one to a maximum
+///
+/// Starting every D2 process at once on a cold machine spikes load. With a
+/// ramp, renders beyond the currently allowed number wait until a running
+/// render finishes or enough time has passed for the limit to grow.
+#[derive(Debug)]
+pub struct ConcurrencyRamp {
+    /// When the ramp started
+    start: Instant,
+    /// Time taken to reach the maximum concurrency
+    duration: Duration,
+    /// Concurrency once the ramp is complete
+    max: usize,
+    /// Number of renders currently running
+    active: Mutex<usize>,
+    /// Signalled whenever a render finishes
+    slot_freed: Condvar,
+}
+
+/// A running render's slot, released when dropped
+#[derive(Debug)]
+pub struct RampPermit<'a> {
+    ramp: &'a ConcurrencyRamp,
+}
+
+impl ConcurrencyRamp {
+    /// Starts a ramp reaching `max` concurrent renders after `duration`
+    ///
+    /// # Arguments
+    /// * `duration` - Time taken to reach the maximum concurrency
+    /// * `max` - Concurrency once the ramp is complete
+    pub fn new(duration: Duration, max: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            duration,
+            max: max.max(1),
+            active: Mutex::new(0),
+            slot_freed: Condvar::new(),
+        }
+    }
+
+    /// Returns the number of concurrent renders allowed after `elapsed`
+    fn allowed(&self, elapsed: Duration) -> usize {
+        if elapsed >= self.duration {
+            return self.max;
+        }
+        let extra = (self.max - 1) as u128 * elapsed.as_millis() / self.duration.as_millis().max(1);
+        1 + usize::try_from(extra).unwrap_or(self.max)
+    }
+
+    /// Waits until another render may start, then claims a slot for it
+    pub fn acquire(&self) -> RampPermit<'_> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        while *active >= self.allowed(self.start.elapsed()) {
+            active = self
+                .slot_freed
+                .wait_timeout(active, RAMP_POLL_INTERVAL)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        *active += 1;
+        drop(active);
+        RampPermit { ramp: self }
+    }
+}
+
+impl Drop for RampPermit<'_> {
+    fn drop(&mut self) {
+        *self
+            .ramp
+            .active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.ramp.slot_freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_grows_linearly() {
+        let ramp = ConcurrencyRamp::new(Duration::from_secs(4), 8);
+        assert_eq!(ramp.allowed(Duration::ZERO), 1);
+        assert_eq!(ramp.allowed(Duration::from_secs(2)), 4);
+        assert_eq!(ramp.allowed(Duration::from_secs(4)), 8);
+        assert_eq!(ramp.allowed(Duration::from_secs(60)), 8);
+    }
+
+    #[test]
+    fn test_zero_duration_allows_full_concurrency() {
+        let ramp = ConcurrencyRamp::new(Duration::ZERO, 8);
+        assert_eq!(ramp.allowed(Duration::ZERO), 8);
+    }
+
+    #[test]
+    fn test_permit_releases_slot() {
+        let ramp = ConcurrencyRamp::new(Duration::from_secs(3600), 8);
+        drop(ramp.acquire());
+        // A second acquire would block forever if the first slot leaked
+        drop(ramp.acquire());
+        assert_eq!(*ramp.active.lock().unwrap(), 0);
+    }
+}
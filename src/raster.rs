@@ -0,0 +1,325 @@
+//! Pixel-level post-processing of rendered PNGs
+//!
+//! Unlike [`crate::png`], which only edits chunks, these transforms decode the
+//! image, change its pixels and encode it again.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use anyhow::Context;
+use color_quant::NeuQuant;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+use crate::config::ResizeFit;
+
+/// Sampling factor of the `NeuQuant` quantizer, from 1 (best quality, slowest) to 30
+const NEUQUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// Decodes a PNG into RGBA pixels
+fn decode(png_bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+    let image = image::load_from_memory_with_format(png_bytes, ImageFormat::Png)
+        .context("Failed to decode PNG")?;
+    Ok(image.into_rgba8())
+}
+
+/// Encodes RGBA pixels as a PNG
+fn encode(image: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut png_bytes = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png_bytes), ImageFormat::Png)
+        .context("Failed to encode PNG")?;
+    Ok(png_bytes)
+}
+
+/// Trims uniform borders off a PNG
+///
+/// Rows and columns at the edges whose pixels all have the background color
+/// are removed. Fully transparent pixels match a transparent background
+/// whatever their color channels. An image consisting only of background is
+/// left as it is.
+///
+/// # Arguments
+/// * `png_bytes` - The PNG to trim
+/// * `background` - The RGBA color to trim, or `None` for the color of the
+///   top-left pixel
+///
+/// # Errors
+/// Returns an error if the bytes aren't a readable PNG.
+pub fn autocrop(png_bytes: &[u8], background: Option<[u8; 4]>) -> anyhow::Result<Vec<u8>> {
+    let image = decode(png_bytes)?;
+    let Some(&corner) = image.pixels().next() else {
+        return Ok(png_bytes.to_vec());
+    };
+    let background = background.map_or(corner, Rgba);
+    let is_background =
+        |pixel: &Rgba<u8>| pixel == &background || (pixel[3] == 0 && background[3] == 0);
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if !is_background(pixel) {
+            bounds = Some(bounds.map_or((x, y, x, y), |(left, top, right, bottom)| {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }));
+        }
+    }
+    let Some((left, top, right, bottom)) = bounds else {
+        return Ok(png_bytes.to_vec());
+    };
+    let (width, height) = (right - left + 1, bottom - top + 1);
+    if (width, height) == image.dimensions() {
+        return Ok(png_bytes.to_vec());
+    }
+    encode(&imageops::crop_imm(&image, left, top, width, height).to_image())
+}
+
+/// Quantizes a PNG to an indexed PNG with a limited palette
+///
+/// Images with at most `colors` distinct colors keep them exactly; others
+/// are quantized with the `NeuQuant` algorithm. Transparency is kept in the
+/// palette's `tRNS` chunk.
+///
+/// # Arguments
+/// * `png_bytes` - The PNG to quantize
+/// * `colors` - The maximum number of palette colors, from 2 to 256
+///
+/// # Errors
+/// Returns an error if the bytes aren't a readable PNG.
+pub fn quantize(png_bytes: &[u8], colors: u16) -> anyhow::Result<Vec<u8>> {
+    let image = decode(png_bytes)?;
+    let colors = usize::from(colors);
+
+    // The image's distinct colors in order of appearance, unless there are
+    // more than `colors`, with a hash map from color to palette index
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let exact = image.pixels().all(|pixel| {
+        if lookup.contains_key(&pixel.0) {
+            return true;
+        }
+        match u8::try_from(palette.len()) {
+            Ok(index) if palette.len() < colors => {
+                lookup.insert(pixel.0, index);
+                palette.push(pixel.0);
+                true
+            }
+            _ => false,
+        }
+    });
+    let indices: Vec<u8> = if exact {
+        image.pixels().map(|pixel| lookup[&pixel.0]).collect()
+    } else {
+        let quantizer = NeuQuant::new(NEUQUANT_SAMPLE_FACTOR, colors, image.as_raw());
+        palette = quantizer
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|color| [color[0], color[1], color[2], color[3]])
+            .collect();
+        // Each distinct color is looked up in the network once
+        lookup.clear();
+        image
+            .pixels()
+            .map(|pixel| {
+                *lookup.entry(pixel.0).or_insert_with(|| {
+                    u8::try_from(quantizer.index_of(&pixel.0)).unwrap_or(u8::MAX)
+                })
+            })
+            .collect()
+    };
+
+    let mut indexed = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut indexed, image.width(), image.height());
+    encoder.set_color(::png::ColorType::Indexed);
+    encoder.set_depth(::png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|color| &color[..3])
+            .copied()
+            .collect::<Vec<_>>(),
+    );
+    if palette.iter().any(|color| color[3] < u8::MAX) {
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect::<Vec<_>>());
+    }
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&indices))
+        .context("Failed to encode indexed PNG")?;
+    Ok(indexed)
+}
+
+/// Downsamples a PNG rendered larger than its native size
+///
+/// # Arguments
+/// * `png_bytes` - The PNG to downsample
+/// * `factor` - How many times larger the PNG was rendered
+///
+/// # Errors
+/// Returns an error if the bytes aren't a readable PNG.
+pub fn downsample(png_bytes: &[u8], factor: u32) -> anyhow::Result<Vec<u8>> {
+    let image = decode(png_bytes)?;
+    let (width, height) = image.dimensions();
+    encode(&imageops::resize(
+        &image,
+        width.div_ceil(factor),
+        height.div_ceil(factor),
+        FilterType::Lanczos3,
+    ))
+}
+
+/// Resizes a PNG to exact dimensions
+///
+/// # Arguments
+/// * `png_bytes` - The PNG to resize
+/// * `(width, height)` - The dimensions of the resized PNG, in pixels
+/// * `fit` - How the image is fitted into the new dimensions
+///
+/// # Errors
+/// Returns an error if the bytes aren't a readable PNG.
+pub fn resize(
+    png_bytes: &[u8],
+    (width, height): (u32, u32),
+    fit: ResizeFit,
+) -> anyhow::Result<Vec<u8>> {
+    let image = DynamicImage::ImageRgba8(decode(png_bytes)?);
+    let resized = match fit {
+        ResizeFit::Contain => {
+            // Centered on a transparent canvas of the requested size
+            let fitted = image
+                .resize(width, height, FilterType::Lanczos3)
+                .into_rgba8();
+            let mut canvas = RgbaImage::new(width, height);
+            let x = (width - fitted.width()) / 2;
+            let y = (height - fitted.height()) / 2;
+            imageops::overlay(&mut canvas, &fitted, x.into(), y.into());
+            canvas
+        }
+        ResizeFit::Cover => image
+            .resize_to_fill(width, height, FilterType::Lanczos3)
+            .into_rgba8(),
+        ResizeFit::Stretch => image
+            .resize_exact(width, height, FilterType::Lanczos3)
+            .into_rgba8(),
+    };
+    encode(&resized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 40x20 PNG, red on the left half and blue on the right
+    fn test_png() -> Vec<u8> {
+        let image = RgbaImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+        encode(&image).unwrap()
+    }
+
+    #[test]
+    fn test_resize() {
+        let png = test_png();
+
+        // Contain keeps the whole image, padded with transparent rows
+        let contained = decode(&resize(&png, (30, 30), ResizeFit::Contain).unwrap()).unwrap();
+        assert_eq!(contained.dimensions(), (30, 30));
+        assert_eq!(contained.get_pixel(15, 0)[3], 0);
+        assert_eq!(contained.get_pixel(0, 15), &Rgba([255, 0, 0, 255]));
+        assert_eq!(contained.get_pixel(29, 15), &Rgba([0, 0, 255, 255]));
+
+        // Cover fills the dimensions, cropping the sides
+        let covered = decode(&resize(&png, (30, 30), ResizeFit::Cover).unwrap()).unwrap();
+        assert_eq!(covered.dimensions(), (30, 30));
+        assert_eq!(covered.get_pixel(15, 0)[3], 255);
+
+        // Stretch ignores the aspect ratio
+        let stretched = decode(&resize(&png, (10, 50), ResizeFit::Stretch).unwrap()).unwrap();
+        assert_eq!(stretched.dimensions(), (10, 50));
+        assert_eq!(stretched.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_downsample() {
+        let downsampled = decode(&downsample(&test_png(), 2).unwrap()).unwrap();
+        assert_eq!(downsampled.dimensions(), (20, 10));
+        assert_eq!(downsampled.get_pixel(0, 5), &Rgba([255, 0, 0, 255]));
+        assert_eq!(downsampled.get_pixel(19, 5), &Rgba([0, 0, 255, 255]));
+
+        // Odd sizes round up
+        let odd = encode(&RgbaImage::new(5, 3)).unwrap();
+        assert_eq!(
+            decode(&downsample(&odd, 2).unwrap()).unwrap().dimensions(),
+            (3, 2)
+        );
+    }
+
+    #[test]
+    fn test_autocrop() {
+        let mut image = RgbaImage::from_pixel(40, 20, Rgba([255, 255, 255, 255]));
+        image.put_pixel(5, 3, Rgba([0, 0, 0, 255]));
+        image.put_pixel(30, 12, Rgba([0, 0, 0, 255]));
+        let png = encode(&image).unwrap();
+
+        // Against the corner's color
+        let cropped = decode(&autocrop(&png, None).unwrap()).unwrap();
+        assert_eq!(cropped.dimensions(), (26, 10));
+        assert_eq!(cropped.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+
+        // Against another color nothing is trimmed
+        assert_eq!(autocrop(&png, Some([0, 0, 0, 0])).unwrap(), png);
+
+        // Against transparency, whatever the transparent pixels' color
+        let mut image = RgbaImage::new(10, 10);
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 0]));
+        image.put_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        let png = encode(&image).unwrap();
+        let cropped = decode(&autocrop(&png, Some([0, 0, 0, 0])).unwrap()).unwrap();
+        assert_eq!(cropped.dimensions(), (1, 1));
+
+        // An image of only background is kept
+        let blank = encode(&RgbaImage::new(4, 4)).unwrap();
+        assert_eq!(autocrop(&blank, None).unwrap(), blank);
+    }
+
+    #[test]
+    fn test_quantize() {
+        // Few colors are kept exactly, including transparency
+        let mut image = RgbaImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        let indexed = quantize(&encode(&image).unwrap(), 256).unwrap();
+        // Color type 3 (indexed) in the IHDR chunk
+        assert_eq!(indexed[25], 3);
+        assert_eq!(decode(&indexed).unwrap(), image);
+
+        // More colors than the palette holds are approximated
+        let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([
+                u8::try_from(x * 4).unwrap(),
+                u8::try_from(y * 4).unwrap(),
+                128,
+                255,
+            ])
+        });
+        let indexed = decode(&quantize(&encode(&gradient).unwrap(), 16).unwrap()).unwrap();
+        assert_eq!(indexed.dimensions(), (64, 64));
+        let mut colors: Vec<_> = indexed.pixels().map(|pixel| pixel.0).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        assert!(colors.len() <= 16);
+    }
+
+    #[test]
+    fn test_resize_rejects_non_png() {
+        assert!(resize(b"<svg/>", (10, 10), ResizeFit::Contain).is_err());
+    }
+}
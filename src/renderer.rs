@@ -0,0 +1,1470 @@
+//! Diagram rendering engines
+//!
+//! [`DiagramRenderer`] abstracts the step that turns diagram source into PNG
+//! bytes, so engines other than the D2 command line tool can be plugged in.
+
+use std::ffi::OsStr;
+use std::fmt::{Debug, Write as _};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use log::{warn, Level};
+use wait_timeout::ChildExt;
+
+use crate::backend::{log_enabled, RenderContext};
+use crate::config::{Config, Fonts, OutputFormat, UnknownVersionBehavior};
+use crate::job_log;
+
+/// Base delay between retries of the startup `d2 --version` check
+///
+/// The delay grows linearly with each attempt.
+const PREFLIGHT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Options a code block's info string may set for its diagram, overriding
+/// the configuration (e.g. ```` ```d2 sketch=true pad=40 ````)
+pub const DIAGRAM_OPTIONS: &[&str] = &["layout", "theme", "dark-theme", "sketch", "pad"];
+
+/// Oldest D2 release the preprocessor supports, used without `min-d2-version`
+const MIN_D2_VERSION: &str = "0.7.0";
+
+/// Base delay between retries of a transiently failed diagram
+///
+/// The delay grows linearly with each attempt.
+const RENDER_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Where to get D2 from, suggested when the binary can't be found
+const D2_INSTALL_URL: &str = "https://d2lang.com";
+
+/// Number of bytes hex-dumped around invalid UTF-8 in D2's stderr
+const STDERR_HEX_DUMP_LEN: usize = 32;
+
+/// Renders diagram source into an image
+///
+/// The preprocessor uses the D2 command line tool by default. Library
+/// consumers can supply their own implementation with
+/// [`D2::with_renderer`](crate::D2::with_renderer) to render diagrams with a
+/// different engine; file naming, linking and post-processing stay the same.
+pub trait DiagramRenderer: Debug + Send + Sync {
+    /// Renders a single diagram, returning the encoded image bytes
+    ///
+    /// Images are PNGs unless `format = "svg"` is configured, in which case
+    /// SVG documents are expected.
+    ///
+    /// # Arguments
+    /// * `content` - The diagram source
+    /// * `ctx` - The render context identifying the diagram
+    ///
+    /// # Errors
+    /// Returns an error if the diagram can't be rendered.
+    fn render(&self, content: &str, ctx: &RenderContext) -> anyhow::Result<Vec<u8>>;
+
+    /// Renders a single diagram, also returning the engine's diagnostics
+    ///
+    /// The diagnostics, e.g. D2's stderr, are stored next to a cached image
+    /// and passed to [`DiagramRenderer::replay_diagnostics`] when the image is
+    /// reused. The default implementation renders with
+    /// [`DiagramRenderer::render`] and returns no diagnostics.
+    ///
+    /// # Arguments
+    /// * `content` - The diagram source
+    /// * `ctx` - The render context identifying the diagram
+    ///
+    /// # Errors
+    /// Returns an error if the diagram can't be rendered.
+    fn render_with_diagnostics(
+        &self,
+        content: &str,
+        ctx: &RenderContext,
+    ) -> anyhow::Result<(Vec<u8>, String)> {
+        self.render(content, ctx)
+            .map(|bytes| (bytes, String::new()))
+    }
+
+    /// Reports the diagnostics of a render whose image is reused from the cache
+    ///
+    /// Lets warnings be logged, or fail the diagram, as if it was rendered
+    /// again. The default implementation does nothing.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context identifying the diagram
+    /// * `diagnostics` - The diagnostics returned when the image was rendered
+    ///
+    /// # Errors
+    /// Returns an error if the diagnostics fail the diagram.
+    fn replay_diagnostics(&self, ctx: &RenderContext, diagnostics: &str) -> anyhow::Result<()> {
+        let _ = (ctx, diagnostics);
+        Ok(())
+    }
+
+    /// Checks that the renderer is usable before any diagram is rendered
+    ///
+    /// Returns the engine's version string, if it has one, for logging.
+    /// The default implementation performs no check.
+    ///
+    /// # Errors
+    /// Returns an error if the renderer can't be used.
+    fn preflight(&self) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Returns a fingerprint of the settings that affect rendered images
+    ///
+    /// Renderers returning a fingerprint have their images cached between
+    /// builds (keyed by it, the diagram source and the version from
+    /// [`DiagramRenderer::preflight`]). The default implementation returns
+    /// `None`, so images are never cached.
+    fn cache_key(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Configuration for the startup `d2 --version` check
+#[derive(Debug, Clone)]
+struct PreflightConfig {
+    /// Timeout for a single version check
+    timeout: Duration,
+    /// Number of retries after a failed version check
+    retries: u32,
+    /// Exact version the D2 binary must report
+    required_version: Option<String>,
+    /// Oldest version the D2 binary may report
+    min_version: String,
+    /// What to do when the D2 binary reports no recognizable version
+    on_unknown_version: UnknownVersionBehavior,
+}
+
+/// Extracts the version from `d2 --version` output, e.g. `0.7.1`
+///
+/// Looks for the first word made of at least two dot-separated numbers,
+/// optionally prefixed with `v`, so wrapper banners around it are tolerated.
+/// Pre-release suffixes like `-rc.1` are kept.
+///
+/// # Arguments
+/// * `output` - The output of `d2 --version`
+fn parse_version(output: &str) -> Option<&str> {
+    output.split_whitespace().find_map(|word| {
+        let version = word.trim_start_matches('v');
+        let core = version.split(['-', '+']).next()?;
+        let numbers: Vec<_> = core.split('.').collect();
+        (numbers.len() >= 2
+            && numbers
+                .iter()
+                .all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())))
+        .then_some(version)
+    })
+}
+
+/// Returns whether an error comes from spawning a binary that doesn't exist
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// A D2 failure that may not recur, such as the process being killed
+#[derive(Debug)]
+struct TransientError(String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// Returns whether rendering a diagram again might succeed after an error
+///
+/// That is only the case for D2 being killed by a signal (e.g. by the OOM
+/// killer) and for D2 failing to start for lack of resources (`EAGAIN` when
+/// the process limit is reached, `ENOMEM`). Any exit status D2 returns
+/// itself is deterministic, whether or not it reports an error.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<TransientError>().is_some()
+        || error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::OutOfMemory
+            )
+        })
+}
+
+/// Checks a D2 version string against an exact pinned version
+///
+/// A leading `v` is ignored on both sides, so `v0.7.1` matches `0.7.1`.
+///
+/// # Arguments
+/// * `version` - The version reported by `d2 --version`
+/// * `required` - The pinned version
+///
+/// # Errors
+/// Returns an error if the versions differ.
+fn check_version_pin(version: &str, required: &str) -> anyhow::Result<()> {
+    let normalize = |version: &str| version.trim().trim_start_matches('v').to_string();
+    if normalize(version) != normalize(required) {
+        bail!(
+            "Installed D2 version {version} doesn't match the required version {required} \
+             (set by `require-d2-version`)"
+        );
+    }
+    Ok(())
+}
+
+/// Parses a D2 version as semver, e.g. `v0.7.1` or `0.7.0-rc.1`
+///
+/// A leading `v` is ignored and a missing patch number counts as `0`, so
+/// `0.7` parses as `0.7.0`.
+///
+/// # Arguments
+/// * `version` - The version to parse
+pub fn parse_semver(version: &str) -> Option<semver::Version> {
+    let version = version.trim().trim_start_matches('v');
+    semver::Version::parse(version).ok().or_else(|| {
+        let split = version.find(['-', '+']).unwrap_or(version.len());
+        let (core, suffix) = version.split_at(split);
+        semver::Version::parse(&format!("{core}.0{suffix}")).ok()
+    })
+}
+
+/// Checks a D2 version string against the oldest supported version
+///
+/// # Arguments
+/// * `version` - The version reported by `d2 --version`
+/// * `minimum` - The oldest supported version
+///
+/// # Errors
+/// Returns an error if the version is older than the minimum, or either
+/// isn't a valid version.
+fn check_min_version(version: &str, minimum: &str) -> anyhow::Result<()> {
+    let parse = |version: &str| {
+        parse_semver(version).with_context(|| format!("Invalid D2 version {version:?}"))
+    };
+    if parse(version)? < parse(minimum)? {
+        bail!(
+            "Installed D2 version {version} is older than the minimum supported version {minimum} \
+             (set by `min-d2-version`); upgrade D2 from {D2_INSTALL_URL}"
+        );
+    }
+    Ok(())
+}
+
+/// Formats D2's stderr for an error message, indenting each line
+///
+/// Invalid UTF-8 is replaced rather than dropped, with a note saying so and,
+/// if requested, a hex dump of the bytes around the first invalid sequence.
+///
+/// # Arguments
+/// * `stderr` - D2's raw standard error output
+/// * `hex_dump` - Whether to hex-dump invalid bytes
+fn format_stderr(stderr: &[u8], hex_dump: bool) -> String {
+    let mut text = String::from_utf8_lossy(stderr).into_owned();
+    if let Err(error) = std::str::from_utf8(stderr) {
+        let offset = error.valid_up_to();
+        text = format!(
+            "{text}\n(stderr contained invalid UTF-8 starting at byte {offset}, shown as \u{fffd})"
+        );
+        if hex_dump {
+            let start = offset.saturating_sub(STDERR_HEX_DUMP_LEN / 2);
+            let end = (start + STDERR_HEX_DUMP_LEN).min(stderr.len());
+            let bytes: Vec<_> = stderr[start..end]
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            text = format!("{text}\n(bytes {start}..{end}: {})", bytes.join(" "));
+        }
+    }
+    format!("\n{text}").replace('\n', "\n  ")
+}
+
+/// Extracts the line and column of each error D2 reports in a diagram
+///
+/// D2 refers to its standard input as `-`, so errors look like
+/// `err: failed to compile -: 2:1: unexpected text`. Lines and columns
+/// start at 1.
+///
+/// # Arguments
+/// * `stderr` - D2's standard error output
+fn error_locations(stderr: &str) -> Vec<(usize, usize)> {
+    let mut locations = Vec::new();
+    for location in stderr.split("-: ").skip(1) {
+        let mut numbers = location.splitn(3, ':').map(str::parse::<usize>);
+        if let (Some(Ok(line)), Some(Ok(column))) = (numbers.next(), numbers.next()) {
+            if line > 0 && !locations.contains(&(line, column)) {
+                locations.push((line, column));
+            }
+        }
+    }
+    locations
+}
+
+/// Shows the diagram lines D2 reports errors on, with a caret under each
+///
+/// Returns an empty string if D2's output names no line of the diagram.
+///
+/// # Arguments
+/// * `stderr` - D2's standard error output
+/// * `content` - The D2 diagram content
+fn error_snippets(stderr: &str, content: &str) -> String {
+    let lines: Vec<_> = content.lines().collect();
+    let mut snippets = String::new();
+    for (line_number, column) in error_locations(stderr) {
+        let Some(line) = lines.get(line_number - 1) else {
+            continue;
+        };
+        let gutter = " ".repeat(line_number.to_string().len());
+        // Keep tabs, so the caret lines up however they are displayed
+        let indent: String = line
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let _ = write!(
+            snippets,
+            "\n  {gutter}--> line {line_number}, column {column}\n  {gutter} |\n  {line_number} | {line}\n  {gutter} | {indent}^"
+        );
+    }
+    snippets
+}
+
+/// Extracts the warnings from D2's stderr, minus suppressed ones
+///
+/// # Arguments
+/// * `stderr` - D2's standard error output
+/// * `suppressed` - Substrings marking warnings to drop
+fn d2_warnings<'a>(stderr: &'a str, suppressed: &'a [String]) -> impl Iterator<Item = &'a str> {
+    stderr
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("warn:"))
+        .map(str::trim)
+        .filter(|warning| {
+            !suppressed
+                .iter()
+                .any(|pattern| warning.contains(pattern.as_str()))
+        })
+}
+
+/// Renders diagrams by running the D2 command line tool
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct D2Cli {
+    /// Path to the D2 binary
+    binary: PathBuf,
+    /// Layout engine to use for D2 diagrams
+    layout: Option<String>,
+    /// Layout engine to retry with when rendering with `layout` fails
+    fallback_layout: Option<String>,
+    /// Custom font configuration
+    fonts: Option<Fonts>,
+    /// Working directory of the D2 process, if not the chapter's directory
+    asset_root: Option<PathBuf>,
+    /// Absolute path to the book's source directory, holding the chapters
+    source_dir: PathBuf,
+    /// Theme ID for D2 diagrams
+    theme_id: Option<String>,
+    /// Dark theme ID for D2 diagrams
+    dark_theme_id: Option<String>,
+    /// Whether diagrams are rendered in hand-drawn sketch mode by default
+    sketch: bool,
+    /// Padding around diagrams in pixels, if not D2's default
+    pad: Option<String>,
+    /// Value of D2's `--scale` flag, when configured or supersampling
+    scale: Option<String>,
+    /// Further flags passed verbatim to D2
+    extra_args: Vec<String>,
+    /// Image format to render, set by the output file's extension
+    format: OutputFormat,
+    /// Time limit for rendering a single diagram, if any
+    timeout: Option<Duration>,
+    /// Number of retries after a transient failure
+    retries: u32,
+    preflight: PreflightConfig,
+    /// Substrings of D2 warnings to drop instead of logging
+    suppress_warnings: Vec<String>,
+    /// Whether D2 warnings fail the diagram
+    warnings_as_errors: bool,
+    /// Whether to hex-dump invalid bytes in D2's error output
+    hex_dump_stderr: bool,
+    /// Whether to suppress all non-error logging
+    quiet: bool,
+}
+
+impl D2Cli {
+    /// Creates a D2 renderer from the preprocessor configuration
+    ///
+    /// D2 runs in the `asset-root` or the chapter's directory rather than
+    /// the book root, so the binary and font paths (relative to the book
+    /// root) are made absolute first.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration for the D2 preprocessor
+    /// * `root_dir` - Absolute path to the book's root directory
+    /// * `source_dir` - Absolute path to the book's source directory
+    pub fn new(config: &Config, root_dir: &Path, source_dir: &Path) -> Self {
+        let asset_root = config.asset_root.as_ref().map(|dir| root_dir.join(dir));
+        let rebase = |path: &Path| {
+            // A bare binary name is looked up in `PATH` rather than relative to a directory
+            if path.components().count() > 1 {
+                root_dir.join(path)
+            } else {
+                path.to_path_buf()
+            }
+        };
+        Self {
+            binary: rebase(&config.path),
+            layout: config.layout.clone(),
+            fallback_layout: config.fallback_layout.clone(),
+            fonts: config.fonts.as_ref().map(|fonts| Fonts {
+                regular: rebase(&fonts.regular),
+                italic: rebase(&fonts.italic),
+                bold: rebase(&fonts.bold),
+                semibold: fonts.semibold.as_deref().map(rebase),
+            }),
+            asset_root,
+            source_dir: source_dir.to_path_buf(),
+            theme_id: config.mode_theme_id().or(config.theme_id.as_ref()).cloned(),
+            dark_theme_id: config.dark_theme_id.clone(),
+            sketch: config.sketch,
+            pad: config.pad.map(|pad| pad.to_string()),
+            // Supersampling only makes sense for raster images
+            scale: config.scale.map(|scale| scale.to_string()).or_else(|| {
+                (config.format == OutputFormat::Png && config.quality.scale() > 1)
+                    .then(|| config.quality.scale().to_string())
+            }),
+            extra_args: config.extra_args.clone(),
+            format: config.format,
+            timeout: (config.timeout > 0).then(|| Duration::from_secs(config.timeout)),
+            retries: config.retries,
+            preflight: PreflightConfig {
+                timeout: Duration::from_secs(config.preflight_timeout),
+                retries: config.preflight_retries,
+                required_version: config.require_d2_version.clone(),
+                min_version: config
+                    .min_d2_version
+                    .clone()
+                    .unwrap_or_else(|| MIN_D2_VERSION.to_string()),
+                on_unknown_version: config.on_unknown_version,
+            },
+            suppress_warnings: config.suppress_warnings.clone(),
+            warnings_as_errors: config.warnings_as_errors,
+            hex_dump_stderr: config.hex_dump_stderr,
+            quiet: config.quiet,
+        }
+    }
+
+    /// Logs the warnings D2 printed while successfully rendering a diagram
+    ///
+    /// D2 prefixes warnings with `warn:` on stderr; other lines (such as its
+    /// `success:` summary) and warnings matching a suppressed substring are
+    /// dropped.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `stderr` - D2's standard error output
+    fn log_warnings(&self, ctx: &RenderContext, stderr: &str) {
+        if !log_enabled(self.quiet, Level::Warn) {
+            return;
+        }
+        for warning in d2_warnings(stderr, &self.suppress_warnings) {
+            job_log::log(
+                Level::Warn,
+                format!(
+                    "D2 warning for diagram ({}, #{}): {warning}",
+                    ctx.chapter(),
+                    ctx.diagram_index()
+                ),
+            );
+        }
+    }
+
+    /// Fails a successful render that produced warnings, if configured
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `stderr` - D2's standard error output
+    ///
+    /// # Errors
+    /// Returns an error listing the warnings if warnings are errors and D2
+    /// printed any that aren't suppressed.
+    fn check_warnings(&self, ctx: &RenderContext, stderr: &str) -> anyhow::Result<()> {
+        if !self.warnings_as_errors {
+            return Ok(());
+        }
+        let warnings: Vec<_> = d2_warnings(stderr, &self.suppress_warnings).collect();
+        if warnings.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "D2 diagram ({}, #{}) rendered with warnings, which are errors with \
+             warnings-as-errors enabled:\n  {}",
+            ctx.chapter(),
+            ctx.diagram_index(),
+            warnings.join("\n  ")
+        )
+    }
+
+    /// Runs `d2 --version` once, bounded by the preflight timeout
+    fn query_version(&self) -> anyhow::Result<String> {
+        let mut child = Command::new(&self.binary)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to spawn D2 process")?;
+
+        let Some(status) = child.wait_timeout(self.preflight.timeout)? else {
+            child
+                .kill()
+                .context("Failed to kill D2 process after timeout")?;
+            let _ = child.wait(); // Reap the killed process to prevent zombie
+            bail!(
+                "timed out after {} seconds",
+                self.preflight.timeout.as_secs()
+            );
+        };
+
+        let output = child
+            .wait_with_output()
+            .context("Failed to collect D2 process output")?;
+        if !status.success() {
+            bail!(
+                "exited with status {status}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Runs the configured version checks on `d2 --version` output
+    ///
+    /// Returns the parsed version, or `None` if the output holds no
+    /// recognizable version and `on-unknown-version` lets the build go on.
+    ///
+    /// # Errors
+    /// Returns an error if the version doesn't match `require-d2-version`,
+    /// is older than `min-d2-version`, or is unknown with `on-unknown-version = "fail"`.
+    fn check_version(&self, output: &str) -> anyhow::Result<Option<String>> {
+        let Some(version) = parse_version(output) else {
+            match self.preflight.on_unknown_version {
+                UnknownVersionBehavior::Fail => bail!(
+                    "Unable to find a version in the output of {} --version: {output:?} \
+                     (set `on-unknown-version` to \"warn\" or \"ignore\" to skip version checks)",
+                    self.binary.display()
+                ),
+                UnknownVersionBehavior::Warn if log_enabled(self.quiet, Level::Warn) => warn!(
+                    "Unable to find a version in the output of {} --version: {output:?}. \
+                     Skipping version checks",
+                    self.binary.display()
+                ),
+                UnknownVersionBehavior::Warn | UnknownVersionBehavior::Ignore => {}
+            }
+            return Ok(None);
+        };
+        if let Some(required) = &self.preflight.required_version {
+            check_version_pin(version, required)?;
+        }
+        check_min_version(version, &self.preflight.min_version)?;
+        Ok(Some(version.to_string()))
+    }
+
+    /// Builds the D2 command line arguments, ending with `-` (read from stdin)
+    ///
+    /// The diagram's info string options take precedence over the
+    /// configured theme IDs.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram, holding its options
+    /// * `layout` - The layout engine to request, if any
+    fn basic_args<'a>(
+        &'a self,
+        ctx: &RenderContext<'a>,
+        layout: Option<&'a str>,
+    ) -> Vec<&'a OsStr> {
+        let mut args = vec![];
+
+        if let Some(fonts) = &self.fonts {
+            args.extend([
+                OsStr::new("--font-regular"),
+                fonts.regular.as_os_str(),
+                OsStr::new("--font-italic"),
+                fonts.italic.as_os_str(),
+                OsStr::new("--font-bold"),
+                fonts.bold.as_os_str(),
+            ]);
+            if let Some(semibold) = &fonts.semibold {
+                args.extend([OsStr::new("--font-semibold"), semibold.as_os_str()]);
+            }
+        }
+        if let Some(layout) = layout {
+            args.extend([OsStr::new("--layout"), layout.as_ref()]);
+        }
+        if let Some(theme_id) = ctx.option("theme").or(self.theme_id.as_deref()) {
+            args.extend([OsStr::new("--theme"), theme_id.as_ref()]);
+        }
+        if let Some(dark_theme_id) = ctx.option("dark-theme").or(self.dark_theme_id.as_deref()) {
+            args.extend([OsStr::new("--dark-theme"), dark_theme_id.as_ref()]);
+        }
+        if ctx
+            .option("sketch")
+            .map_or(self.sketch, |sketch| sketch == "true")
+        {
+            args.push(OsStr::new("--sketch"));
+        }
+        if let Some(pad) = ctx.option("pad").or(self.pad.as_deref()) {
+            args.extend([OsStr::new("--pad"), pad.as_ref()]);
+        }
+        if let Some(scale) = &self.scale {
+            args.extend([OsStr::new("--scale"), scale.as_ref()]);
+        }
+        args.extend(self.extra_args.iter().map(OsStr::new));
+        args.push(OsStr::new("-"));
+        args
+    }
+
+    /// Waits for a D2 process to exit, returning `None` if it exceeds the timeout
+    ///
+    /// Without a configured timeout, waits as long as the process runs.
+    fn wait(&self, child: &mut Child) -> std::io::Result<Option<ExitStatus>> {
+        match self.timeout {
+            Some(timeout) => child.wait_timeout(timeout),
+            None => child.wait().map(Some),
+        }
+    }
+
+    /// Returns the directory D2 runs in for a diagram
+    ///
+    /// D2 resolves `@import`s and local image paths of diagrams read from
+    /// stdin against its working directory. That is the `asset-root` if
+    /// set, else the directory of the chapter containing the diagram, like
+    /// Markdown links and images. Chapters without a directory on disk, e.g.
+    /// generated ones, leave D2 in the current directory.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    fn working_dir(&self, ctx: &RenderContext) -> Option<PathBuf> {
+        if self.asset_root.is_some() {
+            return self.asset_root.clone();
+        }
+        let chapter_dir = ctx
+            .path()
+            .parent()
+            .map_or_else(|| self.source_dir.clone(), |dir| self.source_dir.join(dir));
+        chapter_dir.is_dir().then_some(chapter_dir)
+    }
+
+    /// Executes the D2 binary for a single diagram
+    ///
+    /// Executes the D2 binary with a timeout to prevent hanging on malformed input.
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `content` - The D2 diagram content
+    /// * `args` - Additional arguments for the D2 process
+    ///
+    /// # Errors
+    /// Returns an error if:
+    /// - The D2 process fails to spawn
+    /// - Writing to stdin fails
+    /// - The process exceeds the configured timeout
+    /// - The D2 compilation fails
+    ///
+    /// # Returns
+    /// D2's standard error output
+    fn execute_d2(
+        &self,
+        ctx: &RenderContext,
+        content: &str,
+        args: Vec<&OsStr>,
+    ) -> anyhow::Result<String> {
+        let mut command = Command::new(&self.binary);
+        if let Some(working_dir) = self.working_dir(ctx) {
+            command.current_dir(working_dir);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .args(args)
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "Failed to spawn D2 process. Is D2 installed and available at {}?",
+                    self.binary.display()
+                )
+            })?;
+
+        // Write to stdin safely and close it
+        let write_result = child
+            .stdin
+            .take()
+            .context("Failed to open stdin for D2 process")?
+            .write_all(content.as_bytes());
+        // stdin is closed when the handle taken above is dropped
+
+        if let Err(write_error) = write_result {
+            // D2 most likely exited before reading its input (e.g. it rejected an
+            // argument), in which case its stderr explains the real problem
+            if self.wait(&mut child)?.is_none() {
+                child
+                    .kill()
+                    .context("Failed to kill D2 process after timeout")?;
+            }
+            let output = child
+                .wait_with_output()
+                .context("Failed to collect D2 process output")?;
+            bail!(
+                "Failed to write D2 diagram content to stdin ({}, #{}): {write_error}. \
+                 D2 exited with status {}:{}",
+                ctx.chapter(),
+                ctx.diagram_index(),
+                output.status,
+                format_stderr(&output.stderr, self.hex_dump_stderr)
+            )
+        }
+
+        // Wait for the process with a timeout
+        let Some(status_code) = self.wait(&mut child)? else {
+            // Process exceeded timeout, kill it and reap to prevent zombie
+            child
+                .kill()
+                .context("Failed to kill D2 process after timeout")?;
+            let _ = child.wait(); // Reap the killed process to prevent zombie
+            return Err(anyhow!(
+                "D2 process timed out after {} seconds (the configured `timeout`) while \
+                 processing diagram ({}, #{}). The diagram may be too complex or D2 may be \
+                 hanging. Consider simplifying the diagram or raising `timeout`.",
+                self.timeout.unwrap_or_default().as_secs(),
+                ctx.chapter(),
+                ctx.diagram_index()
+            ));
+        };
+
+        // Collect output after process completes
+        let output = child
+            .wait_with_output()
+            .context("Failed to collect D2 process output")?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if status_code.success() {
+            self.check_warnings(ctx, &stderr)?;
+            self.log_warnings(ctx, &stderr);
+            Ok(stderr.into_owned())
+        } else {
+            let message = format!(
+                "Failed to compile D2 diagram ({}, #{}) - D2 exited with status {}:{}{}",
+                ctx.chapter(),
+                ctx.diagram_index(),
+                status_code,
+                format_stderr(&output.stderr, self.hex_dump_stderr),
+                error_snippets(&stderr, content)
+            );
+            // Without an exit code D2 was killed by a signal, which may not
+            // happen again
+            if status_code.code().is_none() {
+                return Err(TransientError(message).into());
+            }
+            bail!(message)
+        }
+    }
+
+    /// Executes the D2 binary, retrying transient failures
+    ///
+    /// # Arguments
+    /// * `ctx` - The render context for the diagram
+    /// * `content` - The D2 diagram content
+    /// * `args` - Additional arguments for the D2 process
+    ///
+    /// # Returns
+    /// D2's standard error output
+    ///
+    /// # Errors
+    /// Returns the last error if D2 fails deterministically or runs out of retries.
+    fn execute_with_retries(
+        &self,
+        ctx: &RenderContext,
+        content: &str,
+        args: &[&OsStr],
+    ) -> anyhow::Result<String> {
+        let mut attempt = 0;
+        loop {
+            match self.execute_d2(ctx, content, args.to_vec()) {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    if log_enabled(self.quiet, Level::Warn) {
+                        job_log::log(
+                            Level::Warn,
+                            format!(
+                                "Retrying D2 diagram ({}, #{}) after a transient failure (attempt {attempt} \
+                                 of {}): {e:#}",
+                                ctx.chapter(),
+                                ctx.diagram_index(),
+                                self.retries
+                            ),
+                        );
+                    }
+                    std::thread::sleep(RENDER_RETRY_DELAY * attempt);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl DiagramRenderer for D2Cli {
+    /// Renders a diagram with D2
+    ///
+    /// D2 writes the image to a temporary file, which is read back. If rendering
+    /// with the diagram's or configured layout fails and a fallback layout is configured,
+    /// the diagram is retried once with the fallback.
+    fn render(&self, content: &str, ctx: &RenderContext) -> anyhow::Result<Vec<u8>> {
+        self.render_with_diagnostics(content, ctx)
+            .map(|(bytes, _)| bytes)
+    }
+
+    /// Renders a diagram with D2, returning its stderr as the diagnostics
+    fn render_with_diagnostics(
+        &self,
+        content: &str,
+        ctx: &RenderContext,
+    ) -> anyhow::Result<(Vec<u8>, String)> {
+        let output = tempfile::Builder::new()
+            .prefix("mdbook-d2-")
+            .suffix(&format!(".{}", self.format.extension()))
+            .tempfile()
+            .context("Failed to create temporary file for D2 output")?;
+        let args = |layout| {
+            let mut args = self.basic_args(ctx, layout);
+            args.push(output.path().as_os_str());
+            args
+        };
+
+        let layout = ctx.option("layout").or(self.layout.as_deref());
+        let mut result = self.execute_with_retries(ctx, content, &args(layout));
+
+        if let (Err(e), Some(fallback)) = (&result, self.fallback_layout.as_deref()) {
+            if layout != Some(fallback) {
+                if log_enabled(self.quiet, Level::Warn) {
+                    job_log::log(
+                        Level::Warn,
+                        format!(
+                            "Retrying D2 diagram ({}, #{}) with fallback layout '{fallback}' \
+                             after layout '{}' failed: {e}",
+                            ctx.chapter(),
+                            ctx.diagram_index(),
+                            layout.unwrap_or("default")
+                        ),
+                    );
+                }
+                result = self.execute_with_retries(ctx, content, &args(Some(fallback)));
+                if result.is_ok() && log_enabled(self.quiet, Level::Info) {
+                    job_log::log(
+                        Level::Info,
+                        format!(
+                            "D2 diagram ({}, #{}) rendered with fallback layout '{fallback}'",
+                            ctx.chapter(),
+                            ctx.diagram_index()
+                        ),
+                    );
+                }
+            }
+        }
+
+        let stderr = result?;
+        let bytes = fs::read(output.path()).context("Failed to read D2 output")?;
+        Ok((bytes, stderr))
+    }
+
+    /// Logs the warnings in D2's stored stderr, failing the diagram with
+    /// `warnings-as-errors`
+    fn replay_diagnostics(&self, ctx: &RenderContext, diagnostics: &str) -> anyhow::Result<()> {
+        self.check_warnings(ctx, diagnostics)?;
+        self.log_warnings(ctx, diagnostics);
+        Ok(())
+    }
+
+    /// Checks that the D2 binary can be run, returning its version string
+    ///
+    /// Runs `d2 --version` with its own short timeout, retrying a configured
+    /// number of times with a growing delay so a transient hiccup on a busy
+    /// machine doesn't abort the build.
+    fn preflight(&self) -> anyhow::Result<Option<String>> {
+        let mut attempt = 0;
+        loop {
+            match self.query_version() {
+                Ok(output) => return self.check_version(&output),
+                // Retrying won't make a missing binary appear
+                Err(e) if is_not_found(&e) => {
+                    bail!(
+                        "d2 binary not found at {}; install it from {D2_INSTALL_URL} or point `path` \
+                         at an existing binary",
+                        self.binary.display()
+                    )
+                }
+                Err(e) if attempt < self.preflight.retries => {
+                    attempt += 1;
+                    if log_enabled(self.quiet, Level::Warn) {
+                        warn!(
+                            "D2 version check failed (attempt {attempt} of {}): {e:#}",
+                            self.preflight.retries + 1
+                        );
+                    }
+                    std::thread::sleep(PREFLIGHT_RETRY_DELAY * attempt);
+                }
+                Err(e) => {
+                    bail!(
+                        "Unable to run {} --version after {} attempt(s): {e:#}",
+                        self.binary.display(),
+                        attempt + 1
+                    )
+                }
+            }
+        }
+    }
+
+    /// Fingerprints the D2 binary and the flags it is run with
+    fn cache_key(&self) -> Option<String> {
+        Some(format!(
+            "{:?}",
+            (
+                &self.binary,
+                &self.layout,
+                &self.fallback_layout,
+                &self.fonts,
+                &self.theme_id,
+                &self.dark_theme_id,
+                self.sketch,
+                &self.pad,
+                &self.scale,
+                &self.extra_args,
+                self.format,
+                // Changing how warnings are treated renders diagrams again
+                (self.warnings_as_errors, &self.suppress_warnings),
+            )
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RenderQuality, Scale};
+
+    /// Creates a D2 renderer with default configuration
+    fn create_test_renderer() -> D2Cli {
+        D2Cli::new(
+            &Config::default(),
+            Path::new("/book"),
+            Path::new("/book/src"),
+        )
+    }
+
+    #[test]
+    fn test_preflight_missing_binary() {
+        let mut renderer = create_test_renderer();
+        renderer.binary = PathBuf::from("/nonexistent/d2");
+        renderer.preflight.retries = 1;
+
+        let error = renderer.preflight().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "d2 binary not found at /nonexistent/d2; install it from https://d2lang.com or point \
+             `path` at an existing binary"
+        );
+    }
+
+    #[test]
+    fn test_preflight_failing_binary() {
+        let mut renderer = create_test_renderer();
+        renderer.binary = PathBuf::from("false");
+        renderer.preflight.retries = 1;
+
+        let error = renderer.preflight().unwrap_err().to_string();
+        assert!(
+            error.contains("false --version"),
+            "unexpected error: {error}"
+        );
+        assert!(error.contains("2 attempt(s)"), "unexpected error: {error}");
+    }
+
+    #[test]
+    fn test_check_version_pin() {
+        assert!(check_version_pin("v0.7.1", "0.7.1").is_ok());
+        assert!(check_version_pin("0.7.1", "v0.7.1").is_ok());
+
+        let error = check_version_pin("v0.7.0", "0.7.1")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("v0.7.0") && error.contains("0.7.1"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_check_min_version() {
+        assert!(check_min_version("0.7.1", "0.7.0").is_ok());
+        assert!(check_min_version("0.7.0", "v0.7.0").is_ok());
+        assert!(check_min_version("1.2", "0.7.0").is_ok());
+
+        let error = check_min_version("0.6.9", "0.7.0").unwrap_err().to_string();
+        assert!(
+            error.contains("0.6.9") && error.contains("0.7.0"),
+            "{error}"
+        );
+        // Pre-releases come before the release
+        assert!(check_min_version("0.7.0-rc.1", "0.7.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v0.7.1"), Some("0.7.1"));
+        assert_eq!(parse_version("0.6.9\n"), Some("0.6.9"));
+        assert_eq!(
+            parse_version("d2-wrapper 1.2 (d2 v0.7.0-rc.1)"),
+            Some("1.2")
+        );
+        assert_eq!(parse_version("v0.7.0-rc.1"), Some("0.7.0-rc.1"));
+        assert_eq!(parse_version("development build"), None);
+        assert_eq!(parse_version("v1"), None);
+    }
+
+    #[test]
+    fn test_check_version_unknown() {
+        let mut renderer = create_test_renderer();
+        renderer.preflight.required_version = Some("0.7.1".into());
+        assert!(renderer.check_version("v0.7.0").is_err());
+        assert_eq!(
+            renderer.check_version("d2 v0.7.1").unwrap().as_deref(),
+            Some("0.7.1")
+        );
+
+        // Unknown versions skip the pin unless configured to fail
+        assert_eq!(renderer.check_version("custom build").unwrap(), None);
+        renderer.preflight.on_unknown_version = UnknownVersionBehavior::Ignore;
+        assert_eq!(renderer.check_version("custom build").unwrap(), None);
+        renderer.preflight.on_unknown_version = UnknownVersionBehavior::Fail;
+        let error = renderer
+            .check_version("custom build")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("on-unknown-version"), "{error}");
+    }
+
+    #[test]
+    fn test_d2_warnings() {
+        let stderr = "info: compiling\nwarn: using default font\nwarn: unknown key\n\
+                      success: successfully compiled - to out.png in 12ms\n";
+
+        let warnings: Vec<_> = d2_warnings(stderr, &[]).collect();
+        assert_eq!(warnings, ["using default font", "unknown key"]);
+
+        let suppressed = [String::from("default font")];
+        let warnings: Vec<_> = d2_warnings(stderr, &suppressed).collect();
+        assert_eq!(warnings, ["unknown key"]);
+    }
+
+    #[test]
+    fn test_format_stderr() {
+        assert_eq!(
+            format_stderr(b"err: bad\nmore", false),
+            "\n  err: bad\n  more"
+        );
+
+        let stderr = b"panic: \xff\xfe";
+        let formatted = format_stderr(stderr, false);
+        assert!(
+            formatted.contains("invalid UTF-8 starting at byte 7"),
+            "{formatted}"
+        );
+        assert!(!formatted.contains("ff fe"), "{formatted}");
+
+        let formatted = format_stderr(stderr, true);
+        assert!(
+            formatted.contains("(bytes 0..9: 70 61 6e 69 63 3a 20 ff fe)"),
+            "{formatted}"
+        );
+    }
+
+    #[test]
+    fn test_error_snippets() {
+        let content = "a -> b\n\tSYNTAX_ERROR here\nc -> d";
+        let stderr = "err: failed to compile -: 2:9: unexpected text\n";
+        assert_eq!(
+            error_snippets(stderr, content),
+            "\n   --> line 2, column 9\n    |\n  2 | \tSYNTAX_ERROR here\n    | \t       ^"
+        );
+
+        // Lines outside the diagram, e.g. of a watermark, and other output are skipped
+        assert_eq!(
+            error_snippets("err: failed to compile -: 9:1: bad", content),
+            ""
+        );
+        assert_eq!(error_snippets("panic: out of memory", content), "");
+        assert_eq!(
+            error_locations("-: 2:1: x\n-: 3:4: y\n-: 2:1: x"),
+            [(2, 1), (3, 4)]
+        );
+    }
+
+    #[test]
+    fn test_check_warnings() {
+        let mut renderer = create_test_renderer();
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        let stderr = "warn: deprecated syntax\nsuccess: successfully compiled\n";
+        assert!(renderer.check_warnings(&ctx, stderr).is_ok());
+
+        renderer.warnings_as_errors = true;
+        let error = renderer
+            .check_warnings(&ctx, stderr)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("(Test, #1)"), "{error}");
+        assert!(error.contains("deprecated syntax"), "{error}");
+
+        renderer.suppress_warnings = vec![String::from("deprecated")];
+        assert!(renderer.check_warnings(&ctx, stderr).is_ok());
+    }
+
+    #[test]
+    fn test_basic_args_layout() {
+        let renderer = create_test_renderer();
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(renderer.basic_args(&ctx, None), vec![OsStr::new("-")]);
+        assert_eq!(
+            renderer.basic_args(&ctx, Some("elk")),
+            vec![OsStr::new("--layout"), OsStr::new("elk"), OsStr::new("-")]
+        );
+    }
+
+    #[test]
+    fn test_basic_args_diagram_options() {
+        let renderer = D2Cli::new(
+            &Config {
+                theme_id: Some("1".into()),
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let options = [
+            (String::from("theme"), String::from("200")),
+            (String::from("sketch"), String::from("true")),
+            (String::from("pad"), String::from("40")),
+        ];
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1).with_options(&options);
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            ["--theme", "200", "--sketch", "--pad", "40", "-"].map(OsStr::new)
+        );
+    }
+
+    #[test]
+    fn test_basic_args_fonts() {
+        let mut fonts = Fonts {
+            regular: PathBuf::from("/fonts/regular.ttf"),
+            italic: PathBuf::from("/fonts/italic.ttf"),
+            bold: PathBuf::from("/fonts/bold.ttf"),
+            semibold: None,
+        };
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        let mut renderer = create_test_renderer();
+        renderer.fonts = Some(fonts.clone());
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            [
+                "--font-regular",
+                "/fonts/regular.ttf",
+                "--font-italic",
+                "/fonts/italic.ttf",
+                "--font-bold",
+                "/fonts/bold.ttf",
+                "-"
+            ]
+            .map(OsStr::new)
+        );
+
+        fonts.semibold = Some(PathBuf::from("/fonts/semibold.ttf"));
+        renderer.fonts = Some(fonts);
+        assert_eq!(
+            renderer.basic_args(&ctx, None)[6..],
+            ["--font-semibold", "/fonts/semibold.ttf", "-"].map(OsStr::new)
+        );
+    }
+
+    #[test]
+    fn test_basic_args_sketch() {
+        let renderer = D2Cli::new(
+            &Config {
+                sketch: true,
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            ["--sketch", "-"].map(OsStr::new)
+        );
+
+        // Diagrams can opt out of the global setting
+        let options = [(String::from("sketch"), String::from("false"))];
+        let ctx = ctx.with_options(&options);
+        assert_eq!(renderer.basic_args(&ctx, None), ["-"].map(OsStr::new));
+    }
+
+    #[test]
+    fn test_basic_args_pad() {
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        // Without `pad`, D2's default applies
+        let renderer = D2Cli::new(
+            &Config::default(),
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        assert_eq!(renderer.basic_args(&ctx, None), ["-"].map(OsStr::new));
+
+        let renderer = D2Cli::new(
+            &Config {
+                pad: Some(20),
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            ["--pad", "20", "-"].map(OsStr::new)
+        );
+    }
+
+    #[test]
+    fn test_basic_args_scale() {
+        let renderer = D2Cli::new(
+            &Config {
+                scale: Some(Scale::try_from(3.0).unwrap()),
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            ["--scale", "3", "-"].map(OsStr::new)
+        );
+    }
+
+    #[test]
+    fn test_basic_args_extra_args() {
+        let renderer = D2Cli::new(
+            &Config {
+                layout: Some("elk".into()),
+                extra_args: vec!["--sketch".into()],
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(
+            renderer.basic_args(&ctx, Some("elk")),
+            ["--layout", "elk", "--sketch", "-"].map(OsStr::new)
+        );
+    }
+
+    #[test]
+    fn test_basic_args_high_quality() {
+        let renderer = D2Cli::new(
+            &Config {
+                quality: RenderQuality::High,
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(
+            renderer.basic_args(&ctx, None),
+            vec![OsStr::new("--scale"), OsStr::new("2"), OsStr::new("-")]
+        );
+    }
+
+    #[test]
+    fn test_basic_args_svg_ignores_quality() {
+        let renderer = D2Cli::new(
+            &Config {
+                format: OutputFormat::Svg,
+                quality: RenderQuality::High,
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        assert_eq!(renderer.basic_args(&ctx, None), vec![OsStr::new("-")]);
+    }
+
+    #[test]
+    fn test_asset_root_rebases_paths() {
+        let fonts = Fonts {
+            regular: PathBuf::from("fonts/regular.ttf"),
+            italic: PathBuf::from("fonts/italic.ttf"),
+            bold: PathBuf::from("/usr/share/fonts/bold.ttf"),
+            semibold: Some(PathBuf::from("fonts/semibold.ttf")),
+        };
+        let renderer = D2Cli::new(
+            &Config {
+                path: PathBuf::from("bin/d2"),
+                fonts: Some(fonts.clone()),
+                asset_root: Some(PathBuf::from("assets")),
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        assert_eq!(
+            renderer.asset_root.as_deref(),
+            Some(Path::new("/book/assets"))
+        );
+        assert_eq!(renderer.binary, Path::new("/book/bin/d2"));
+        let rebased = renderer.fonts.unwrap();
+        assert_eq!(rebased.regular, Path::new("/book/fonts/regular.ttf"));
+        assert_eq!(rebased.bold, fonts.bold);
+        assert_eq!(
+            rebased.semibold.as_deref(),
+            Some(Path::new("/book/fonts/semibold.ttf"))
+        );
+
+        // Without an asset root, D2 runs in the chapter's directory, so paths are rebased too
+        let renderer = D2Cli::new(
+            &Config {
+                fonts: Some(fonts),
+                ..Config::default()
+            },
+            Path::new("/book"),
+            Path::new("/book/src"),
+        );
+        assert_eq!(renderer.binary, Path::new("d2"));
+        assert_eq!(
+            renderer.fonts.unwrap().regular,
+            Path::new("/book/fonts/regular.ttf")
+        );
+    }
+
+    #[test]
+    fn test_working_dir() {
+        let source_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(source_dir.path().join("guide")).unwrap();
+        let mut renderer = D2Cli::new(&Config::default(), Path::new("/book"), source_dir.path());
+
+        let ctx = RenderContext::new(Path::new("guide/setup.md"), "Setup", None, 1);
+        assert_eq!(
+            renderer.working_dir(&ctx),
+            Some(source_dir.path().join("guide"))
+        );
+        let ctx = RenderContext::new(Path::new("intro.md"), "Intro", None, 1);
+        assert_eq!(
+            renderer.working_dir(&ctx).as_deref(),
+            Some(source_dir.path())
+        );
+        let ctx = RenderContext::new(Path::new("generated/page.md"), "Page", None, 1);
+        assert_eq!(renderer.working_dir(&ctx), None);
+
+        renderer.asset_root = Some(PathBuf::from("/book/assets"));
+        assert_eq!(
+            renderer.working_dir(&ctx).as_deref(),
+            Some(Path::new("/book/assets"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout() {
+        let mut renderer = create_test_renderer();
+        renderer.binary = PathBuf::from("sleep");
+        renderer.timeout = Some(Duration::from_secs(1));
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+
+        let error = renderer
+            .execute_d2(&ctx, "", vec![OsStr::new("5")])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("timed out after 1 seconds"), "{error}");
+
+        // Without a timeout, the process runs to completion
+        renderer.timeout = None;
+        assert!(renderer.execute_d2(&ctx, "", vec![OsStr::new("0")]).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let runs = dir.path().join("runs");
+        let mut renderer = create_test_renderer();
+        renderer.binary = PathBuf::from("sh");
+        renderer.retries = 2;
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        let run = |script: &str| {
+            let _ = fs::remove_file(&runs);
+            let script = format!("echo run >> '{}'; {script}", runs.display());
+            let result =
+                renderer.execute_with_retries(&ctx, "", &[OsStr::new("-c"), OsStr::new(&script)]);
+            (
+                result.is_ok(),
+                fs::read_to_string(&runs).unwrap().lines().count(),
+            )
+        };
+
+        // Being killed by a signal is retried, up to the limit
+        assert_eq!(run("kill -9 $$"), (false, 3));
+        // Errors in the diagram are deterministic
+        assert_eq!(
+            run("echo 'err: failed to compile -: 2:1: unexpected text' >&2; exit 1"),
+            (false, 1)
+        );
+        // So is any other exit status D2 returns itself, with or without `err:`
+        assert_eq!(run("echo 'panic: invalid layout' >&2; exit 2"), (false, 1));
+        assert_eq!(run("exit 1"), (false, 1));
+        assert_eq!(run("exit 0"), (true, 1));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        use std::io::{Error, ErrorKind};
+
+        assert!(is_transient(&Error::from(ErrorKind::WouldBlock).into()));
+        assert!(is_transient(&Error::from(ErrorKind::OutOfMemory).into()));
+        assert!(is_transient(&TransientError("killed".into()).into()));
+        assert!(!is_transient(&Error::from(ErrorKind::NotFound).into()));
+        assert!(!is_transient(
+            &Error::from(ErrorKind::PermissionDenied).into()
+        ));
+        assert!(!is_transient(&anyhow!("D2 exited with status 1")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stdin_write_failure_reports_exit_status() {
+        // `false` exits without reading stdin, so writing more than a pipe buffer fails
+        let mut renderer = create_test_renderer();
+        renderer.binary = PathBuf::from("false");
+        let ctx = RenderContext::new(Path::new("test.md"), "Test", None, 1);
+        let content = "a -> b\n".repeat(200_000);
+
+        let error = renderer
+            .execute_d2(&ctx, &content, renderer.basic_args(&ctx, None))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("Failed to write D2 diagram content to stdin"),
+            "{error}"
+        );
+        assert!(error.contains("D2 exited with status"), "{error}");
+    }
+}
@@ -0,0 +1,81 @@
+//! Minimal SVG text rewriting for post-processing rendered diagrams
+
+use std::collections::HashMap;
+
+/// Start of an element's ID attribute
+const ID_ATTRIBUTE: &str = " id=\"";
+
+/// Rewrites the element IDs in an SVG image to deterministic ones
+///
+/// Every `id="..."` attribute is renamed to `d2-{n}`, numbered in order of
+/// first appearance, and references to it (`url(#...)`, `href="#..."`) are
+/// updated to match. IDs thus only change when the diagram's structure does,
+/// so committed SVGs don't churn between builds.
+///
+/// # Arguments
+/// * `svg` - The SVG image's text
+pub fn stabilize_ids(svg: &str) -> String {
+    let mut ids: HashMap<&str, String> = HashMap::new();
+    let mut rest = svg;
+    while let Some(start) = rest.find(ID_ATTRIBUTE) {
+        rest = &rest[start + ID_ATTRIBUTE.len()..];
+        let Some(end) = rest.find('"') else {
+            break;
+        };
+        let next = ids.len() + 1;
+        ids.entry(&rest[..end])
+            .or_insert_with(|| format!("d2-{next}"));
+        rest = &rest[end..];
+    }
+    if ids.is_empty() {
+        return svg.to_string();
+    }
+
+    // Rewrite in a single pass, so a renamed ID can't be renamed again
+    let mut result = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(start) = rest.find([' ', '#']) {
+        let reference = if rest[start..].starts_with(ID_ATTRIBUTE) {
+            Some(ID_ATTRIBUTE.len())
+        } else {
+            rest[start..].starts_with('#').then_some(1)
+        };
+        let prefix_len = reference.unwrap_or(1);
+        result.push_str(&rest[..start + prefix_len]);
+        rest = &rest[start + prefix_len..];
+        let Some(end) = rest.find(['"', ')']).filter(|_| reference.is_some()) else {
+            continue;
+        };
+        if let Some(new) = ids.get(&rest[..end]) {
+            result.push_str(new);
+            rest = &rest[end..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stabilize_ids() {
+        let svg = r##"<svg><defs><clipPath id="clip-8161"><rect/></clipPath><mask id="m-3"/><g id="clip-81"/></defs><g clip-path="url(#clip-8161)" mask="url(#m-3)"><use href="#clip-81"/></g></svg>"##;
+
+        assert_eq!(
+            stabilize_ids(svg),
+            r##"<svg><defs><clipPath id="d2-1"><rect/></clipPath><mask id="d2-2"/><g id="d2-3"/></defs><g clip-path="url(#d2-1)" mask="url(#d2-2)"><use href="#d2-3"/></g></svg>"##
+        );
+        // A later render with other random IDs gives the same result
+        assert_eq!(
+            stabilize_ids(&svg.replace("8161", "2024").replace("m-3", "m-9")),
+            stabilize_ids(svg)
+        );
+    }
+
+    #[test]
+    fn test_stabilize_ids_without_ids() {
+        assert_eq!(stabilize_ids("<svg><g/></svg>"), "<svg><g/></svg>");
+    }
+}
@@ -0,0 +1,94 @@
+//! Concurrency limiting by estimated diagram weight
+
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// Limits the total weight of diagrams rendered at once
+///
+/// A flat process cap lets several huge diagrams render side by side and
+/// spike memory. Limiting the sum of weights instead runs many light
+/// diagrams in parallel while heavy ones run mostly alone. A diagram heavier
+/// than the whole capacity still runs, but only when nothing else is. With a
+/// weight of 1 per render, it simply limits the number of renders.
+#[derive(Debug)]
+pub struct WeightLimiter {
+    /// Maximum total weight of running renders
+    capacity: usize,
+    /// Total weight of running renders
+    active: Mutex<usize>,
+    /// Signalled whenever a render finishes
+    weight_freed: Condvar,
+}
+
+/// A running render's share of the capacity, released when dropped
+#[derive(Debug)]
+pub struct WeightPermit<'a> {
+    limiter: &'a WeightLimiter,
+    weight: usize,
+}
+
+impl WeightLimiter {
+    /// Creates a limiter allowing a total weight of `capacity` at once
+    ///
+    /// # Arguments
+    /// * `capacity` - Maximum total weight of running renders
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            active: Mutex::new(0),
+            weight_freed: Condvar::new(),
+        }
+    }
+
+    /// Waits until a render of the given weight fits, then claims its share
+    ///
+    /// # Arguments
+    /// * `weight` - The render's estimated weight
+    pub fn acquire(&self, weight: usize) -> WeightPermit<'_> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        while *active > 0 && *active + weight > self.capacity {
+            active = self
+                .weight_freed
+                .wait(active)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *active += weight;
+        drop(active);
+        WeightPermit {
+            limiter: self,
+            weight,
+        }
+    }
+}
+
+impl Drop for WeightPermit<'_> {
+    fn drop(&mut self) {
+        *self
+            .limiter
+            .active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) -= self.weight;
+        self.limiter.weight_freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_renders_share_capacity() {
+        let limiter = WeightLimiter::new(10);
+        let _a = limiter.acquire(4);
+        let _b = limiter.acquire(6);
+        assert_eq!(*limiter.active.lock().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_oversized_render_runs_alone() {
+        let limiter = WeightLimiter::new(10);
+        let permit = limiter.acquire(50);
+        assert_eq!(*limiter.active.lock().unwrap(), 50);
+        drop(permit);
+        assert_eq!(*limiter.active.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,26 @@
+use std::process::Command;
+
+#[test]
+fn help_describes_subcommands() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mdbook-d2-png"))
+        .arg("--help")
+        .output()
+        .expect("couldn't run mdbook-d2-png");
+    assert!(output.status.success());
+
+    let help = String::from_utf8(output.stdout).unwrap();
+    for (command, about) in [
+        ("supports", "Check if a renderer is supported"),
+        (
+            "render",
+            "Render a single diagram with the book's configuration",
+        ),
+        ("clean", "Remove stale diagram images"),
+    ] {
+        let line = help
+            .lines()
+            .find(|line| line.trim_start().starts_with(command))
+            .unwrap_or_else(|| panic!("no `{command}` in help:\n{help}"));
+        assert!(line.contains(about), "{line}");
+    }
+}
@@ -34,6 +34,20 @@ impl TestBook {
     ///
     /// A Result containing the [`TestBook`] instance or an error
     pub fn new(book: &str) -> anyhow::Result<Self> {
+        Self::with_preprocessor(book, D2::new())
+    }
+
+    /// Creates a new [`TestBook`] instance built with a given preprocessor
+    ///
+    /// # Arguments
+    ///
+    /// * `book` - The name of the book in the test library
+    /// * `preprocessor` - The configured [`D2`] preprocessor to build with
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the [`TestBook`] instance or an error
+    pub fn with_preprocessor(book: &str, preprocessor: D2) -> anyhow::Result<Self> {
         let temp_dir = tempfile::tempdir().context("unable to create temporary directory")?;
 
         let source_book_root = library().join(book);
@@ -43,7 +57,7 @@ impl TestBook {
 
         let mut book = MDBook::load(temp_dir.path()).context("unable to load book from disk")?;
 
-        book.with_preprocessor(D2)
+        book.with_preprocessor(preprocessor)
             .build()
             .context("failed to build book")?;
 
@@ -53,6 +67,20 @@ impl TestBook {
         })
     }
 
+    /// Builds the book again, e.g. after editing its sources
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating whether the build succeeded
+    pub fn rebuild(&mut self) -> anyhow::Result<()> {
+        let mut book = MDBook::load(&self.book.root).context("unable to load book from disk")?;
+        book.with_preprocessor(D2::new())
+            .build()
+            .context("failed to build book")?;
+        self.book = book;
+        Ok(())
+    }
+
     /// Checks if the first chapter of the book contains a specific snippet
     ///
     /// # Arguments
@@ -63,18 +91,45 @@ impl TestBook {
     ///
     /// A boolean indicating whether the snippet was found
     pub fn chapter1_contains(&self, snippet: &str) -> bool {
-        let chapter1 = self
+        self.chapter1_count(snippet) > 0
+    }
+
+    /// Counts the occurrences of a specific snippet in the first chapter
+    ///
+    /// # Arguments
+    ///
+    /// * `snippet` - The text to search for in the chapter
+    ///
+    /// # Returns
+    ///
+    /// The number of non-overlapping occurrences of the snippet
+    pub fn chapter1_count(&self, snippet: &str) -> usize {
+        self.chapter_count("chapter1.html", snippet)
+    }
+
+    /// Counts the occurrences of a specific snippet in a rendered chapter
+    ///
+    /// # Arguments
+    ///
+    /// * `chapter` - The chapter's HTML file, relative to the build directory
+    /// * `snippet` - The text to search for in the chapter
+    ///
+    /// # Returns
+    ///
+    /// The number of non-overlapping occurrences of the snippet
+    pub fn chapter_count(&self, chapter: &str, snippet: &str) -> usize {
+        let chapter = self
             .book
             .root
             .join(&self.book.config.build.build_dir)
-            .join("chapter1.html");
-        dbg!(&chapter1);
+            .join(chapter);
+        dbg!(&chapter);
         let mut content = String::new();
-        File::open(chapter1)
-            .expect("couldn't read chapter1.html")
+        File::open(chapter)
+            .expect("couldn't read chapter")
             .read_to_string(&mut content)
             .unwrap();
-        content.contains(snippet)
+        content.matches(snippet).count()
     }
 }
 
@@ -1,6 +1,30 @@
 mod common;
 
 use common::TestBook;
+use mdbook_d2_png::{DiagramRenderer, RenderContext, D2};
+
+/// Returns the hex-encoded SHA-256 digest of some bytes
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    use std::fmt::Write;
+
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Renderer producing fixed bytes instead of running D2
+#[derive(Debug)]
+struct StaticRenderer;
+
+impl DiagramRenderer for StaticRenderer {
+    fn render(&self, content: &str, _ctx: &RenderContext) -> anyhow::Result<Vec<u8>> {
+        Ok(format!("rendered: {content}").into_bytes())
+    }
+}
 
 #[test]
 fn inline() {
@@ -15,7 +39,37 @@ fn inline() {
 fn simple() {
     let test_book = TestBook::new("simple").expect("couldn't create book");
 
-    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn resize() {
+    let test_book = TestBook::new("resize").expect("couldn't create book");
+
+    // The fake d2 renders 40x20 images, downsampled to 20x10 and then resized
+    let png = std::fs::read(test_book.book.source_dir().join("d2/1.1.png")).unwrap();
+    assert_eq!(&png[16..24], &[0, 0, 0, 80, 0, 0, 0, 60]);
+    // Indexed color, with `palette`
+    assert_eq!(png[25], 3);
+    assert!(test_book.chapter1_contains(r#"data-natural-width="80" data-natural-height="60""#));
+    // Downsampling for `quality = "high"` leaves the resized image at its size
+    assert!(!test_book.chapter1_contains(r#"width="40""#));
+}
+
+#[test]
+fn svg() {
+    let test_book = TestBook::new("svg").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/1.1.svg").exists());
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.svg" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn diagram_options() {
+    // Unknown options are ignored with a warning rather than failing the build
+    let test_book = TestBook::new("options").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Chapter 1, diagram 1" />"#));
 }
 
 #[test]
@@ -25,7 +79,314 @@ fn simple_output_dir() {
     let output = test_book.book.source_dir().join("d2/1.1.png");
 
     assert!(output.exists());
-    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Chapter 1, diagram 1" />"#));
+
+    // Temporary files are renamed into place, leaving none behind
+    let mut entries: Vec<_> = std::fs::read_dir(output.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, ["1.1.png", "manifest.json"]);
+}
+
+#[test]
+fn chapter_without_diagrams_first() {
+    let test_book = TestBook::new("multi-chapter").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/2.1.png").exists());
+    assert!(test_book.chapter1_contains(r#"img src="d2/2.1.png" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn numbering_scope_part() {
+    let test_book = TestBook::new("parts").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/2.1.png").exists());
+    assert!(test_book.chapter1_contains(r#"img src="d2/2.1.png" alt="Figure 1.1" />"#));
+}
+
+#[test]
+fn localized_output_dir() {
+    let test_book = TestBook::new("localized").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/de/1.1.png").exists());
+    assert!(test_book.chapter1_contains(r#"img src="d2/de/1.1.png" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn dedup_scope_book() {
+    let test_book = TestBook::new("dedup").expect("couldn't create book");
+
+    let output_dir = test_book.book.source_dir().join("d2");
+    assert!(output_dir.join("1.1.png").exists());
+    assert!(output_dir.join("2.2.png").exists());
+    assert!(!output_dir.join("2.1.png").exists());
+    assert!(!output_dir.join("2.3.png").exists());
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Figure 2.1" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/2.2.png" alt="Figure 2.2" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Figure 2.3" />"#));
+
+    // A chapter in a subdirectory links the shared file relative to itself
+    assert!(!output_dir.join("3.1.png").exists());
+    assert_eq!(
+        test_book.chapter_count(
+            "nested/chapter2.html",
+            r#"img src="../d2/1.1.png" alt="Figure 3.1" />"#
+        ),
+        1
+    );
+
+    // Imports resolve against each chapter's directory, so aren't shared across them
+    assert!(output_dir.join("2.4.png").exists());
+    assert!(output_dir.join("3.2.png").exists());
+}
+
+#[test]
+fn og_image() {
+    let test_book = TestBook::new("og-image").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(
+        r#"<meta property="og:image" content="https://example.com/book/d2/1.2.png">"#
+    ));
+    assert_eq!(test_book.chapter1_count(r#"property="og:image""#), 1);
+}
+
+#[test]
+fn on_error_embed() {
+    let test_book = TestBook::new("on-error").expect("couldn't create book");
+
+    assert_eq!(test_book.chapter1_count("<img"), 1);
+    assert!(test_book.chapter1_contains(r#"<pre class="d2-error">"#));
+    assert!(test_book.chapter1_contains("unexpected text"));
+    // The offending line is quoted below D2's message
+    assert!(test_book.chapter1_contains("2 | SYNTAX_ERROR &lt;broken&gt;"));
+    // A missing source file fails only its own diagram
+    assert_eq!(test_book.chapter1_count(r#"<pre class="d2-error">"#), 2);
+    assert!(
+        test_book.chapter1_contains("Failed to read D2 diagram (Chapter 1, #3) from missing.d2")
+    );
+}
+
+#[test]
+fn strict() {
+    let Err(error) = TestBook::new("strict") else {
+        panic!("build should fail");
+    };
+
+    // Every failure is reported, not just the first
+    let error = format!("{error:#}");
+    assert!(
+        error.contains("2 D2 diagram(s) failed to render: Chapter 1 (#1), Chapter 1 (#3)"),
+        "{error}"
+    );
+}
+
+#[test]
+fn picture() {
+    let test_book = TestBook::new("picture").expect("couldn't create book");
+
+    let output_dir = test_book.book.source_dir().join("d2");
+    assert!(output_dir.join("1.1.png").exists());
+    assert!(output_dir.join("1.1.dark.png").exists());
+    assert!(test_book.chapter1_contains(
+        r#"<picture><source media="(prefers-color-scheme: dark)" srcset="d2/1.1.dark.png" /><img class="d2-diagram" src="d2/1.1.png" alt="Chapter 1, diagram 1" width="40" height="20" /></picture>"#
+    ));
+}
+
+#[test]
+fn alt() {
+    let test_book = TestBook::new("alt").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Request flow" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.2.png" alt="Login sequence" />"#));
+}
+
+#[test]
+fn figure() {
+    let test_book = TestBook::new("figure").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(
+        r#"<figure><img class="d2-diagram" src="d2/1.1.png" alt="Chapter 1, diagram 1" /><figcaption>Request <em>flow</em></figcaption></figure>"#
+    ));
+    // Diagrams without a caption keep the plain image
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.2.png" alt="Chapter 1, diagram 2" />"#));
+}
+
+#[test]
+fn lazy() {
+    let test_book = TestBook::new("lazy").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(
+        r#"<img class="d2-diagram" src="d2/1.1.png" alt="Chapter 1, diagram 1" loading="lazy" />"#
+    ));
+}
+
+#[test]
+fn size() {
+    let test_book = TestBook::new("size").expect("couldn't create book");
+
+    assert!(test_book
+        .chapter1_contains(r#"src="d2/1.1.png" alt="Chapter 1, diagram 1" width="600" />"#));
+    assert!(test_book.chapter1_contains(
+        r#"src="d2/1.2.png" alt="Chapter 1, diagram 2" width="300" height="100" />"#
+    ));
+}
+
+#[test]
+fn code_block_aliases() {
+    let test_book = TestBook::new("aliases").expect("couldn't create book");
+
+    assert_eq!(test_book.chapter1_count("<img"), 2);
+    assert!(test_book.book.source_dir().join("d2/1.2.png").exists());
+    // The default language is replaced
+    assert!(test_book.chapter1_contains("e -&gt; f"));
+}
+
+#[test]
+fn file_reference() {
+    let test_book = TestBook::new("file-ref").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Login flow" />"#));
+}
+
+#[test]
+fn file_reference_missing() {
+    let test_book = TestBook::new("file-ref-missing").expect("couldn't create book");
+
+    // Only the diagram with the missing file is skipped, despite `on-error = "fail"`
+    let output_dir = test_book.book.source_dir().join("d2");
+    assert!(output_dir.join("1.1.png").exists());
+    assert!(!output_dir.join("1.2.png").exists());
+    assert_eq!(test_book.chapter1_count("<img"), 1);
+}
+
+#[test]
+fn manifest() {
+    let test_book = TestBook::new("simple").expect("couldn't create book");
+
+    let manifest =
+        std::fs::read_to_string(test_book.book.source_dir().join("d2/manifest.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    let files = manifest["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["path"], "d2/1.1.png");
+    assert_eq!(files[0]["chapter"], "Chapter 1");
+    assert_eq!(files[0]["section"], "1");
+    assert_eq!(files[0]["diagram_index"], 1);
+    assert_eq!(files[0]["hash"].as_str().unwrap().len(), 64);
+    let png = std::fs::read(test_book.book.source_dir().join("d2/1.1.png")).unwrap();
+    assert_eq!(files[0]["integrity"], sha256_hex(&png));
+}
+
+#[test]
+fn hashed_filenames() {
+    let test_book = TestBook::new("hashed-filenames").expect("couldn't create book");
+
+    let output_dir = test_book.book.source_dir().join("d2");
+    let mut names: Vec<_> = std::fs::read_dir(&output_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name != "manifest.json")
+        .collect();
+    assert_eq!(names.len(), 1);
+    let name = names.pop().unwrap();
+
+    // Named after the first digits of the file's SHA-256
+    let png = std::fs::read(output_dir.join(&name)).unwrap();
+    let integrity = sha256_hex(&png);
+    assert_eq!(name, format!("1.1.{}.png", &integrity[..8]));
+    assert!(test_book.chapter1_contains(&format!(r#"img src="d2/{name}""#)));
+
+    let manifest = std::fs::read_to_string(output_dir.join("manifest.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest["files"][0]["path"], format!("d2/{name}"));
+    assert_eq!(manifest["files"][0]["integrity"], integrity);
+}
+
+#[test]
+fn clean_removes_stale_images() {
+    let mut test_book = TestBook::new("simple").expect("couldn't create book");
+    let output_dir = test_book.book.source_dir().join("d2");
+    assert!(output_dir.join("manifest.json").exists());
+    // An image added by hand, which no build generated
+    std::fs::write(output_dir.join("logo.png"), "logo").unwrap();
+
+    // Moving the diagram to a new chapter 1 leaves its old image behind
+    let chapter = test_book.book.source_dir().join("chapter1.md");
+    let content = std::fs::read_to_string(&chapter).unwrap();
+    std::fs::write(
+        &chapter,
+        format!("# Chapter 1\n\n```d2\nx -> y\n```\n\n{content}"),
+    )
+    .unwrap();
+    test_book.rebuild().expect("couldn't rebuild book");
+    assert!(output_dir.join("1.2.png").exists());
+    std::fs::write(&chapter, content).unwrap();
+    test_book.rebuild().expect("couldn't rebuild book");
+
+    let stale = mdbook_d2_png::clean(&test_book.book.root, true).unwrap();
+    assert_eq!(stale, [output_dir.join("1.2.png")]);
+    assert!(output_dir.join("1.2.png").exists());
+
+    mdbook_d2_png::clean(&test_book.book.root, false).unwrap();
+    assert!(!output_dir.join("1.2.png").exists());
+    assert!(output_dir.join("1.1.png").exists());
+    assert!(output_dir.join("logo.png").exists());
+    assert_eq!(
+        mdbook_d2_png::clean(&test_book.book.root, true).unwrap(),
+        Vec::<std::path::PathBuf>::new()
+    );
+}
+
+#[test]
+fn clean_refuses_after_partial_build() {
+    let test_book = TestBook::new("only-tags").expect("couldn't create book");
+
+    let error = mdbook_d2_png::clean(&test_book.book.root, true).unwrap_err();
+    assert!(
+        format!("{error:#}").contains("left diagrams out"),
+        "{error:#}"
+    );
+}
+
+#[test]
+fn invalid_config_fails_build() {
+    // Reported as a build error rather than a panic
+    let Err(error) = TestBook::new("invalid-config") else {
+        panic!("book with an invalid configuration built");
+    };
+    assert!(
+        format!("{error:#}").contains("`palette` must be from 2 to 256 colors"),
+        "{error:#}"
+    );
+}
+
+#[test]
+fn render_single_diagram() {
+    let test_book = TestBook::new("simple").expect("couldn't create book");
+    let cache_dir = test_book.book.root.join(".d2-png-cache");
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+
+    let (image, _) = mdbook_d2_png::render_diagram(&test_book.book.root, None, "a -> b");
+    assert!(image.unwrap().starts_with(b"\x89PNG"));
+    assert!(!cache_dir.exists());
+
+    let (image, _) = mdbook_d2_png::render_diagram(&test_book.book.root, None, "SYNTAX_ERROR");
+    let error = format!("{:#}", image.unwrap_err());
+    assert!(error.contains("unexpected text"), "{error}");
+}
+
+#[test]
+fn only_tags() {
+    let test_book = TestBook::new("only-tags").expect("couldn't create book");
+
+    let output_dir = test_book.book.source_dir().join("d2");
+    assert!(output_dir.join("1.1.png").exists());
+    assert!(!output_dir.join("1.2.png").exists());
+    assert_eq!(test_book.chapter1_count("<img"), 1);
+    assert!(test_book.chapter1_contains("a -&gt; b"));
+    assert!(test_book.chapter1_contains("e -&gt; f"));
 }
 
 #[test]
@@ -35,5 +396,155 @@ fn custom_src() {
     let output = test_book.book.source_dir().join("d2/1.1.png");
 
     assert!(output.exists());
-    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="" />"#));
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn split() {
+    let test_book = TestBook::new("split").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/1.1-1.png").exists());
+    assert!(test_book.book.source_dir().join("d2/1.1-2.png").exists());
+    assert!(
+        test_book.chapter1_contains(r#"img src="d2/1.1-1.png" alt="Chapter 1, diagram 1-1" />"#)
+    );
+    assert!(
+        test_book.chapter1_contains(r#"img src="d2/1.1-2.png" alt="Chapter 1, diagram 1-2" />"#)
+    );
+}
+
+#[test]
+fn legend() {
+    let test_book = TestBook::new("legend").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/1.1-1.png").exists());
+    assert!(test_book.book.source_dir().join("d2/1.1-2.png").exists());
+    assert!(
+        test_book.chapter1_contains(r#"img src="d2/1.1-1.png" alt="Chapter 1, diagram 1-1" />"#)
+    );
+    assert!(test_book.chapter1_contains(r#"<img class="d2-diagram d2-legend" src="d2/1.1-2.png""#));
+}
+
+#[test]
+fn zoomable() {
+    let test_book = TestBook::new("zoomable").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(
+        r#"<span class="d2-zoom"><img class="d2-diagram" src="d2/1.2.png" alt="Chapter 1, diagram 2" /></span>"#
+    ));
+    assert_eq!(test_book.chapter1_count(r#"<span class="d2-zoom">"#), 2);
+    // The snippet is added once per page, not per diagram
+    assert_eq!(test_book.chapter1_count("window.d2Zoom = true"), 1);
+}
+
+#[test]
+fn stable_id() {
+    let test_book = TestBook::new("stable-id").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/arch-v2.png").exists());
+    assert!(
+        test_book.chapter1_contains(r#"img src="d2/arch-v2.png" alt="Chapter 1, diagram 1" />"#)
+    );
+}
+
+#[test]
+fn stable_id_collision() {
+    let Err(error) = TestBook::new("stable-id-collision") else {
+        panic!("build should fail");
+    };
+
+    let error = format!("{error:#}");
+    assert!(
+        error.contains("Intro (#1) and Chapter 1 (#1) share the stable_id `arch-v2`"),
+        "{error}"
+    );
+}
+
+#[test]
+fn table() {
+    let test_book = TestBook::new("table").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/1.1.png").exists());
+    assert!(test_book
+        .chapter1_contains(r#"<td><img src="d2/1.1.png" alt="Chapter 1, diagram 1" /></td>"#));
+    // Ordinary code spans in table cells are left alone
+    assert!(test_book.chapter1_contains("<td><code>d2 fmt</code></td>"));
+}
+
+#[test]
+fn custom_renderer() {
+    let test_book = TestBook::with_preprocessor("simple", D2::with_renderer(StaticRenderer))
+        .expect("couldn't create book");
+
+    let output = std::fs::read_to_string(test_book.book.source_dir().join("d2/1.1.png")).unwrap();
+    assert!(output.starts_with("rendered: "), "{output}");
+    assert!(test_book.chapter1_contains(r#"img src="d2/1.1.png" alt="Chapter 1, diagram 1" />"#));
+}
+
+#[test]
+fn total_timeout_exceeded() {
+    let Err(error) = TestBook::new("total-timeout") else {
+        panic!("build should time out");
+    };
+
+    assert!(format!("{error:#}").contains("total timeout"), "{error:#}");
+}
+
+#[test]
+fn details() {
+    let test_book = TestBook::new("details").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains(
+        "<summary>Architecture</summary>\n<p><img src=\"d2/1.1.png\" alt=\"Chapter 1, diagram 1\" /></p>\n</details>"
+    ));
+    assert!(test_book.chapter1_contains(
+        "<div>\n<p><img src=\"d2/1.2.png\" alt=\"Chapter 1, diagram 2\" /></p>\n</div>\n</details>"
+    ));
+}
+
+#[test]
+fn js_data_file() {
+    let test_book = TestBook::new("js-data").expect("couldn't create book");
+
+    let module =
+        std::fs::read_to_string(test_book.book.source_dir().join("d2/diagrams.js")).unwrap();
+    assert!(module.starts_with("export default {"), "{module}");
+    assert!(
+        module.contains(r#""1.1": "data:image/png;base64,"#),
+        "{module}"
+    );
+    assert!(test_book.chapter1_contains(
+        r#"<img class="d2-diagram" alt="Chapter 1, diagram 1" data-d2-id="1.1" />"#
+    ));
+    assert!(!test_book.book.source_dir().join("d2/1.1.png").exists());
+}
+
+#[test]
+fn max_diagrams() {
+    let test_book = TestBook::new("max-diagrams").expect("couldn't create book");
+
+    assert!(test_book.book.source_dir().join("d2/1.1-1.png").exists());
+    assert!(!test_book.book.source_dir().join("d2/1.1-2.png").exists());
+    assert!(test_book.chapter1_contains("<em>D2 diagram skipped for fast preview"));
+}
+
+#[test]
+fn max_diagrams_per_chapter_exceeded() {
+    let Err(error) = TestBook::new("max-per-chapter") else {
+        panic!("build should fail");
+    };
+
+    assert!(
+        format!("{error:#}").contains("max-diagrams-per-chapter"),
+        "{error:#}"
+    );
+}
+
+#[cfg(not(feature = "fetch"))]
+#[test]
+fn fetch_without_feature() {
+    let test_book = TestBook::new("fetch").expect("couldn't create book");
+
+    assert!(test_book.chapter1_contains("(Chapter 1, #1) from http://127.0.0.1:9/arch.d2"));
+    assert!(test_book.chapter1_contains("`fetch` feature"));
 }